@@ -0,0 +1,59 @@
+// Times reading the balance of pending and committed accounts with
+// `try_account` and `with_account`. Run with `cargo run --release
+// --example account_reads`.
+
+use ethereum_pyspec_db::{Account, Db, MutableTransaction};
+use ethereum_types::{H160, U256};
+use std::hint::black_box;
+use std::time::Instant;
+
+const ACCOUNTS: u64 = 10_000;
+const ROUNDS: u64 = 100;
+
+fn address(i: u64) -> H160 {
+    H160::from_low_u64_be(i + 1)
+}
+
+// Nanoseconds per read
+fn time(txn: &mut MutableTransaction, read: fn(&mut MutableTransaction, H160) -> U256) -> f64 {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for i in 0..ACCOUNTS {
+            black_box(read(txn, address(i)));
+        }
+    }
+    start.elapsed().as_nanos() as f64 / (ROUNDS * ACCOUNTS) as f64
+}
+
+fn try_account(txn: &mut MutableTransaction, address: H160) -> U256 {
+    txn.try_account(address).unwrap().unwrap().balance
+}
+
+fn with_account(txn: &mut MutableTransaction, address: H160) -> U256 {
+    txn.with_account(address, |account| account.unwrap().balance)
+        .unwrap()
+}
+
+fn report(name: &str, txn: &mut MutableTransaction) {
+    println!(
+        "{:<10} try_account: {:>7.2}ns  with_account: {:>7.2}ns",
+        name,
+        time(txn, try_account),
+        time(txn, with_account)
+    );
+}
+
+fn main() -> anyhow::Result<()> {
+    let db = Db::memory()?;
+    let mut txn = db.begin_mut()?;
+    for i in 0..ACCOUNTS {
+        txn.set_account(address(i), Some(Account::eoa(i, U256::from(i))));
+    }
+    report("pending", &mut txn);
+    txn.commit()?;
+
+    let mut txn = db.begin_mut()?;
+    report("committed", &mut txn);
+    txn.commit()?;
+    Ok(())
+}
@@ -0,0 +1,59 @@
+// Times `common_prefix` against the byte at a time comparison it replaced,
+// on nibble lists of the lengths the walker compares. Run with `cargo run
+// --release --example common_prefix`.
+
+use ethereum_pyspec_db::trie_core::common_prefix;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::hint::black_box;
+use std::time::Instant;
+
+const PAIRS: usize = 10_000;
+const ROUNDS: usize = 200;
+
+fn byte_at_a_time(xs: &[u8], ys: &[u8]) -> usize {
+    xs.iter().zip(ys).take_while(|(x, y)| x == y).count()
+}
+
+// Pairs of nibble lists of `len` sharing a random prefix, as a walk over
+// sorted keys sees them
+fn pairs(rng: &mut ChaCha8Rng, len: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+    (0..PAIRS)
+        .map(|_| {
+            let xs: Vec<u8> = (0..len).map(|_| rng.gen_range(0..16)).collect();
+            let mut ys = xs.clone();
+            let shared = rng.gen_range(0..=len);
+            for y in &mut ys[shared..] {
+                *y = (*y + rng.gen_range(1..16)) % 16;
+            }
+            (xs, ys)
+        })
+        .collect()
+}
+
+// Nanoseconds per call
+fn time(pairs: &[(Vec<u8>, Vec<u8>)], f: fn(&[u8], &[u8]) -> usize) -> f64 {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for (xs, ys) in pairs {
+            black_box(f(black_box(xs), black_box(ys)));
+        }
+    }
+    start.elapsed().as_nanos() as f64 / (ROUNDS * PAIRS) as f64
+}
+
+fn main() {
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    for len in [8, 32, 64] {
+        let pairs = pairs(&mut rng, len);
+        for (xs, ys) in &pairs {
+            assert_eq!(common_prefix(xs, ys), byte_at_a_time(xs, ys));
+        }
+        println!(
+            "{:>2} nibbles  word at a time: {:>6.2}ns  byte at a time: {:>6.2}ns",
+            len,
+            time(&pairs, common_prefix),
+            time(&pairs, byte_at_a_time)
+        );
+    }
+}
@@ -0,0 +1,54 @@
+// Compares dirty lists keyed by `PackedKey` against the `NibbleList` keys
+// they replaced: the memory taken by an entry and the time to build and
+// sort a list. Run with `cargo run --release --example dirty_list_keys`.
+
+use ethereum_pyspec_db::structs::{get_internal_key, get_packed_key, NibbleList, PackedKey};
+use smallvec::SmallVec;
+use std::cmp::Reverse;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+const KEYS: u64 = 1_000_000;
+
+type Value = Option<SmallVec<[u8; 36]>>;
+
+fn report(name: &str, entry: usize, build: Duration, sort: Duration) {
+    println!(
+        "{:<12} entry: {:>4} bytes  list: {:>4} MiB  build: {:>8.2?}  sort: {:>8.2?}",
+        name,
+        entry,
+        (entry as u64 * KEYS) >> 20,
+        build,
+        sort
+    );
+}
+
+fn main() {
+    let values = || (0..KEYS).map(|i| Some(SmallVec::from_slice(&i.to_be_bytes())));
+
+    {
+        let start = Instant::now();
+        let mut dirty_list: Vec<(NibbleList, Value)> = (0..KEYS)
+            .map(|i| get_internal_key(i.to_be_bytes()))
+            .zip(values())
+            .collect();
+        let build = start.elapsed();
+        let start = Instant::now();
+        dirty_list.sort_by(|x, y| y.0.cmp(&x.0));
+        let sort = start.elapsed();
+        report("nibble list", size_of::<(NibbleList, Value)>(), build, sort);
+    }
+
+    {
+        let start = Instant::now();
+        let mut dirty_list: Vec<(PackedKey, Value)> = (0..KEYS)
+            .map(|i| get_packed_key(i.to_be_bytes()))
+            .zip(values())
+            .collect();
+        let build = start.elapsed();
+        let start = Instant::now();
+        dirty_list.sort_by_key(|x| Reverse(x.0));
+        let sort = start.elapsed();
+        report("packed key", size_of::<(PackedKey, Value)>(), build, sort);
+    }
+}
@@ -0,0 +1,60 @@
+// Times account reads from a file database, through read and write
+// transactions, and the cost of beginning a read transaction. Run with
+// `cargo run --release --example disk_reads`.
+
+use ethereum_pyspec_db::{Account, Db};
+use ethereum_types::{H160, U256};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ACCOUNTS: u64 = 100_000;
+const READS: u32 = 1_000_000;
+const BEGINS: u32 = 10_000;
+
+fn address(i: u64) -> H160 {
+    H160::from_low_u64_be(i + 1)
+}
+
+fn report(name: &str, per_op: Duration) {
+    println!("{:<16} {:>8.2?}", name, per_op);
+}
+
+fn main() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = Db::file(dir.path())?;
+    let mut txn = db.begin_mut()?;
+    for i in 0..ACCOUNTS {
+        txn.set_account(address(i), Some(Account::eoa(i, U256::from(i))));
+    }
+    txn.commit()?;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let reads: Vec<_> = (0..READS)
+        .map(|_| address(rng.gen_range(0..ACCOUNTS)))
+        .collect();
+
+    let txn = db.begin_ro()?;
+    let start = Instant::now();
+    for address in &reads {
+        black_box(txn.try_account(*address)?);
+    }
+    report("read txn get", start.elapsed() / READS);
+    drop(txn);
+
+    let mut txn = db.begin_mut()?;
+    let start = Instant::now();
+    for address in &reads {
+        black_box(txn.try_account(*address)?);
+    }
+    report("write txn get", start.elapsed() / READS);
+    txn.rollback()?;
+
+    let start = Instant::now();
+    for _ in 0..BEGINS {
+        black_box(db.begin_ro()?);
+    }
+    report("begin_ro", start.elapsed() / BEGINS);
+    Ok(())
+}
@@ -0,0 +1,50 @@
+// Times committing a large cache to disk, into an empty table, where every
+// put is appended, and into a populated one, where the puts land between
+// the existing keys. Run with `cargo run --release --example large_commit`.
+
+use ethereum_pyspec_db::backend::{Backend, FileOptions, Table};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::time::{Duration, Instant};
+
+const KEYS: usize = 500_000;
+
+fn keys(rng: &mut ChaCha8Rng) -> Vec<Vec<u8>> {
+    (0..KEYS)
+        .map(|_| {
+            let mut key = vec![Table::State as u8];
+            key.extend_from_slice(&rng.gen::<[u8; 32]>());
+            key
+        })
+        .collect()
+}
+
+// Puts `keys` and commits them, returning the time taken by the commit
+fn commit(backend: &Backend, keys: &[Vec<u8>]) -> anyhow::Result<Duration> {
+    let mut tx = backend.begin_mut()?;
+    for key in keys {
+        tx.put(key, &key[1..])?;
+    }
+    let start = Instant::now();
+    tx.commit()?;
+    Ok(start.elapsed())
+}
+
+fn report(name: &str, commit: Duration) {
+    println!(
+        "{:<10} {} keys  commit: {:>8.2?}  per key: {:>6.0?}",
+        name,
+        KEYS,
+        commit,
+        commit / KEYS as u32
+    );
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let dir = tempfile::tempdir()?;
+    let backend = Backend::file(dir.path(), &FileOptions::default())?;
+    report("empty", commit(&backend, &keys(&mut rng))?);
+    report("populated", commit(&backend, &keys(&mut rng))?);
+    Ok(())
+}
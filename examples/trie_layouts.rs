@@ -0,0 +1,131 @@
+// Compares the path keyed trie layout against the hash keyed proof of
+// concept. Run with `cargo run --release --features hash-keyed --example
+// trie_layouts`.
+
+use ethereum_pyspec_db::backend::{Backend, BackendTransaction, FileOptions};
+use ethereum_pyspec_db::hash_keyed;
+use ethereum_pyspec_db::keys::{trie_key, ACCOUNT_TRIE_PREFIX};
+use ethereum_pyspec_db::structs::{get_packed_key, InternalNode};
+use ethereum_pyspec_db::walk::{sort_dirty_list, DirtyList, Walker, EMPTY_TRIE_ROOT};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use smallvec::SmallVec;
+use std::time::{Duration, Instant};
+
+const BATCHES: usize = 100;
+const BATCH_SIZE: usize = 1000;
+const READS: usize = 100_000;
+
+fn batches() -> Vec<DirtyList> {
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    (0..BATCHES)
+        .map(|_| {
+            let mut dirty_list: DirtyList = (0..BATCH_SIZE)
+                .map(|_| {
+                    let key = get_packed_key(
+                        rng.gen_range(0..(BATCHES * BATCH_SIZE) as u64)
+                            .to_be_bytes(),
+                    );
+                    let value: [u8; 32] = rng.gen();
+                    (key, Some(SmallVec::from_slice(&value)))
+                })
+                .collect();
+            sort_dirty_list(&mut dirty_list);
+            dirty_list
+        })
+        .collect()
+}
+
+fn path_keyed_get(tx: &BackendTransaction, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut nibble_list = Vec::new();
+    loop {
+        let node = match tx.get(&trie_key(ACCOUNT_TRIE_PREFIX, &nibble_list))? {
+            None => return Ok(None),
+            Some(data) => InternalNode::unmarshal(&data)?,
+        };
+        let rest = &key[nibble_list.len()..];
+        match node {
+            InternalNode::Leaf { rest_of_key, value } => {
+                return Ok((rest_of_key.as_slice() == rest).then(|| value.to_vec()));
+            }
+            InternalNode::Branch {
+                extension_nibbles, ..
+            } => {
+                if !rest.starts_with(&extension_nibbles) {
+                    return Ok(None);
+                }
+                nibble_list
+                    .extend_from_slice(&key[nibble_list.len()..][..extension_nibbles.len() + 1]);
+            }
+        }
+    }
+}
+
+fn file_size(dir: &tempfile::TempDir) -> anyhow::Result<u64> {
+    Ok(std::fs::metadata(dir.path().join("mdbx.dat"))?.len())
+}
+
+fn report(name: &str, write: Duration, read: Duration, size: u64) {
+    println!(
+        "{:<12} write: {:>8.2?}  read: {:>8.2?}  size: {:>6} MiB",
+        name,
+        write,
+        read,
+        size >> 20
+    );
+}
+
+fn main() -> anyhow::Result<()> {
+    let batches = batches();
+    let mut rng = ChaCha8Rng::seed_from_u64(1);
+    let reads: Vec<_> = (0..READS)
+        .map(|_| {
+            let batch = &batches[rng.gen_range(0..BATCHES)];
+            batch[rng.gen_range(0..batch.len())].0.unpack()
+        })
+        .collect();
+
+    {
+        let dir = tempfile::tempdir()?;
+        let backend = Backend::file(dir.path(), &FileOptions::default())?;
+        let mut tx = backend.begin_mut()?;
+        let start = Instant::now();
+        for dirty_list in &batches {
+            let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list.clone(), &tx);
+            walker.root()?;
+            let writes = walker.into_writes();
+            tx.apply(writes);
+            tx.commit_and_continue()?;
+        }
+        let write = start.elapsed();
+        let start = Instant::now();
+        for key in &reads {
+            assert!(path_keyed_get(&tx, key)?.is_some());
+        }
+        let read = start.elapsed();
+        tx.commit()?;
+        report("path keyed", write, read, file_size(&dir)?);
+    }
+
+    {
+        let dir = tempfile::tempdir()?;
+        let backend = Backend::file(dir.path(), &FileOptions::default())?;
+        let mut tx = backend.begin_mut()?;
+        let mut root = *EMPTY_TRIE_ROOT;
+        let start = Instant::now();
+        for dirty_list in &batches {
+            root = hash_keyed::update_root(&mut tx, root, dirty_list.clone())?;
+            tx.commit_and_continue()?;
+        }
+        let write = start.elapsed();
+        let start = Instant::now();
+        for key in &reads {
+            assert!(hash_keyed::get(&tx, root, key)?.is_some());
+        }
+        let read = start.elapsed();
+        tx.commit()?;
+        report("hash keyed", write, read, file_size(&dir)?);
+    }
+
+    Ok(())
+}
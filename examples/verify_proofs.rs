@@ -0,0 +1,62 @@
+// Compares verifying account proofs one at a time against `verify_many`,
+// which shares the nodes at the top of the trie. Run with `cargo run
+// --release --example verify_proofs`.
+
+use ethereum_pyspec_db::{keccak256, proof, Account, Db};
+use ethereum_types::{H160, U256};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::time::{Duration, Instant};
+
+const ACCOUNTS: u64 = 100_000;
+const PROOFS: usize = 10_000;
+const ROUNDS: usize = 10;
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{:<12} {:>8.2?} per round, {:>8.2?} per proof",
+        name,
+        elapsed / ROUNDS as u32,
+        elapsed / (ROUNDS * PROOFS) as u32
+    );
+}
+
+fn main() -> anyhow::Result<()> {
+    let db = Db::memory()?;
+    let mut txn = db.begin_mut()?;
+    for i in 0..ACCOUNTS {
+        txn.set_account(
+            H160::from_low_u64_be(i),
+            Some(Account::eoa(i, U256::from(i))),
+        );
+    }
+    let root = txn.commit()?;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let txn = db.begin_mut()?;
+    let items = (0..PROOFS)
+        .map(|_| {
+            let address = H160::from_low_u64_be(rng.gen_range(0..ACCOUNTS));
+            Ok((
+                keccak256(address).as_bytes().to_vec(),
+                txn.account_proof(root, address)?,
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for (key, proof) in &items {
+            assert!(proof::verify_proof(root, key, proof)?.is_some());
+        }
+    }
+    report("independent", start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        assert!(proof::verify_many(root, &items)?.iter().all(Option::is_some));
+    }
+    report("verify_many", start.elapsed());
+
+    Ok(())
+}
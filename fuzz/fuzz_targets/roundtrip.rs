@@ -0,0 +1,9 @@
+#![no_main]
+
+// Run with `cargo fuzz run roundtrip fuzz/corpus/roundtrip`
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    ethereum_pyspec_db::structs::roundtrip_check(data);
+});
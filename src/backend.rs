@@ -1,67 +1,679 @@
 use std::borrow::Cow;
-use std::ops::Bound::{Excluded, Unbounded};
+use std::ffi::CString;
+use std::ops::Bound::{Excluded, Included, Unbounded};
 
 use anyhow;
 use arrayvec::ArrayVec;
 use libmdbx::{
-    Environment, EnvironmentFlags, Geometry, Mode, SyncMode, Transaction, WriteFlags, WriteMap, RW,
+    Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, Geometry, Mode, PageSize,
+    SyncMode, Transaction, WriteFlags, WriteMap, RO, RW,
 };
 use smallvec::SmallVec;
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+use crate::error::DbError;
+
+type CacheValue = Option<SmallVec<[u8; 128]>>;
+type CacheKey = ArrayVec<u8, MAX_KEY_LEN>;
+type Cache = BTreeMap<CacheKey, CacheValue>;
+
+// Longest key that can be written. The longest keys in use are storage trie
+// nodes, 65 bytes of prefix followed by the packed path.
+pub const MAX_KEY_LEN: usize = 96;
+
+pub const DEFAULT_MAX_SIZE: usize = 1 << 40;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOptions {
+    pub max_size: usize,
+    // Double `max_size` and retry when a write transaction runs out of space
+    pub auto_grow: bool,
+    // Flush a write transaction's writes to MDBX once roughly this many
+    // bytes are pending
+    pub auto_flush_bytes: Option<usize>,
+    // MDBX's page size for a new file, a power of two from 256 to 65536.
+    // `None` leaves it to MDBX. An existing file keeps its page size, and
+    // opening it with a different one fails.
+    pub page_size: Option<usize>,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+            auto_grow: false,
+            auto_flush_bytes: None,
+            page_size: None,
+        }
+    }
+}
+
+// What a write transaction has done to disk, so it can be replayed into a
+// fresh MDBX transaction after growing the map.
+enum JournalEntry {
+    Flush(Cache),
+    ClearPrefix(Vec<u8>, usize),
+}
+
+fn is_map_full(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<DbError>(), Some(DbError::MapFull))
+}
+
+// Keys are namespaced by their first byte. In memory the prefix is kept as
+// part of the key, on disk each namespace is a separate named MDBX database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    Metadata = 0,
+    State = 1,
+    Trie = 2,
+    Code = 3,
+    // Trie nodes keyed by hash, see `hash_keyed`
+    Nodes = 4,
+    // `keccak256(x) -> x` for trie keys, see `DbOptions::record_preimages`
+    Preimages = 5,
+    // Keys owned by users of the crate, see `MutableTransaction::raw_put`
+    Raw = 255,
+}
+
+impl Table {
+    pub const ALL: [Table; 7] = [
+        Table::Metadata,
+        Table::State,
+        Table::Trie,
+        Table::Code,
+        Table::Nodes,
+        Table::Preimages,
+        Table::Raw,
+    ];
+
+    pub fn prefix(self) -> u8 {
+        self as u8
+    }
+
+    // Position in `ALL`, prefixes are not contiguous
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|&table| table == self).unwrap()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::Metadata => "metadata",
+            Table::State => "state",
+            Table::Trie => "trie",
+            Table::Code => "code",
+            Table::Nodes => "nodes",
+            Table::Preimages => "preimages",
+            Table::Raw => "raw",
+        }
+    }
+
+    pub fn from_prefix(prefix: u8) -> anyhow::Result<Self> {
+        Ok(match prefix {
+            0 => Table::Metadata,
+            1 => Table::State,
+            2 => Table::Trie,
+            3 => Table::Code,
+            4 => Table::Nodes,
+            5 => Table::Preimages,
+            255 => Table::Raw,
+            _ => anyhow::bail!("Unknown table prefix {}", prefix),
+        })
+    }
+}
+
+fn split_key(key: &[u8]) -> anyhow::Result<(Table, &[u8])> {
+    let (prefix, rest) = key
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("Key is missing a table prefix"))?;
+    Ok((Table::from_prefix(*prefix)?, rest))
+}
+
+// A write transaction holds the cache lock exclusively for its lifetime. On
+// disk the cache only holds unflushed writes and readers use their own MDBX
+// snapshot, but in memory the cache is the database, so readers share the
+// lock and a writer waits for them to finish.
+fn cache_key(key: &[u8]) -> anyhow::Result<CacheKey> {
+    CacheKey::try_from(key).map_err(|_| {
+        anyhow::anyhow!(
+            "Key of {} bytes in table {} exceeds the maximum of {}",
+            key.len(),
+            Table::from_prefix(key[0]).map_or("<unknown>", Table::name),
+            MAX_KEY_LEN
+        )
+    })
+}
 
 pub struct Backend {
-    cache: BTreeMap<ArrayVec<u8, 96>, Option<SmallVec<[u8; 128]>>>,
+    cache: Arc<RwLock<Cache>>,
+    // The threads holding `cache`, one entry per transaction, see `Hold`
+    holders: Mutex<Vec<(ThreadId, bool)>>,
     disk: Option<Environment<WriteMap>>,
+    // Handles to `disk`'s tables, indexed by `Table::index`, empty in memory
+    dbs: Vec<Database<'static>>,
+    // Only changed by the single writer, when the map has to grow
+    max_size: AtomicUsize,
+    auto_grow: bool,
+    // Pending writes past which a write transaction flushes, see `spill`
+    cache_limit: Option<usize>,
+    // As `cache_limit`, in bytes, see `FileOptions::auto_flush_bytes`
+    flush_bytes: Option<usize>,
+    path: Option<std::path::PathBuf>,
+    // Declared after `disk` so the environment is closed before the
+    // directory is removed
+    _spill_dir: Option<SpillDir>,
+}
+
+// A temporary directory that is removed on drop, see `Backend::spill`
+struct SpillDir(std::path::PathBuf);
+
+impl SpillDir {
+    fn new() -> anyhow::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "pyspec-db-spill-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Only the kind of backend and where it lives, the data is not read
+impl std::fmt::Debug for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match (&self.disk, &self._spill_dir) {
+            (None, _) => "memory",
+            (Some(_), None) => "file",
+            (Some(_), Some(_)) => "spill",
+        };
+        f.debug_struct("Backend")
+            .field("kind", &kind)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Backend {
     pub fn memory() -> anyhow::Result<Self> {
         Ok(Self {
-            cache: BTreeMap::new(),
+            cache: Arc::default(),
+            holders: Mutex::default(),
             disk: None,
+            dbs: Vec::new(),
+            max_size: AtomicUsize::new(0),
+            auto_grow: false,
+            cache_limit: None,
+            flush_bytes: None,
+            path: None,
+            _spill_dir: None,
+        })
+    }
+
+    // A memory backend holding `entries`, whose keys include the table prefix
+    pub fn memory_from(
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> anyhow::Result<Self> {
+        let mut cache = Cache::new();
+        for (key, value) in entries {
+            split_key(&key)?;
+            cache.insert(cache_key(&key)?, Some(SmallVec::from_vec(value)));
+        }
+        Ok(Self {
+            cache: Arc::new(RwLock::new(cache)),
+            ..Self::memory()?
+        })
+    }
+
+    // A copy of a memory backend. It takes the cache lock as a reader, so it
+    // waits for a pending write transaction and copies committed data only.
+    pub fn try_clone(&self) -> anyhow::Result<Self> {
+        anyhow::ensure!(self.disk.is_none(), "Only a memory backend can be cloned");
+        anyhow::ensure!(
+            !self.held()?.contains(&true),
+            "This thread holds a write transaction, a clone would deadlock"
+        );
+        let cache = self
+            .cache
+            .read()
+            .map_err(|_| anyhow::anyhow!("Cache lock poisoned"))?
+            .clone();
+        Ok(Self {
+            cache: Arc::new(RwLock::new(cache)),
+            ..Self::memory()?
+        })
+    }
+
+    pub fn file(path: &std::path::Path, options: &FileOptions) -> anyhow::Result<Self> {
+        Self::open_env(path, options, SyncMode::SafeNoSync)
+    }
+
+    // A file backend for data that does not fit in memory but need not be
+    // durable. It lives in a temporary directory removed on drop and is never
+    // synced. A write transaction flushes its writes to MDBX whenever more
+    // than `cache_limit` are pending, rather than holding them until
+    // `BackendTransaction::flush`.
+    pub fn spill(cache_limit: usize) -> anyhow::Result<Self> {
+        let dir = SpillDir::new()?;
+        let backend = Self::open_env(&dir.0, &FileOptions::default(), SyncMode::UtterlyNoSync)?;
+        Ok(Self {
+            cache_limit: Some(cache_limit),
+            _spill_dir: Some(dir),
+            ..backend
         })
     }
 
-    pub fn file(path: &std::path::Path) -> anyhow::Result<Self> {
+    fn open_env(
+        path: &std::path::Path,
+        options: &FileOptions,
+        sync_mode: SyncMode,
+    ) -> anyhow::Result<Self> {
+        if let Some(page_size) = options.page_size {
+            anyhow::ensure!(
+                page_size.is_power_of_two() && (256..=65536).contains(&page_size),
+                "Invalid page size {}",
+                page_size
+            );
+        }
         let mut builder = Environment::<WriteMap>::new();
         builder.set_flags(EnvironmentFlags {
             exclusive: true,
-            mode: Mode::ReadWrite {
-                sync_mode: SyncMode::SafeNoSync,
-            },
+            mode: Mode::ReadWrite { sync_mode },
             ..EnvironmentFlags::default()
         });
         builder.set_geometry(Geometry {
-            size: Some(0..(2_usize).pow(40)),
+            size: Some(0..options.max_size),
             growth_step: Some((2_isize).pow(20)),
+            page_size: options.page_size.map(PageSize::Set),
             ..Geometry::default()
         });
+        builder.set_max_dbs(Table::ALL.len());
+        let disk = builder.open(path)?;
+        if let Some(page_size) = options.page_size {
+            let existing = disk.stat()?.page_size() as usize;
+            anyhow::ensure!(
+                existing == page_size,
+                "Database has page size {}, not {}",
+                existing,
+                page_size
+            );
+        }
+        // The handles stay open for the life of the environment, so they
+        // can be used by any transaction on it, including the ones a write
+        // transaction moves to on commit or after growing the map. MDBX only
+        // hands them out from a committed transaction.
+        let dbs = {
+            let txn = disk.begin_rw_txn()?;
+            for table in Table::ALL {
+                txn.prime_for_permaopen(
+                    txn.create_db(Some(table.name()), DatabaseFlags::default())?,
+                );
+            }
+            migrate_single_table(&txn)?;
+            let (_, dbs) = txn.commit_and_rebind_open_dbs()?;
+            // Safety: the handles are bound to `disk`, which moves into the
+            // backend alongside them. They are only lent out by reference,
+            // for at most as long as the backend is borrowed, and handles
+            // need no cleanup, so none is used after `disk` is closed.
+            unsafe { std::mem::transmute::<Vec<Database<'_>>, Vec<Database<'static>>>(dbs) }
+        };
         Ok(Self {
-            cache: BTreeMap::new(),
-            disk: Some(builder.open(path)?),
+            cache: Arc::default(),
+            holders: Mutex::default(),
+            disk: Some(disk),
+            dbs,
+            max_size: AtomicUsize::new(options.max_size),
+            auto_grow: options.auto_grow,
+            cache_limit: None,
+            flush_bytes: options.auto_flush_bytes,
+            path: Some(path.to_path_buf()),
+            _spill_dir: None,
         })
     }
 
-    pub fn begin_mut(&mut self) -> anyhow::Result<BackendTransaction> {
+    // Writes a compacted copy of the committed database into `target`
+    pub fn compact(&self, target: &std::path::Path) -> anyhow::Result<()> {
+        if let Some(disk) = &self.disk {
+            std::fs::create_dir_all(target)?;
+            let dest = target.join("mdbx.dat");
+            anyhow::ensure!(!dest.exists(), "{} already exists", dest.display());
+            let dest = CString::new(
+                dest.to_str()
+                    .ok_or(anyhow::anyhow!("Failed to encode path"))?,
+            )?;
+            // Safety: `disk` outlives the call and `dest` is nul terminated
+            let rc = unsafe {
+                mdbx_sys::mdbx_env_copy(disk.env(), dest.as_ptr(), mdbx_sys::MDBX_CP_COMPACT)
+            };
+            if rc != mdbx_sys::MDBX_SUCCESS {
+                return Err(libmdbx::Error::from_err_code(rc).into());
+            }
+        }
+        Ok(())
+    }
+
+    // Commits use `SyncMode::SafeNoSync`, which keeps the database consistent
+    // but may lose the last commits on a system crash. This flushes them to
+    // disk. The memory backend has nothing to sync.
+    pub fn sync(&self) -> anyhow::Result<()> {
+        if let Some(disk) = &self.disk {
+            disk.sync(true).map_err(DbError::from)?;
+        }
+        Ok(())
+    }
+
+    // MDBX's page size, `None` in memory
+    pub fn page_size(&self) -> anyhow::Result<Option<usize>> {
         Ok(match &self.disk {
-            None => BackendTransaction {
-                cache: &mut self.cache,
-                txn: None,
-            },
+            None => None,
+            Some(disk) => Some(disk.stat()?.page_size() as usize),
+        })
+    }
+
+    // Bytes of the file in use, up to the last allocated page. This includes
+    // free pages not yet reused. The memory backend has no file, so zero.
+    pub fn physical_size(&self) -> anyhow::Result<u64> {
+        Ok(match &self.disk {
+            None => 0,
             Some(disk) => {
-                let txn = disk.begin_rw_txn()?;
-                BackendTransaction {
-                    cache: &mut self.cache,
-                    txn: Some(txn),
-                }
+                let page_size = disk.stat()?.page_size() as u64;
+                (disk.info()?.last_pgno() as u64 + 1) * page_size
             }
         })
     }
+
+    // Whether each `cache` lock the current thread holds is for writing
+    fn held(&self) -> anyhow::Result<Vec<bool>> {
+        let thread = std::thread::current().id();
+        Ok(self
+            .holders
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Holders lock poisoned"))?
+            .iter()
+            .filter(|(holder, _)| *holder == thread)
+            .map(|&(_, write)| write)
+            .collect())
+    }
+
+    fn hold(&self, write: bool) -> anyhow::Result<Hold<'_>> {
+        let entry = (std::thread::current().id(), write);
+        self.holders
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Holders lock poisoned"))?
+            .push(entry);
+        Ok(Hold {
+            holders: &self.holders,
+            entry,
+        })
+    }
+
+    // A thread reading a memory database while another waits to write gets
+    // an error rather than queueing behind the writer, which waits for it.
+    pub fn begin_ro(&self) -> anyhow::Result<BackendReadTransaction<'_>> {
+        Ok(match &self.disk {
+            None => {
+                let held = self.held()?;
+                anyhow::ensure!(
+                    !held.contains(&true),
+                    "This thread holds a write transaction, a read would deadlock"
+                );
+                let cache = if held.is_empty() {
+                    self.cache
+                        .read()
+                        .map_err(|_| anyhow::anyhow!("Cache lock poisoned"))?
+                } else {
+                    match self.cache.try_read() {
+                        Ok(cache) => cache,
+                        Err(TryLockError::Poisoned(_)) => anyhow::bail!("Cache lock poisoned"),
+                        Err(TryLockError::WouldBlock) => anyhow::bail!(
+                            "A write is waiting for this thread's read transaction, \
+                             another read would deadlock"
+                        ),
+                    }
+                };
+                BackendReadTransaction::Memory(cache, self.hold(false)?)
+            }
+            Some(disk) => BackendReadTransaction::Disk(disk, disk.begin_ro_txn()?, &self.dbs),
+        })
+    }
+
+    // MDBX allows a single write transaction at a time, a second one waits
+    // for the first to finish. In memory a writer also waits for the readers.
+    // A thread holding a transaction it would wait for gets an error.
+    pub fn begin_mut(&self) -> anyhow::Result<BackendTransaction<'_>> {
+        anyhow::ensure!(
+            self.held()?.is_empty(),
+            "This thread holds a transaction, a write would deadlock"
+        );
+        let txn = match &self.disk {
+            None => None,
+            Some(disk) => Some(disk.begin_rw_txn()?),
+        };
+        Ok(BackendTransaction {
+            cache: self
+                .cache
+                .write()
+                .map_err(|_| anyhow::anyhow!("Cache lock poisoned"))?,
+            _hold: self.hold(true)?,
+            undo: Vec::new(),
+            env: self.disk.as_ref(),
+            txn,
+            dbs: &self.dbs,
+            max_size: if self.disk.is_some() && self.auto_grow {
+                Some(&self.max_size)
+            } else {
+                None
+            },
+            journal: Vec::new(),
+            unjournaled: false,
+            keys_written: 0,
+            keys_deleted: 0,
+            cache_limit: self.cache_limit,
+            flush_bytes: self.flush_bytes,
+            cache_bytes: 0,
+        })
+    }
+}
+
+// A transaction's entry in `Backend::holders`, removed on drop
+pub struct Hold<'a> {
+    holders: &'a Mutex<Vec<(ThreadId, bool)>>,
+    entry: (ThreadId, bool),
+}
+
+impl Drop for Hold<'_> {
+    fn drop(&mut self) {
+        let mut holders = self.holders.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(i) = holders.iter().position(|holder| *holder == self.entry) {
+            holders.swap_remove(i);
+        }
+    }
+}
+
+pub enum BackendReadTransaction<'txn> {
+    Memory(RwLockReadGuard<'txn, Cache>, Hold<'txn>),
+    Disk(
+        &'txn Environment<WriteMap>,
+        Transaction<'txn, RO, WriteMap>,
+        &'txn [Database<'txn>],
+    ),
+}
+
+impl<'txn> BackendReadTransaction<'txn> {
+    pub fn get(&'txn self, key: &[u8]) -> anyhow::Result<Option<Cow<'txn, [u8]>>> {
+        Ok(match self {
+            Self::Memory(cache, _) => cache
+                .get(key)
+                .and_then(|value| value.as_ref().map(|value| Cow::from(value.as_slice()))),
+            Self::Disk(_, txn, dbs) => {
+                let (table, key) = split_key(key)?;
+                txn.get(&dbs[table.index()], key)?
+            }
+        })
+    }
+
+    // Number of write transactions committed since this snapshot was taken
+    pub fn lag(&self) -> anyhow::Result<u64> {
+        Ok(match self {
+            Self::Memory(..) => 0,
+            Self::Disk(env, txn, _) => (env.info()?.last_txnid() as u64).saturating_sub(txn.id()),
+        })
+    }
+
+    pub fn iter_prefix(&self, prefix: &[u8]) -> ReadPrefixIter<'_, 'txn> {
+        assert!(!prefix.is_empty(), "Prefix must include the table");
+        ReadPrefixIter {
+            tx: self,
+            prefix: prefix.to_vec(),
+            next: prefix.to_vec(),
+            cursor: None,
+            done: false,
+        }
+    }
+}
+
+// As `PrefixIter`, over a read transaction, which has no writes to merge
+pub struct ReadPrefixIter<'a, 'txn> {
+    tx: &'a BackendReadTransaction<'txn>,
+    prefix: Vec<u8>,
+    next: Vec<u8>,
+    cursor: Option<Cursor<'a, RO>>,
+    done: bool,
+}
+
+impl<'a, 'txn> ReadPrefixIter<'a, 'txn> {
+    fn next_entry(&mut self) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let (key, value) = match self.tx {
+                BackendReadTransaction::Memory(cache, _) => {
+                    match cache
+                        .range::<[u8], _>((Included(self.next.as_slice()), Unbounded))
+                        .next()
+                    {
+                        None => return Ok(None),
+                        Some((key, value)) => {
+                            (key.to_vec(), value.as_ref().map(|value| value.to_vec()))
+                        }
+                    }
+                }
+                BackendReadTransaction::Disk(_, txn, dbs) => {
+                    if self.cursor.is_none() {
+                        let (table, _) = split_key(&self.prefix)?;
+                        self.cursor = Some(txn.cursor(&dbs[table.index()])?);
+                    }
+                    let (table, key) = self.next.split_at(1);
+                    match self
+                        .cursor
+                        .as_mut()
+                        .unwrap()
+                        .set_range::<Vec<u8>, Vec<u8>>(key)?
+                    {
+                        None => return Ok(None),
+                        Some((key, value)) => ([table, &key].concat(), Some(value)),
+                    }
+                }
+            };
+            if !key.starts_with(&self.prefix) {
+                return Ok(None);
+            }
+            self.next.clone_from(&key);
+            self.next.push(0);
+            if let Some(value) = value {
+                return Ok(Some((key, value)));
+            }
+        }
+    }
+}
+
+impl<'a, 'txn> Iterator for ReadPrefixIter<'a, 'txn> {
+    type Item = anyhow::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.next_entry().transpose();
+        self.done = !matches!(entry, Some(Ok(_)));
+        entry
+    }
+}
+
+// Writes collected away from the transaction, e.g. by a `Walker`, so that
+// several can be prepared concurrently and applied afterwards.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StagedWrites(Cache);
+
+impl StagedWrites {
+    pub fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.0
+            .get(key)
+            .map(|value| value.as_ref().map(|value| value.as_slice()))
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.0
+            .insert(cache_key(key)?, Some(SmallVec::from_slice(value)));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.0.insert(cache_key(key)?, None);
+        Ok(())
+    }
+    pub fn num_puts(&self) -> usize {
+        self.0.values().filter(|value| value.is_some()).count()
+    }
+
+    pub fn num_deletes(&self) -> usize {
+        self.0.values().filter(|value| value.is_none()).count()
+    }
 }
 
 pub struct BackendTransaction<'txn> {
-    cache: &'txn mut BTreeMap<ArrayVec<u8, 96>, Option<SmallVec<[u8; 128]>>>,
+    cache: RwLockWriteGuard<'txn, Cache>,
+    _hold: Hold<'txn>,
+    // The memory backend has no MDBX transaction to abort, so the previous
+    // cache entries are recorded to allow a rollback.
+    undo: Vec<(CacheKey, Option<CacheValue>)>,
+    env: Option<&'txn Environment<WriteMap>>,
     txn: Option<Transaction<'txn, RW, WriteMap>>,
+    // The table handles, see `Backend::dbs`
+    dbs: &'txn [Database<'txn>],
+    // Set if the map may be grown, the journal is only kept in that case
+    // and for the final flush of `try_commit`
+    max_size: Option<&'txn AtomicUsize>,
+    journal: Vec<JournalEntry>,
+    // Whether anything was written to disk since the last commit without
+    // being journaled, so `try_commit` cannot replay it
+    unjournaled: bool,
+    // Keys written to and deleted from disk by the MDBX transaction
+    keys_written: usize,
+    keys_deleted: usize,
+    cache_limit: Option<usize>,
+    flush_bytes: Option<usize>,
+    // Approximate size of the writes in the cache, only kept up to date on
+    // disk where the cache holds nothing else
+    cache_bytes: usize,
+}
+
+// Returned by `BackendTransaction::commit_reported`, e.g. to slow an import
+// down when commits get expensive
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommitReport {
+    pub keys_written: usize,
+    pub keys_deleted: usize,
+    // Time taken by the final flush and the commit itself
+    pub duration: Duration,
 }
 
 impl<'txn> BackendTransaction<'txn> {
@@ -71,88 +683,711 @@ impl<'txn> BackendTransaction<'txn> {
         } else {
             match &self.txn {
                 None => None,
-                Some(txn) => txn.get(&txn.open_db(None)?, key)?,
+                Some(txn) => {
+                    let (table, key) = split_key(key)?;
+                    txn.get(&self.dbs[table.index()], key)?
+                }
             }
         })
     }
 
+    fn insert(&mut self, key: CacheKey, value: CacheValue) {
+        self.cache_bytes += entry_bytes(&key, &value);
+        let old_value = self.cache.insert(key.clone(), value);
+        if let Some(old_value) = &old_value {
+            self.cache_bytes = self
+                .cache_bytes
+                .saturating_sub(entry_bytes(&key, old_value));
+        }
+        if self.env.is_none() {
+            self.undo.push((key, old_value));
+        }
+    }
+
     pub fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
-        self.cache
-            .insert(key.try_into()?, Some(SmallVec::from_slice(value)));
-        Ok(())
+        self.insert(cache_key(key)?, Some(SmallVec::from_slice(value)));
+        self.spill_if_full()
     }
 
     pub fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
-        self.cache.insert(key.try_into()?, None);
+        self.insert(cache_key(key)?, None);
+        self.spill_if_full()
+    }
+
+    // Approximate size of the writes not yet flushed to MDBX. In memory they
+    // are never flushed, and this only counts the transaction's own writes.
+    pub fn pending_bytes(&self) -> usize {
+        self.cache_bytes
+    }
+
+    // Whether the transaction has written anything since it began or last
+    // committed
+    pub fn has_uncommitted_writes(&self) -> bool {
+        if self.env.is_none() {
+            return !self.undo.is_empty();
+        }
+        !self.cache.is_empty() || self.keys_written > 0 || self.keys_deleted > 0
+    }
+
+    // Bounds the cache of a backend from `Backend::spill`, or with
+    // `FileOptions::auto_flush_bytes`. The MDBX transaction stays open, so
+    // flushed writes are still read back.
+    fn spill_if_full(&mut self) -> anyhow::Result<()> {
+        if matches!(self.cache_limit, Some(limit) if self.cache.len() > limit)
+            || matches!(self.flush_bytes, Some(limit) if self.cache_bytes > limit)
+        {
+            self.flush()?;
+        }
         Ok(())
     }
 
+    pub fn iter_prefix(&self, prefix: &[u8]) -> PrefixIter<'_, 'txn> {
+        assert!(!prefix.is_empty(), "Prefix must include the table");
+        PrefixIter {
+            prefix: prefix.to_vec(),
+            next: prefix.to_vec(),
+            cache: &self.cache,
+            txn: self.txn.as_ref(),
+            dbs: self.dbs,
+            cursor: None,
+            done: false,
+        }
+    }
+
+    // The number of keys `iter_prefix` would return, e.g. to decide whether
+    // to clear the prefix in chunks. The keys on disk are counted with a
+    // cursor, without reading their values, then corrected for the writes
+    // in the cache.
+    pub fn count_prefix(&self, prefix: &[u8]) -> anyhow::Result<usize> {
+        let mut count = match &self.txn {
+            None => 0,
+            Some(txn) => count_prefix_on_disk(txn, self.dbs, prefix)?,
+        };
+        let cached = self
+            .cache
+            .range::<[u8], _>((Included(prefix), Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix));
+        for (key, value) in cached {
+            let on_disk = match &self.txn {
+                None => false,
+                Some(txn) => {
+                    let (table, key) = split_key(key)?;
+                    txn.get::<()>(&self.dbs[table.index()], key)?.is_some()
+                }
+            };
+            match (value.is_some(), on_disk) {
+                (true, false) => count += 1,
+                (false, true) => count -= 1,
+                _ => {}
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn apply(&mut self, staged: StagedWrites) {
+        for (key, value) in staged.0 {
+            self.insert(key, value);
+        }
+    }
+
     pub fn clear_prefix(&mut self, prefix: &[u8]) -> anyhow::Result<()> {
+        self.clear_prefix_bounded(prefix, usize::MAX)?;
+        Ok(())
+    }
+
+    // As `clear_prefix`, but deletes at most `limit` keys from disk so that
+    // the MDBX transaction stays small. Returns whether the prefix is now
+    // clear, if not the caller should commit and call again.
+    pub fn clear_prefix_bounded(&mut self, prefix: &[u8], limit: usize) -> anyhow::Result<bool> {
         let to_delete: Vec<_> = self
             .cache
-            .range((Excluded(ArrayVec::<u8, 96>::try_from(prefix)?), Unbounded))
+            .range((Excluded(cache_key(prefix)?), Unbounded))
             .map(|x| x.0)
             .take_while(|x| x.starts_with(prefix))
             .cloned()
             .collect();
         for key in to_delete {
-            self.cache.remove(&key);
+            let old_value = self.cache.remove(&key);
+            if let Some(old_value) = &old_value {
+                self.cache_bytes = self
+                    .cache_bytes
+                    .saturating_sub(entry_bytes(&key, old_value));
+            }
+            if self.env.is_none() {
+                self.undo.push((key, old_value));
+            }
         }
-        if let Some(txn) = &self.txn {
-            let mut cursor = txn.cursor(&txn.open_db(None)?)?;
-            let ((), ()) = match cursor.set_range(prefix)? {
-                Some(x) => x,
-                None => return Ok(()),
-            };
-            loop {
-                match cursor.next()? {
-                    None => break,
-                    Some(((), ())) => {}
+        let deleted = match &self.txn {
+            None => return Ok(true),
+            Some(txn) => clear_prefix_on_disk(txn, self.dbs, prefix, limit)?,
+        };
+        self.keys_deleted += deleted;
+        if self.max_size.is_some() {
+            self.journal
+                .push(JournalEntry::ClearPrefix(prefix.to_vec(), deleted));
+        } else if deleted > 0 {
+            self.unjournaled = true;
+        }
+        Ok(deleted < limit)
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.flush_journaled(self.max_size.is_some())
+    }
+
+    // As `flush`, moving the writes into the journal if `journal`
+    fn flush_journaled(&mut self, journal: bool) -> anyhow::Result<()> {
+        let txn = match &self.txn {
+            None => return Ok(()),
+            Some(txn) => txn,
+        };
+        let (written, deleted) = match write_to_disk(txn, self.dbs, &self.cache) {
+            Err(err) if is_map_full(&err) && self.max_size.is_some() => {
+                self.grow_and_replay()?;
+                write_to_disk(self.txn.as_ref().unwrap(), self.dbs, &self.cache)?
+            }
+            res => res?,
+        };
+        self.keys_written += written;
+        self.keys_deleted += deleted;
+        self.cache_bytes = 0;
+        if journal {
+            self.journal
+                .push(JournalEntry::Flush(std::mem::take(&mut *self.cache)));
+        } else {
+            self.unjournaled |= !self.cache.is_empty();
+            self.cache.clear();
+        }
+        Ok(())
+    }
+
+    // MDBX aborts a transaction that runs out of space, so the map is grown
+    // and everything the transaction wrote is replayed into a new one.
+    fn grow_and_replay(&mut self) -> anyhow::Result<()> {
+        let env = self.env.unwrap();
+        let max_size = self.max_size.unwrap();
+        drop(self.txn.take());
+        let new_size = max_size.load(Ordering::Relaxed).saturating_mul(2);
+        max_size.store(new_size, Ordering::Relaxed);
+        // Safety: the environment is open and this thread holds no
+        // transaction on it
+        let rc = unsafe {
+            mdbx_sys::mdbx_env_set_geometry(env.env(), -1, -1, new_size as isize, -1, -1, -1)
+        };
+        if rc != mdbx_sys::MDBX_SUCCESS {
+            return Err(DbError::from(libmdbx::Error::from_err_code(rc)).into());
+        }
+        self.replay()
+    }
+
+    // Begins a new MDBX transaction holding everything in the journal
+    fn replay(&mut self) -> anyhow::Result<()> {
+        let env = self.env.unwrap();
+        drop(self.txn.take());
+        let txn = env.begin_rw_txn()?;
+        for entry in &self.journal {
+            match entry {
+                JournalEntry::Flush(writes) => {
+                    write_to_disk(&txn, self.dbs, writes)?;
+                }
+                JournalEntry::ClearPrefix(prefix, deleted) => {
+                    clear_prefix_on_disk(&txn, self.dbs, prefix, *deleted)?;
                 }
-                match cursor.get_current::<Cow<[u8]>, ()>()? {
-                    None => break,
-                    Some((key, ())) => {
-                        if !key.starts_with(prefix) {
-                            break;
-                        }
-                    }
-                };
-                cursor.del(WriteFlags::default())?;
             }
         }
+        self.txn = Some(txn);
         Ok(())
     }
 
-    pub fn flush(&mut self) -> anyhow::Result<()> {
-        match &self.txn {
-            None => Ok(()),
-            Some(txn) => {
-                let db = txn.open_db(None)?;
-                let mut cursor = txn.cursor(&db)?;
-                for (key, value) in self.cache.iter() {
-                    if let Some(value) = value {
-                        cursor.put(key, value, WriteFlags::default())?;
+    fn commit_txn(&mut self) -> anyhow::Result<()> {
+        if let Some(txn) = self.txn.take() {
+            match txn.commit() {
+                Err(libmdbx::Error::MapFull) if self.max_size.is_some() => {
+                    self.grow_and_replay()?;
+                    self.txn.take().unwrap().commit().map_err(DbError::from)?;
+                }
+                res => {
+                    res.map_err(DbError::from)?;
+                }
+            }
+        }
+        self.journal.clear();
+        Ok(())
+    }
+
+    // A failed commit aborts the MDBX transaction and discards the writes
+    // since the transaction began or last committed, as `rollback` does, so
+    // nothing of them is persisted or left in the cache for later
+    // transactions. The transaction can then be repeated from the start.
+    fn commit_or_abort(&mut self) -> anyhow::Result<()> {
+        let res = self.flush().and_then(|()| self.commit_txn());
+        if res.is_err() {
+            self.abort();
+        }
+        res
+    }
+
+    // On error the transaction is left without an MDBX transaction and must
+    // be discarded.
+    pub fn commit_and_continue(&mut self) -> anyhow::Result<()> {
+        self.commit_or_abort()?;
+        self.continue_after_commit()
+    }
+
+    // As `commit_and_continue`, but a failed commit keeps the writes since
+    // the last commit, in the cache or replayed from the journal into a new
+    // MDBX transaction, so they can still be read and the commit retried,
+    // e.g. after freeing space. Writes flushed without `auto_grow` are not
+    // journaled, so if any were the failed commit aborts as `commit` does.
+    pub fn try_commit(&mut self) -> anyhow::Result<()> {
+        if self.env.is_none() || self.unjournaled {
+            return self.commit_and_continue();
+        }
+        let res = self.flush_journaled(true).and_then(|()| self.commit_txn());
+        if let Err(err) = res {
+            self.replay().inspect_err(|_| self.abort())?;
+            return Err(err);
+        }
+        self.continue_after_commit()
+    }
+
+    fn continue_after_commit(&mut self) -> anyhow::Result<()> {
+        self.undo.clear();
+        self.unjournaled = false;
+        if let Some(env) = self.env {
+            self.txn = Some(env.begin_rw_txn()?);
+        }
+        self.keys_written = 0;
+        self.keys_deleted = 0;
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        self.commit_or_abort()
+    }
+
+    // As `commit`, also reporting the keys the transaction wrote and deleted
+    // since it began or last committed
+    pub fn commit_reported(mut self) -> anyhow::Result<CommitReport> {
+        let start = Instant::now();
+        self.commit_or_abort()?;
+        let (keys_written, keys_deleted) = self.keys_changed();
+        Ok(CommitReport {
+            keys_written,
+            keys_deleted,
+            duration: start.elapsed(),
+        })
+    }
+
+    fn keys_changed(&self) -> (usize, usize) {
+        if self.env.is_some() {
+            return (self.keys_written, self.keys_deleted);
+        }
+        // In memory every key changed is in the undo log, its first entry
+        // holds the value from before the transaction
+        let mut before = BTreeMap::new();
+        for (key, old_value) in &self.undo {
+            before.entry(key).or_insert(old_value);
+        }
+        let mut res = (0, 0);
+        for (key, old_value) in before {
+            match (old_value, self.cache.get(key)) {
+                (_, Some(Some(_))) => res.0 += 1,
+                (Some(Some(_)), _) => res.1 += 1,
+                _ => {}
+            }
+        }
+        res
+    }
+
+    pub fn rollback(mut self) -> anyhow::Result<()> {
+        self.abort();
+        Ok(())
+    }
+
+    // As `rollback`, without ending the transaction, which is then left
+    // without an MDBX transaction and must be discarded
+    pub fn abort(&mut self) {
+        for (key, old_value) in self.undo.drain(..).rev() {
+            match old_value {
+                None => self.cache.remove(&key),
+                Some(old_value) => self.cache.insert(key, old_value),
+            };
+        }
+        if self.env.is_some() {
+            self.cache.clear();
+        }
+        self.journal.clear();
+        self.unjournaled = false;
+        // Dropping the MDBX transaction aborts it
+        self.txn = None;
+    }
+}
+
+// Iterates in key order over the entries starting with `prefix`, merging
+// writes still in the cache over what has been flushed to disk. Every step
+// searches from `next`, so `seek` can skip ranges without reading them.
+pub struct PrefixIter<'a, 'txn> {
+    prefix: Vec<u8>,
+    next: Vec<u8>,
+    cache: &'a Cache,
+    txn: Option<&'a Transaction<'txn, RW, WriteMap>>,
+    dbs: &'a [Database<'txn>],
+    cursor: Option<Cursor<'a, RW>>,
+    done: bool,
+}
+
+impl<'a, 'txn> PrefixIter<'a, 'txn> {
+    // Continues from the first key at or after `key`
+    pub fn seek(&mut self, key: &[u8]) {
+        self.next = key.to_vec();
+        self.done = false;
+    }
+
+    fn next_entry(&mut self) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let cached = self
+                .cache
+                .range::<[u8], _>((Included(self.next.as_slice()), Unbounded))
+                .next()
+                .map(|(key, value)| (key.to_vec(), value.as_ref().map(|value| value.to_vec())));
+            let on_disk = match self.txn {
+                None => None,
+                Some(txn) => {
+                    if self.cursor.is_none() {
+                        let (table, _) = split_key(&self.prefix)?;
+                        self.cursor = Some(txn.cursor(&self.dbs[table.index()])?);
+                    }
+                    let (table, key) = self.next.split_at(1);
+                    self.cursor
+                        .as_mut()
+                        .unwrap()
+                        .set_range::<Vec<u8>, Vec<u8>>(key)?
+                        .map(|(key, value)| ([table, &key].concat(), Some(value)))
+                }
+            };
+            // A cached entry shadows the one on disk, deletions included
+            let (key, value) = match (cached, on_disk) {
+                (None, None) => return Ok(None),
+                (Some(entry), None) | (None, Some(entry)) => entry,
+                (Some(cached), Some(on_disk)) => {
+                    if cached.0 <= on_disk.0 {
+                        cached
                     } else {
-                        let x: Option<()> = cursor.set(key)?;
-                        if x.is_some() {
-                            txn.del(&db, key, None)?;
-                        }
+                        on_disk
                     }
                 }
-                self.cache.clear();
-                Ok(())
+            };
+            if !key.starts_with(&self.prefix) {
+                return Ok(None);
+            }
+            self.next.clone_from(&key);
+            self.next.push(0);
+            if let Some(value) = value {
+                return Ok(Some((key, value)));
             }
         }
     }
+}
 
-    pub fn commit(mut self) -> anyhow::Result<()> {
-        self.flush()?;
-        match self.txn {
-            None => Ok(()),
-            Some(txn) => {
-                txn.commit()?;
-                Ok(())
+impl<'a, 'txn> Iterator for PrefixIter<'a, 'txn> {
+    type Item = anyhow::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.next_entry().transpose();
+        self.done = !matches!(entry, Some(Ok(_)));
+        entry
+    }
+}
+
+// Version 0 files kept every key, with its table prefix, in the unnamed
+// database. This moves them into their tables, leaving the version for
+// `Db::open` to update. The unnamed database also holds a record per named
+// table, keyed by the table's name, and no name starts with a prefix.
+fn migrate_single_table(txn: &Transaction<'_, RW, WriteMap>) -> anyhow::Result<()> {
+    let main_db = txn.open_db(None)?;
+    if txn.get::<()>(&main_db, b"\0version")?.is_none() {
+        return Ok(());
+    }
+    let dbs = Table::ALL
+        .iter()
+        .map(|table| txn.open_db(Some(table.name())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut cursor = txn.cursor(&main_db)?;
+    let mut entry = cursor.first::<Vec<u8>, Vec<u8>>()?;
+    while let Some((key, value)) = entry {
+        if let Some(table) = key
+            .first()
+            .and_then(|&prefix| Table::from_prefix(prefix).ok())
+        {
+            txn.put(
+                &dbs[table.index()],
+                &key[1..],
+                &value,
+                WriteFlags::default(),
+            )?;
+            cursor.del(WriteFlags::default())?;
+        }
+        entry = cursor.next()?;
+    }
+    Ok(())
+}
+
+// Deletes up to `limit` keys starting with `prefix`, but not `prefix`
+// itself, and returns how many were deleted.
+fn clear_prefix_on_disk(
+    txn: &Transaction<'_, RW, WriteMap>,
+    dbs: &[Database],
+    prefix: &[u8],
+    limit: usize,
+) -> anyhow::Result<usize> {
+    let (table, prefix) = split_key(prefix)?;
+    let mut cursor = txn.cursor(&dbs[table.index()])?;
+    // If `prefix` itself is missing the cursor starts on a key to delete
+    let mut skip = match cursor.set_range::<Cow<[u8]>, ()>(prefix)? {
+        Some((key, ())) => key == prefix,
+        None => return Ok(0),
+    };
+    let mut deleted = 0;
+    while deleted < limit {
+        if skip {
+            match cursor.next()? {
+                None => break,
+                Some(((), ())) => {}
+            }
+        }
+        skip = true;
+        match cursor.get_current::<Cow<[u8]>, ()>()? {
+            None => break,
+            Some((key, ())) => {
+                if !key.starts_with(prefix) {
+                    break;
+                }
+            }
+        };
+        cursor.del(WriteFlags::default())?;
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+fn count_prefix_on_disk(
+    txn: &Transaction<'_, RW, WriteMap>,
+    dbs: &[Database],
+    prefix: &[u8],
+) -> anyhow::Result<usize> {
+    let (table, prefix) = split_key(prefix)?;
+    let mut cursor = txn.cursor(&dbs[table.index()])?;
+    let mut count = 0;
+    let mut entry = cursor.set_range::<Cow<[u8]>, ()>(prefix)?;
+    while let Some((key, ())) = entry {
+        if !key.starts_with(prefix) {
+            break;
+        }
+        count += 1;
+        entry = cursor.next()?;
+    }
+    Ok(count)
+}
+
+fn entry_bytes(key: &CacheKey, value: &CacheValue) -> usize {
+    key.len() + value.as_ref().map_or(0, |value| value.len())
+}
+
+// Writes go to disk in ascending key order, the order of the cache, however
+// they were made. The cache only holds the last write of each key, so what
+// ends up on disk depends on the final value of each key alone. A deletion
+// looks the key up first and is skipped if it is missing. Returns the number
+// of keys written and deleted.
+fn write_to_disk(
+    txn: &Transaction<'_, RW, WriteMap>,
+    dbs: &[Database],
+    writes: &Cache,
+) -> anyhow::Result<(usize, usize)> {
+    let mut cursors = dbs
+        .iter()
+        .map(|db| txn.cursor(db))
+        .collect::<Result<Vec<_>, _>>()?;
+    // The writes are sorted, so once a key is past the end of its table every
+    // following put to that table can be appended, which saves MDBX searching
+    // for the insert position. Deletes don't move the last key back, it can
+    // only be ahead of the table's real end, so an append is never out of
+    // order.
+    let mut last_keys = cursors
+        .iter_mut()
+        .map(|cursor| Ok(cursor.last::<Vec<u8>, ()>()?.map(|(key, ())| key)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (mut written, mut deleted) = (0, 0);
+    for (key, value) in writes.iter() {
+        let (table, key) = split_key(key)?;
+        let cursor = &mut cursors[table.index()];
+        let last_key = &mut last_keys[table.index()];
+        if let Some(value) = value {
+            written += 1;
+            let append = match last_key {
+                None => true,
+                Some(last_key) => key > last_key.as_slice(),
+            };
+            if append {
+                cursor
+                    .put(key, value, WriteFlags::APPEND)
+                    .map_err(DbError::from)?;
+                *last_key = Some(key.to_vec());
+            } else {
+                cursor
+                    .put(key, value, WriteFlags::default())
+                    .map_err(DbError::from)?;
+            }
+        } else {
+            let x: Option<()> = cursor.set(key)?;
+            if x.is_some() {
+                txn.del(&dbs[table.index()], key, None)
+                    .map_err(DbError::from)?;
+                deleted += 1;
             }
         }
     }
+    Ok((written, deleted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_count_prefix(backend: &Backend) {
+        let key = |slot: u8| [Table::State as u8, 1, slot];
+        let mut tx = backend.begin_mut().unwrap();
+        for slot in 0..10 {
+            tx.put(&key(slot), &[slot]).unwrap();
+        }
+        tx.put(&[Table::State as u8, 2, 0], &[0]).unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = backend.begin_mut().unwrap();
+        let prefix = [Table::State as u8, 1];
+        assert_eq!(tx.count_prefix(&prefix).unwrap(), 10);
+        // Tombstones over keys on disk, a key only in the cache, an
+        // overwrite and a delete of a missing key
+        tx.delete(&key(0)).unwrap();
+        tx.delete(&key(5)).unwrap();
+        tx.put(&key(20), &[20]).unwrap();
+        tx.put(&key(3), &[30]).unwrap();
+        tx.delete(&key(21)).unwrap();
+        assert_eq!(tx.count_prefix(&prefix).unwrap(), 9);
+        assert_eq!(
+            tx.count_prefix(&prefix).unwrap(),
+            tx.iter_prefix(&prefix).count()
+        );
+        tx.flush().unwrap();
+        tx.put(&key(22), &[22]).unwrap();
+        assert_eq!(tx.count_prefix(&prefix).unwrap(), 10);
+        assert_eq!(tx.count_prefix(&[Table::State as u8]).unwrap(), 11);
+        assert_eq!(tx.count_prefix(&[Table::Code as u8]).unwrap(), 0);
+    }
+
+    // Every entry of every table
+    fn contents(backend: &Backend) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let tx = backend.begin_mut().unwrap();
+        Table::ALL
+            .iter()
+            .flat_map(|table| tx.iter_prefix(&[table.prefix()]).collect::<Vec<_>>())
+            .collect::<anyhow::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_write_order() {
+        let ops: Vec<(Vec<u8>, Option<Vec<u8>>)> = (0u8..50)
+            .map(|i| {
+                let table = Table::ALL[i as usize % Table::ALL.len()];
+                let value = (i % 7 != 0).then(|| vec![i; i as usize]);
+                (vec![table.prefix(), i / 3, i], value)
+            })
+            .collect();
+        let mut results = Vec::new();
+        for reverse in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let backend = Backend::file(dir.path(), &FileOptions::default()).unwrap();
+            let mut tx = backend.begin_mut().unwrap();
+            // Overwritten below, and deleted on disk by the keys with no value
+            for (key, _) in &ops {
+                tx.put(key, b"old").unwrap();
+            }
+            tx.commit().unwrap();
+
+            let mut ordered = ops.clone();
+            if reverse {
+                ordered.reverse();
+            }
+            let mut tx = backend.begin_mut().unwrap();
+            for (i, (key, value)) in ordered.iter().enumerate() {
+                // A stale write first, in a different order each time
+                if reverse {
+                    tx.delete(key).unwrap();
+                } else {
+                    tx.put(key, b"stale").unwrap();
+                }
+                match value {
+                    Some(value) => tx.put(key, value).unwrap(),
+                    None => tx.delete(key).unwrap(),
+                }
+                if reverse && i == 25 {
+                    tx.flush().unwrap();
+                }
+            }
+            tx.commit().unwrap();
+            results.push(contents(&backend));
+        }
+        let mut expected: Vec<_> = ops
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        expected.sort();
+        assert_eq!(results[0], expected);
+        assert_eq!(results[1], expected);
+    }
+
+    #[test]
+    fn test_append_after_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Backend::file(dir.path(), &FileOptions::default()).unwrap();
+        let state = |i: u8| [Table::State as u8, i];
+        let code = |i: u8| [Table::Code as u8, i];
+        let mut tx = backend.begin_mut().unwrap();
+        for i in [1, 3, 5] {
+            tx.put(&state(i), &[i]).unwrap();
+        }
+        tx.put(&code(1), &[1]).unwrap();
+        tx.commit().unwrap();
+
+        // Deleting the last key of a table leaves the remembered last key
+        // behind the table's real end, the puts after it must still land
+        let mut tx = backend.begin_mut().unwrap();
+        tx.put(&state(4), &[4]).unwrap();
+        tx.delete(&state(5)).unwrap();
+        tx.put(&state(6), &[6]).unwrap();
+        tx.put(&state(7), &[7]).unwrap();
+        tx.delete(&code(1)).unwrap();
+        tx.put(&code(2), &[2]).unwrap();
+        tx.flush().unwrap();
+        tx.delete(&state(7)).unwrap();
+        tx.put(&state(8), &[8]).unwrap();
+        tx.commit().unwrap();
+
+        let expected: Vec<_> = [state(1), state(3), state(4), state(6), state(8), code(2)]
+            .into_iter()
+            .map(|key| (key.to_vec(), vec![key[1]]))
+            .collect();
+        assert_eq!(contents(&backend), expected);
+    }
+
+    #[test]
+    fn test_count_prefix() {
+        check_count_prefix(&Backend::memory().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        check_count_prefix(&Backend::file(dir.path(), &FileOptions::default()).unwrap());
+    }
 }
@@ -0,0 +1,2101 @@
+use anyhow::Context;
+use ethereum_types::{H160, H256, U256};
+use rlp::RlpStream;
+use smallvec::SmallVec;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{remove_dir, remove_dir_all, remove_file};
+use std::io::BufRead;
+use std::sync::Arc;
+
+pub use crate::backend::CommitReport;
+use crate::backend::{
+    Backend, BackendReadTransaction, BackendTransaction, FileOptions, PrefixIter, StagedWrites,
+    DEFAULT_MAX_SIZE,
+};
+pub use crate::error::DbError;
+pub use crate::keys::MAX_RAW_KEY_LEN;
+use crate::keys::{
+    account_key, account_key_address, code_key, metadata_key, preimage_key, raw_key,
+    storage_end_key, storage_key, storage_key_address, storage_trie_prefix, ACCOUNT_TRIE_PREFIX,
+    CODE_PREFIX, METADATA_PREFIX, NODES_PREFIX, PREIMAGES_PREFIX, STATE_PREFIX, TRIE_PREFIX,
+};
+use crate::proof::decode_storage_value;
+use crate::state_test::{self, TestAccount, TestState};
+pub use crate::structs::Account;
+use crate::structs::{
+    get_internal_key, get_packed_key, marshal_storage, unmarshal_storage, PackedKey,
+};
+pub use crate::util::{code_hash, keccak256, keccak256_into, EMPTY_CODE_HASH};
+use crate::walk::{
+    get_at_root, leaf_at_path, leaves_from, proof_at_root, root_node_rlp, sort_dirty_list,
+    trie_path, trie_stats, DirtyList, LeafIter, Walker, EMPTY_TRIE_ROOT,
+};
+pub use crate::walk::{NodeCounts, TrieStats, TrieStep};
+
+// Accounts keyed by address hash with their proof, see
+// `MutableTransaction::account_range_proof`
+pub type AccountRange = (Vec<(H256, Account)>, Vec<Vec<u8>>);
+
+// Version of the layout on disk, checked when a file database is opened
+pub const CURRENT_VERSION: u32 = 1;
+
+// `CURRENT_VERSION` as stored, in decimal
+pub static DB_VERSION: &[u8] = b"1";
+
+// Metadata key set while `destroy_storage_in_batches` runs, holding the
+// address and the batch size
+static DESTROYING_STORAGE: &[u8] = b"destroying_storage";
+
+// EIP-170 limit on deployed contract code
+pub const MAX_CODE_SIZE: usize = 24576;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbOptions {
+    // If set, `store_code` rejects longer code, e.g. `Some(MAX_CODE_SIZE)`.
+    // Off by default since states from before EIP-170 may exceed it.
+    pub max_code_size: Option<usize>,
+    // Upper bound on the size of the database file, writing past it fails
+    // with `DbError::MapFull`
+    pub max_size: usize,
+    // Instead of failing with `DbError::MapFull`, double `max_size` and retry.
+    // The whole map is reserved as address space, so growing without bound
+    // can exhaust it on 32 bit targets. Every write of a transaction is kept
+    // in memory until it commits, to be replayed if the map has to grow.
+    pub auto_grow: bool,
+    // Store `keccak256(address) -> address` and `keccak256(slot) -> slot` so
+    // trie keys can be mapped back with `preimage`. Costs roughly as much
+    // space again as the state itself.
+    pub record_preimages: bool,
+    // Hash code read by `code_from_hash` and fail with `DbError::CorruptCode`
+    // if it does not match, to catch corruption on disk
+    pub verify_code_on_read: bool,
+    // Flush a write transaction's writes to disk once about this many bytes
+    // are pending, to bound its memory during a large import. The
+    // transaction still reads its own writes. With `auto_grow` the flushed
+    // writes are kept for replay, so memory is not bounded. Has no effect in
+    // memory.
+    pub auto_flush_bytes: Option<usize>,
+    // MDBX's page size, see `FileOptions::page_size`. It can only be chosen
+    // when the database is created.
+    pub page_size: Option<usize>,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            max_code_size: None,
+            max_size: DEFAULT_MAX_SIZE,
+            auto_grow: false,
+            record_preimages: false,
+            verify_code_on_read: false,
+            auto_flush_bytes: None,
+            page_size: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Db {
+    backend: Backend,
+    options: DbOptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opened {
+    pub created: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateRootSummary {
+    pub root: H256,
+    pub accounts_flushed: usize,
+    // Storage tries with changed slots or destroyed storage
+    pub storage_tries_touched: usize,
+    // Storage tries read to find their root, the others were known
+    pub storage_tries_walked: usize,
+    pub nodes_written: usize,
+    pub nodes_deleted: usize,
+}
+
+// Returned by `Db::space_report`. The logical sizes are summed over values
+// only, keys are left out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceReport {
+    pub account_bytes: u64,
+    pub storage_bytes: u64,
+    pub code_bytes: u64,
+    pub trie_node_bytes: u64,
+    pub physical_bytes: u64,
+}
+
+// A change made by a transaction, see `MutableTransaction::drain_pending`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PendingChange {
+    // Every storage slot of the account was deleted
+    Destroyed {
+        address: H160,
+    },
+    // The account was set, possibly to its previous value
+    Account {
+        address: H160,
+        new: Option<Account>,
+    },
+    Storage {
+        address: H160,
+        slot: H256,
+        value: U256,
+    },
+}
+
+// The pending changes flushed by `MutableTransaction::state_root_retaining`,
+// as they were set, so values equal to those on disk are included
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Changeset {
+    pub accounts: HashMap<H160, Option<Account>>,
+    pub storage: HashMap<H160, HashMap<H256, U256>>,
+    pub destroyed_storage: HashSet<H160>,
+    pub hashed_accounts: HashMap<H256, Option<Account>>,
+    pub hashed_storage: HashMap<H256, HashMap<H256, U256>>,
+}
+
+// Blocks of changes prepared ahead of committing them, e.g. by a thread
+// executing blocks while another validates them, see `Db::commit_staged`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StagingArea {
+    blocks: Vec<(Vec<PendingChange>, Option<H256>)>,
+}
+
+impl StagingArea {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Stages a block applied after those staged before it, checked against
+    // `expected_root` when given
+    pub fn push(
+        &mut self,
+        changes: impl IntoIterator<Item = PendingChange>,
+        expected_root: Option<H256>,
+    ) {
+        self.blocks
+            .push((changes.into_iter().collect(), expected_root));
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+// Returned by `MutableTransaction::commit_if_root`, recover it with
+// `anyhow::Error::downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateRootMismatch {
+    pub expected: H256,
+    pub got: H256,
+    // The accounts changed since the state was last flushed, one of which is
+    // likely wrong, in the order of their address hash, as in the trie.
+    // Accounts set by hash are left out.
+    pub changed_accounts: Vec<H160>,
+}
+
+impl std::fmt::Display for StateRootMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "State root mismatch expected: {:?}, got: {:?}",
+            self.expected, self.got
+        )?;
+        if !self.changed_accounts.is_empty() {
+            let shown = self.changed_accounts.len().min(MISMATCH_ACCOUNTS_SHOWN);
+            write!(
+                f,
+                ", changed accounts: {:?}",
+                &self.changed_accounts[..shown]
+            )?;
+            if shown < self.changed_accounts.len() {
+                write!(f, " and {} more", self.changed_accounts.len() - shown)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StateRootMismatch {}
+
+impl Db {
+    pub fn file(path: &std::path::Path) -> anyhow::Result<Self> {
+        Ok(Self::open(path)?.0)
+    }
+
+    pub fn open(path: &std::path::Path) -> anyhow::Result<(Self, Opened)> {
+        Self::open_with_options(path, DbOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: &std::path::Path,
+        options: DbOptions,
+    ) -> anyhow::Result<(Self, Opened)> {
+        std::fs::create_dir_all(path)?;
+        let backend = Backend::file(
+            path,
+            &FileOptions {
+                max_size: options.max_size,
+                auto_grow: options.auto_grow,
+                auto_flush_bytes: options.auto_flush_bytes,
+                page_size: options.page_size,
+            },
+        )?;
+
+        let self_ = Self { backend, options };
+
+        let mut tx = self_.begin_mut()?;
+        let created = match tx.metadata(b"version")? {
+            None => {
+                tx.set_metadata(b"version", DB_VERSION)?;
+                true
+            }
+            Some(version) => {
+                let version = parse_version(&version)?;
+                anyhow::ensure!(
+                    version == 0 || version == CURRENT_VERSION,
+                    "Wrong DB_VERSION expected: {}, got: {}",
+                    CURRENT_VERSION,
+                    version,
+                );
+                // `Backend::file` has moved a version 0 file's keys into
+                // their tables, which is all version 1 changed
+                if version == 0 {
+                    tx.set_metadata(b"version", DB_VERSION)?;
+                }
+                false
+            }
+        };
+        if let Some(marker) = tx.metadata(DESTROYING_STORAGE)? {
+            let (address, batch_size) = parse_destroy_marker(&marker)?;
+            tx.destroy_storage_in_batches(address, batch_size)?;
+        }
+        tx.commit()?;
+
+        Ok((self_, Opened { created }))
+    }
+
+    // The version the database was created with. A memory database stores
+    // none and is always `CURRENT_VERSION`.
+    pub fn version(&self) -> anyhow::Result<u32> {
+        match self.begin_ro()?.metadata(b"version")? {
+            None => Ok(CURRENT_VERSION),
+            Some(version) => parse_version(&version),
+        }
+    }
+
+    pub fn open_existing(path: &std::path::Path) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            path.join("mdbx.dat").is_file(),
+            "No database found at {}",
+            path.display()
+        );
+        Self::file(path)
+    }
+
+    pub fn memory() -> anyhow::Result<Self> {
+        Self::memory_with_options(DbOptions::default())
+    }
+
+    pub fn memory_with_options(options: DbOptions) -> anyhow::Result<Self> {
+        Ok(Self {
+            backend: Backend::memory()?,
+            options,
+        })
+    }
+
+    // Seeds a memory database with raw key-value pairs, e.g. fixture trie
+    // nodes, see `keys` for the layout
+    pub fn memory_from(
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> anyhow::Result<Self> {
+        Self::memory_from_with_options(entries, DbOptions::default())
+    }
+
+    pub fn memory_from_with_options(
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+        options: DbOptions,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            backend: Backend::memory_from(entries)?,
+            options,
+        })
+    }
+
+    // Behaves like `memory`, with no version check or durability, but keeps
+    // the data in a temporary MDBX environment deleted on drop so it is not
+    // bounded by memory. A write transaction holds at most `cache_limit`
+    // writes in memory before flushing them to the environment.
+    pub fn open_in_memory_with_disk_spill(cache_limit: usize) -> anyhow::Result<Self> {
+        Ok(Self {
+            backend: Backend::spill(cache_limit)?,
+            options: DbOptions::default(),
+        })
+    }
+
+    // An independent copy of a memory database, e.g. to branch the state in
+    // tests. Fails for a file database, use `compact` to copy one instead.
+    pub fn try_clone(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            backend: self.backend.try_clone()?,
+            options: self.options.clone(),
+        })
+    }
+
+    // A memory database holding `accounts`, committed, e.g.
+    // `Db::try_from_iter([(address, Account::eoa(0, balance))])?`. Also
+    // available as `collect`, which panics on error.
+    pub fn try_from_iter(
+        accounts: impl IntoIterator<Item = (H160, Account)>,
+    ) -> anyhow::Result<Self> {
+        let mut db = Self::memory()?;
+        db.try_extend(accounts)?;
+        Ok(db)
+    }
+
+    // Sets `accounts` and commits, see `try_from_iter`. Also available as
+    // `extend`, which panics on error.
+    pub fn try_extend(
+        &mut self,
+        accounts: impl IntoIterator<Item = (H160, Account)>,
+    ) -> anyhow::Result<()> {
+        let mut txn = self.begin_mut()?;
+        for (address, account) in accounts {
+            txn.set_account(address, Some(account));
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn memory_shared() -> anyhow::Result<SharedDb> {
+        Ok(SharedDb {
+            db: Arc::new(Self::memory()?),
+        })
+    }
+
+    // Removes the database at `path`, failing on any file other than MDBX's,
+    // see `delete_permissive`
+    pub fn delete(path: &std::path::Path) -> anyhow::Result<()> {
+        if path.exists() {
+            for dir_entry in path.read_dir()? {
+                let dir_entry = dir_entry?;
+                if ["mdbx.dat", "mdbx.lck"].contains(
+                    &dir_entry
+                        .path()
+                        .file_name()
+                        .unwrap()
+                        .to_str()
+                        .ok_or(anyhow::anyhow!("Failed to decode filename"))?,
+                ) {
+                    remove_file(dir_entry.path())?;
+                } else {
+                    anyhow::bail!("Unexpected file in DB: {}", dir_entry.path().display());
+                }
+            }
+            remove_dir(path)?;
+        }
+        Ok(())
+    }
+
+    // As `delete`, but also removes stray files, e.g. left by a crash. The
+    // directory must still look like a database: it must hold `mdbx.dat` and
+    // no subdirectories.
+    pub fn delete_permissive(path: &std::path::Path) -> anyhow::Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            path.join("mdbx.dat").is_file(),
+            "Not a DB, mdbx.dat is missing: {}",
+            path.display()
+        );
+        for dir_entry in path.read_dir()? {
+            let dir_entry = dir_entry?;
+            anyhow::ensure!(
+                !dir_entry.file_type()?.is_dir(),
+                "Unexpected directory in DB: {}",
+                dir_entry.path().display()
+            );
+        }
+        remove_dir_all(path)?;
+        Ok(())
+    }
+
+    // Writes a defragmented copy of the database to the directory `target`,
+    // which can then replace the original to reclaim space freed by
+    // deletions. Only committed data is copied, a write transaction in
+    // progress is left out. The memory backend has nothing to reclaim, so this
+    // does nothing.
+    pub fn compact(&self, target: &std::path::Path) -> anyhow::Result<()> {
+        self.backend.compact(target)
+    }
+
+    // Makes every committed transaction durable, so that reopening after a
+    // crash recovers the last committed state, see `Backend::sync`
+    pub fn checkpoint(&self) -> anyhow::Result<()> {
+        self.backend.sync()
+    }
+
+    // MDBX's page size, fixed when the database was created. A memory
+    // database has no pages and reports `None`.
+    pub fn page_size(&self) -> anyhow::Result<Option<usize>> {
+        self.backend.page_size()
+    }
+
+    // Compares the size of the committed state with the space the file
+    // takes, to tell when `compact` is worth it. This is a full scan of the
+    // state, the code and the trie nodes, so it is slow on a large database.
+    // It reads a snapshot, so on disk writers are not held up, but like any
+    // long lived reader it keeps MDBX from reusing pages freed meanwhile. A
+    // memory database has no file and reports zero `physical_bytes`.
+    pub fn space_report(&self) -> anyhow::Result<SpaceReport> {
+        let tx = self.backend.begin_ro()?;
+        let mut report = SpaceReport {
+            physical_bytes: self.backend.physical_size()?,
+            ..SpaceReport::default()
+        };
+        for item in tx.iter_prefix(&[STATE_PREFIX]) {
+            let (key, value) = item?;
+            if account_key_address(&key).is_some() {
+                report.account_bytes += value.len() as u64;
+            } else {
+                report.storage_bytes += value.len() as u64;
+            }
+        }
+        for item in tx.iter_prefix(&[CODE_PREFIX]) {
+            report.code_bytes += item?.1.len() as u64;
+        }
+        for item in tx.iter_prefix(&[TRIE_PREFIX]) {
+            report.trie_node_bytes += item?.1.len() as u64;
+        }
+        Ok(report)
+    }
+
+    // Deletes the trie nodes last written in an epoch before `epoch`, see
+    // `MutableTransaction::set_epoch`. Nodes written without an epoch are
+    // older than every epoch. The flat state is kept, but reads through the
+    // trie, such as `try_account_at` and proofs, fail on pruned paths, and a
+    // state root computed after writing to a pruned path is wrong. Returns
+    // the number of nodes deleted.
+    #[cfg(feature = "node-epochs")]
+    pub fn prune_older_than(&self, epoch: u8) -> anyhow::Result<u64> {
+        let mut tx = self.backend.begin_mut()?;
+        let mut stale = Vec::new();
+        for item in tx.iter_prefix(&[crate::keys::TRIE_PREFIX]) {
+            let (key, value) = item?;
+            if crate::trie_core::node_epoch(&value).unwrap_or(0) < epoch {
+                stale.push(key);
+            }
+        }
+        for key in &stale {
+            tx.delete(key)?;
+        }
+        tx.commit()?;
+        Ok(stale.len() as u64)
+    }
+
+    // Applies the staged blocks in order in one transaction, each on top of
+    // the ones before, and returns the state root after each. If a root does
+    // not match the expected one, this fails with `StateRootMismatch` and
+    // nothing is committed.
+    pub fn commit_staged(&self, staging: StagingArea) -> anyhow::Result<Vec<H256>> {
+        let mut tx = self.begin_mut()?;
+        let mut roots = Vec::with_capacity(staging.len());
+        for (changes, expected_root) in staging.blocks {
+            tx.apply_changes(changes)?;
+            match tx.state_root_matching(expected_root) {
+                Ok(root) => roots.push(root),
+                Err(err) => {
+                    tx.rollback()?;
+                    return Err(err);
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(roots)
+    }
+
+    // Reads from the returned transaction see the database as of the last
+    // commit. MDBX cannot reuse pages freed after the snapshot was taken, so a
+    // long lived reader makes the file grow, see `ReadTransaction::lag`.
+    pub fn begin_ro(&self) -> anyhow::Result<ReadTransaction<'_>> {
+        Ok(ReadTransaction {
+            tx: self.backend.begin_ro()?,
+            verify_code_on_read: self.options.verify_code_on_read,
+        })
+    }
+
+    // There is at most one write transaction at a time, beginning another
+    // waits until it commits or rolls back. Read transactions on disk are
+    // unaffected, but in memory there are no snapshots, so a writer also waits
+    // for the readers and blocks new ones. Beginning a transaction that would
+    // wait on one the current thread holds returns an error.
+    pub fn begin_mut(&self) -> anyhow::Result<MutableTransaction<'_>> {
+        let tx = self.backend.begin_mut()?;
+        Ok(MutableTransaction::new(tx, &self.options))
+    }
+}
+
+impl FromIterator<(H160, Account)> for Db {
+    fn from_iter<I: IntoIterator<Item = (H160, Account)>>(accounts: I) -> Self {
+        Self::try_from_iter(accounts).unwrap()
+    }
+}
+
+impl Extend<(H160, Account)> for Db {
+    fn extend<I: IntoIterator<Item = (H160, Account)>>(&mut self, accounts: I) {
+        self.try_extend(accounts).unwrap()
+    }
+}
+
+// A memory `Db` that can be cloned and shared between threads, see
+// `Db::begin_mut` for how readers and the writer interact.
+#[derive(Clone)]
+pub struct SharedDb {
+    db: Arc<Db>,
+}
+
+impl SharedDb {
+    pub fn begin_ro(&self) -> anyhow::Result<ReadTransaction<'_>> {
+        self.db.begin_ro()
+    }
+
+    pub fn begin_mut(&self) -> anyhow::Result<MutableTransaction<'_>> {
+        self.db.begin_mut()
+    }
+}
+
+pub struct ReadTransaction<'db> {
+    tx: BackendReadTransaction<'db>,
+    verify_code_on_read: bool,
+}
+
+impl<'db> ReadTransaction<'db> {
+    pub fn lag(&self) -> anyhow::Result<u64> {
+        self.tx.lag()
+    }
+
+    pub fn metadata(&self, key: &[u8]) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        let db_key = metadata_key(key);
+        self.tx.get(&db_key)
+    }
+
+    pub fn code_from_hash(&self, code_hash: H256) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        if code_hash == *EMPTY_CODE_HASH {
+            return Ok(Some(Cow::Borrowed(&[])));
+        }
+        let db_key = code_key(code_hash);
+        let code = self.tx.get(&db_key)?;
+        if self.verify_code_on_read {
+            verify_code(code_hash, code.as_deref())?;
+        }
+        Ok(code)
+    }
+
+    pub fn preimage(&self, hash: H256) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        let db_key = preimage_key(hash);
+        self.tx.get(&db_key)
+    }
+
+    pub fn raw_get(&self, namespace: u8, key: &[u8]) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        let db_key = raw_key(namespace, key)?;
+        self.tx.get(&db_key)
+    }
+
+    pub fn try_account(&self, address: H160) -> anyhow::Result<Option<Account>> {
+        let db_key = account_key(address);
+        self.tx
+            .get(&db_key)?
+            .map(|data| Account::unmarshal(&data))
+            .transpose()
+    }
+
+    pub fn storage(&self, address: H160, key: H256) -> anyhow::Result<U256> {
+        let db_key = storage_key(address, key);
+        match self.tx.get(&db_key)? {
+            None => Ok(U256::zero()),
+            Some(data) => unmarshal_storage(&data),
+        }
+    }
+
+    // As `storage`, but `None` if the account does not exist rather than zero
+    pub fn checked_storage(&self, address: H160, key: H256) -> anyhow::Result<Option<U256>> {
+        if self.try_account(address)?.is_none() {
+            return Ok(None);
+        }
+        self.storage(address, key).map(Some)
+    }
+}
+
+pub struct MutableTransaction<'db> {
+    tx: BackendTransaction<'db>,
+    accounts: HashMap<H160, Option<Account>>,
+    storage: HashMap<H160, HashMap<H256, U256>>,
+    destroyed_storage: HashSet<H160>,
+    // Accounts whose storage trie was written outside `state_root`, so their
+    // leaf is rewritten even if the account itself is unchanged
+    walked_storage: HashSet<H160>,
+    // Storage roots known from `storage_root` and earlier `state_root`s, so
+    // that `state_root` does not read the storage trie of an account whose
+    // storage has not changed since, e.g. on a balance update. Kept for the
+    // whole transaction, but each transaction starts without any, so its
+    // first `state_root` still reads the tries of the accounts changed.
+    storage_roots: HashMap<H160, H256>,
+    // The state root as of the last `state_root`, returned again without
+    // touching the backend while nothing is pending
+    last_root: Option<H256>,
+    hashed_accounts: HashMap<H256, Option<Account>>,
+    hashed_storage: HashMap<H256, HashMap<H256, U256>>,
+    max_code_size: Option<usize>,
+    record_preimages: bool,
+    verify_code_on_read: bool,
+    epoch: Option<u8>,
+    last_changeset: Option<Changeset>,
+}
+
+// Counts of the pending changes, which are not printed themselves
+impl std::fmt::Debug for MutableTransaction<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MutableTransaction")
+            .field("dirty_accounts", &self.accounts.len())
+            .field("storage_tries", &self.storage.len())
+            .field("destroyed_storage", &self.destroyed_storage.len())
+            .field("hashed_accounts", &self.hashed_accounts.len())
+            .field("hashed_storage_tries", &self.hashed_storage.len())
+            .field("pending_bytes", &self.pending_bytes())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'db> MutableTransaction<'db> {
+    fn new(tx: BackendTransaction<'db>, options: &DbOptions) -> Self {
+        Self {
+            tx,
+            accounts: HashMap::new(),
+            storage: HashMap::new(),
+            destroyed_storage: HashSet::new(),
+            walked_storage: HashSet::new(),
+            storage_roots: HashMap::new(),
+            last_root: None,
+            hashed_accounts: HashMap::new(),
+            hashed_storage: HashMap::new(),
+            max_code_size: options.max_code_size,
+            record_preimages: options.record_preimages,
+            verify_code_on_read: options.verify_code_on_read,
+            epoch: None,
+            last_changeset: None,
+        }
+    }
+
+    pub fn metadata(&self, key: &[u8]) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        let db_key = metadata_key(key);
+        self.tx.get(&db_key)
+    }
+
+    // Approximate size of the writes held in memory, see
+    // `DbOptions::auto_flush_bytes`. Pending changes not yet flushed by
+    // `state_root` are not counted.
+    pub fn pending_bytes(&self) -> usize {
+        self.tx.pending_bytes()
+    }
+
+    pub fn set_metadata(&mut self, key: &[u8], val: &[u8]) -> anyhow::Result<()> {
+        let db_key = metadata_key(key);
+        self.tx.put(&db_key, val)?;
+        Ok(())
+    }
+
+    // Every metadata entry in key order, pending writes included, such as
+    // `version` and the keys set by users of the crate
+    pub fn iter_metadata(&self) -> impl Iterator<Item = anyhow::Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.tx
+            .iter_prefix(&[METADATA_PREFIX])
+            .map(|entry| entry.map(|(key, value)| (key[1..].to_vec(), value)))
+    }
+
+    pub fn store_code(&mut self, code: &[u8]) -> anyhow::Result<H256> {
+        if code.is_empty() {
+            return Ok(*EMPTY_CODE_HASH);
+        }
+        if let Some(max_code_size) = self.max_code_size {
+            anyhow::ensure!(
+                code.len() <= max_code_size,
+                "Code size {} exceeds the limit of {}",
+                code.len(),
+                max_code_size
+            );
+        }
+        let code_hash = code_hash(code);
+        let db_key = code_key(code_hash);
+        if self.tx.get(&db_key)?.is_none() {
+            self.tx.put(&db_key, code)?;
+        }
+        Ok(code_hash)
+    }
+
+    pub fn code_from_hash(&mut self, code_hash: H256) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        if code_hash == *EMPTY_CODE_HASH {
+            return Ok(Some(Cow::Borrowed(&[])));
+        }
+        let db_key = code_key(code_hash);
+        let code = self.tx.get(&db_key)?;
+        if self.verify_code_on_read {
+            verify_code(code_hash, code.as_deref())?;
+        }
+        Ok(code)
+    }
+
+    // The address or slot hashing to the trie key `hash`. Only recorded with
+    // `DbOptions::record_preimages`, when the state root is computed, and never
+    // by `set_account_by_hash` or `set_storage_by_hash`.
+    pub fn preimage(&self, hash: H256) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        let db_key = preimage_key(hash);
+        self.tx.get(&db_key)
+    }
+
+    // Key-value pairs stored alongside the state for users of the crate, e.g.
+    // an indexer's own tables, so that they commit atomically with it. Each
+    // `namespace` is a separate keyspace. They are not part of any trie and
+    // do not affect the state root. Keys are limited to `MAX_RAW_KEY_LEN`
+    // bytes.
+    pub fn raw_get(&self, namespace: u8, key: &[u8]) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        let db_key = raw_key(namespace, key)?;
+        self.tx.get(&db_key)
+    }
+
+    pub fn raw_put(&mut self, namespace: u8, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        let db_key = raw_key(namespace, key)?;
+        self.tx.put(&db_key, value)
+    }
+
+    pub fn raw_delete(&mut self, namespace: u8, key: &[u8]) -> anyhow::Result<()> {
+        let db_key = raw_key(namespace, key)?;
+        self.tx.delete(&db_key)
+    }
+
+    // Imports accounts by hash, see `set_account_by_hash`, from `reader`
+    // until it ends. Each record is a 4 byte big endian length followed by
+    // the RLP list of the address hash and the account as in the trie. The
+    // export carries no storage, so an account with a storage root is
+    // rejected. Pending accounts are flushed every `IMPORT_FLUSH_INTERVAL`
+    // records to bound memory. Returns the number of accounts imported.
+    pub fn import_rlp_accounts(&mut self, mut reader: impl BufRead) -> anyhow::Result<usize> {
+        let mut count = 0;
+        while !reader.fill_buf()?.is_empty() {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            let mut record = vec![0; u32::from_be_bytes(len) as usize];
+            reader
+                .read_exact(&mut record)
+                .with_context(|| format!("Record {} is truncated", count))?;
+            let rlp = rlp::Rlp::new(&record);
+            anyhow::ensure!(rlp.item_count()? == 2, "Record {} is not a pair", count);
+            let addr_hash: H256 = rlp.val_at(0)?;
+            let leaf = rlp.at(1)?;
+            let storage_root: H256 = leaf.val_at(2)?;
+            anyhow::ensure!(
+                storage_root == *EMPTY_TRIE_ROOT,
+                "Account {:?} has storage, which the export does not include",
+                addr_hash
+            );
+            let account = account_from_leaf(leaf.as_raw())?;
+            anyhow::ensure!(
+                !account.code_hash.is_zero(),
+                "Account {:?} has a zero code hash",
+                addr_hash
+            );
+            self.set_account_by_hash(addr_hash, Some(account));
+            count += 1;
+            if count % IMPORT_FLUSH_INTERVAL == 0 {
+                self.state_root()?;
+            }
+        }
+        Ok(count)
+    }
+
+    // Deletes every key in `namespace`. Raw keys live in their own table, so
+    // this cannot touch the state whatever the namespace.
+    pub fn clear_raw_namespace(&mut self, namespace: u8) -> anyhow::Result<()> {
+        let prefix = raw_key(namespace, &[])?;
+        self.tx.clear_prefix(&prefix)?;
+        // The empty key is the prefix itself, which `clear_prefix` keeps
+        self.tx.delete(&prefix)
+    }
+
+    // Deletes all accounts, storage, tries, code, hash keyed nodes and
+    // preimages, leaving the empty state, along with the changes not yet
+    // written. Metadata such as the version and raw keys are kept.
+    pub fn clear_all(&mut self) -> anyhow::Result<()> {
+        self.accounts.clear();
+        self.storage.clear();
+        self.destroyed_storage.clear();
+        self.walked_storage.clear();
+        self.storage_roots.clear();
+        self.last_root = None;
+        self.hashed_accounts.clear();
+        self.hashed_storage.clear();
+        self.last_changeset = None;
+        for prefix in [
+            STATE_PREFIX,
+            TRIE_PREFIX,
+            CODE_PREFIX,
+            NODES_PREFIX,
+            PREIMAGES_PREFIX,
+        ] {
+            self.tx.clear_prefix(&[prefix])?;
+            // The account trie's root node is keyed by the prefix alone
+            self.tx.delete(&[prefix])?;
+        }
+        Ok(())
+    }
+
+    // Panics if the account's code hash is zero, an account without code has
+    // `EMPTY_CODE_HASH`
+    pub fn set_account(&mut self, address: H160, account: Option<Account>) {
+        check_code_hash(&account);
+        self.accounts.insert(address, account);
+    }
+
+    // Sets the account stored under `addr_hash` in the trie without knowing
+    // its address, e.g. when restoring from a snapshot. `addr_hash` must be
+    // `keccak256(address)`, otherwise the trie is corrupted. Only the trie is
+    // written, so the account cannot be read back with `try_account`. Panics
+    // as `set_account`.
+    pub fn set_account_by_hash(&mut self, addr_hash: H256, account: Option<Account>) {
+        check_code_hash(&account);
+        self.hashed_accounts.insert(addr_hash, account);
+    }
+
+    // Sets a storage slot by pre-hashed keys, see `set_account_by_hash`. The
+    // account must have been set by hash in the same transaction.
+    pub fn set_storage_by_hash(
+        &mut self,
+        addr_hash: H256,
+        slot_hash: H256,
+        value: U256,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            matches!(self.hashed_accounts.get(&addr_hash), Some(Some(_))),
+            "Attempted to set storage on account {:?} not set by hash",
+            addr_hash
+        );
+        self.hashed_storage
+            .entry(addr_hash)
+            .or_default()
+            .insert(slot_hash, value);
+        Ok(())
+    }
+
+    pub fn try_account(&mut self, address: H160) -> anyhow::Result<Option<Account>> {
+        if let Some(account) = self.accounts.get(&address) {
+            Ok(account.clone())
+        } else {
+            let db_key = account_key(address);
+            match self.tx.get(&db_key)? {
+                None => Ok(None),
+                Some(data) => Ok(Some(Account::unmarshal(&data)?)),
+            }
+        }
+    }
+
+    // Like `try_account`, but borrows a pending account instead of cloning it
+    pub fn with_account<R>(
+        &mut self,
+        address: H160,
+        f: impl FnOnce(Option<&Account>) -> R,
+    ) -> anyhow::Result<R> {
+        if let Some(account) = self.accounts.get(&address) {
+            Ok(f(account.as_ref()))
+        } else {
+            let db_key = account_key(address);
+            match self.tx.get(&db_key)? {
+                None => Ok(f(None)),
+                Some(data) => Ok(f(Some(&Account::unmarshal(&data)?))),
+            }
+        }
+    }
+
+    // Applies `f` to the account in place and sets it, loading it first if
+    // it is not pending. Returns false, without calling `f`, if the account
+    // does not exist. Panics as `set_account` if `f` zeroes the code hash.
+    pub fn modify_account(
+        &mut self,
+        address: H160,
+        f: impl FnOnce(&mut Account),
+    ) -> anyhow::Result<bool> {
+        if !self.accounts.contains_key(&address) {
+            match self.try_account(address)? {
+                None => return Ok(false),
+                account => self.accounts.insert(address, account),
+            };
+        }
+        match self.accounts.get_mut(&address) {
+            Some(Some(account)) => f(account),
+            _ => return Ok(false),
+        }
+        check_code_hash(&self.accounts[&address]);
+        Ok(true)
+    }
+
+    // Every account in address order, pending changes included. Accounts set
+    // with `set_account_by_hash` have no address and are not visited. Storage
+    // is skipped over rather than read, but each account still costs a seek.
+    pub fn iter_accounts(&self) -> impl Iterator<Item = anyhow::Result<(H160, Account)>> + '_ {
+        let mut pending: Vec<_> = self
+            .accounts
+            .iter()
+            .map(|(address, account)| (*address, account.clone()))
+            .collect();
+        pending.sort_unstable_by_key(|(address, _)| *address);
+        AccountIter {
+            entries: self.tx.iter_prefix(&[STATE_PREFIX]),
+            stored: None,
+            pending: pending.into_iter().peekable(),
+        }
+    }
+
+    // As `iter_accounts`, but only accounts with code. This is a filtered scan,
+    // so it visits every account.
+    pub fn iter_contracts(&self) -> impl Iterator<Item = anyhow::Result<(H160, Account)>> + '_ {
+        self.iter_accounts().filter(|entry| {
+            entry
+                .as_ref()
+                .map_or(true, |(_, account)| account.code_hash != *EMPTY_CODE_HASH)
+        })
+    }
+
+    // Every account in state trie order, by address hash, from `start`, as
+    // snap sync and `debug_accountRange` expect. Pending changes are flushed
+    // first, see `state_root`. The address is only known if its preimage was
+    // recorded, see `DbOptions::record_preimages`.
+    pub fn iter_accounts_by_trie_order(
+        &mut self,
+        start: H256,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<(H256, Option<H160>, Account)>> + '_>
+    {
+        self.state_root()?;
+        let this = &*self;
+        Ok(
+            LeafIter::new(&this.tx, ACCOUNT_TRIE_PREFIX, &PackedKey(start.0)).map(move |leaf| {
+                let (key, leaf) = leaf?;
+                let hash = H256(key.0);
+                let address = this
+                    .preimage(hash)?
+                    .map(|preimage| H160::from_slice(&preimage));
+                Ok((hash, address, account_from_leaf(&leaf)?))
+            }),
+        )
+    }
+
+    // Number of accounts, pending changes included. This is a scan, see
+    // `iter_accounts`.
+    pub fn account_count(&self) -> anyhow::Result<u64> {
+        let mut count = 0;
+        for entry in self.iter_accounts() {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Number of non-zero storage slots over all accounts, pending changes
+    // included. This scans the whole state, and reads every pending slot to
+    // tell a new slot from an overwritten one.
+    pub fn storage_slot_count(&self) -> anyhow::Result<u64> {
+        let mut count: u64 = 0;
+        for entry in self.tx.iter_prefix(&[STATE_PREFIX]) {
+            let (key, _) = entry?;
+            match storage_key_address(&key) {
+                Some(address) if !self.destroyed_storage.contains(&address) => count += 1,
+                _ => {}
+            }
+        }
+        for (address, slots) in &self.storage {
+            let destroyed = self.destroyed_storage.contains(address);
+            for (key, value) in slots {
+                let stored = !destroyed && self.tx.get(&storage_key(*address, *key))?.is_some();
+                match (stored, value.is_zero()) {
+                    (true, true) => count -= 1,
+                    (false, false) => count += 1,
+                    _ => {}
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    // Reads the account as of the state root `root`, walking the trie rather
+    // than the flat state. Trie nodes are stored by path and overwritten when
+    // a root is computed, so this fails with `DbError::RootUnavailable` for
+    // any root other than the most recently computed one. Changes made since
+    // that root are not visible.
+    pub fn try_account_at(&self, root: H256, address: H160) -> anyhow::Result<Option<Account>> {
+        Ok(match self.account_leaf_at(root, address)? {
+            None => None,
+            Some(leaf) => Some(account_from_leaf(&leaf)?),
+        })
+    }
+
+    // As `try_account_at`, for a storage slot
+    pub fn storage_at(&self, root: H256, address: H160, key: H256) -> anyhow::Result<U256> {
+        let storage_root = match self.account_leaf_at(root, address)? {
+            None => return Ok(U256::zero()),
+            Some(leaf) => rlp::Rlp::new(&leaf).val_at(2)?,
+        };
+        let trie_prefix = storage_trie_prefix(&get_internal_key(address));
+        Ok(
+            match get_at_root(&self.tx, &trie_prefix, storage_root, &get_packed_key(key))? {
+                None => U256::zero(),
+                Some(leaf) => decode_storage_value(&leaf)?,
+            },
+        )
+    }
+
+    // The proof of the account as of `root`, see `proof::verify_proof`. The
+    // key it proves is the hash of the address. Fails as `try_account_at`.
+    pub fn account_proof(&self, root: H256, address: H160) -> anyhow::Result<Vec<Vec<u8>>> {
+        proof_at_root(
+            &self.tx,
+            ACCOUNT_TRIE_PREFIX,
+            root,
+            &get_packed_key(address),
+        )
+    }
+
+    // The proof of the storage slot as of `root`, see
+    // `proof::verify_storage_proof`. It is checked against the storage root
+    // in the account proved by `account_proof`, and is empty if there is no
+    // account. Fails as `try_account_at`.
+    pub fn storage_proof(
+        &self,
+        root: H256,
+        address: H160,
+        key: H256,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let storage_root = match self.account_leaf_at(root, address)? {
+            None => return Ok(Vec::new()),
+            Some(leaf) => rlp::Rlp::new(&leaf).val_at(2)?,
+        };
+        let trie_prefix = storage_trie_prefix(&get_internal_key(address));
+        proof_at_root(&self.tx, &trie_prefix, storage_root, &get_packed_key(key))
+    }
+
+    // The state in the JSON of the Ethereum state tests, see `state_test`.
+    // Pending changes are flushed first. Accounts set with
+    // `set_account_by_hash` have no address and are left out.
+    pub fn to_state_test_json(&mut self) -> anyhow::Result<String> {
+        self.state_root()?;
+        let mut state = TestState::new();
+        let mut code_hashes = Vec::new();
+        for entry in self.tx.iter_prefix(&[STATE_PREFIX]) {
+            let (key, value) = entry?;
+            if let Some(address) = account_key_address(&key) {
+                let account = Account::unmarshal(&value)?;
+                code_hashes.push((address, account.code_hash));
+                let account = TestAccount {
+                    balance: account.balance,
+                    nonce: account.nonce,
+                    ..TestAccount::default()
+                };
+                state.insert(address, account);
+            } else if let Some(address) = storage_key_address(&key) {
+                let slot = H256::from_slice(&key[21..]);
+                let account = state
+                    .get_mut(&address)
+                    .ok_or_else(|| anyhow::anyhow!("Storage of missing account {:?}", address))?;
+                account.storage.insert(slot, unmarshal_storage(&value)?);
+            }
+        }
+        for (address, code_hash) in code_hashes {
+            let code = self
+                .code_from_hash(code_hash)?
+                .ok_or_else(|| anyhow::anyhow!("Code {:?} is missing", code_hash))?
+                .to_vec();
+            state.get_mut(&address).unwrap().code = code;
+        }
+        Ok(state_test::to_json(&state))
+    }
+
+    // Sets the accounts of a state test's `pre` section, see `state_test`.
+    // Accounts already in the state are overwritten, but their other storage
+    // slots are kept.
+    pub fn from_state_test_json(&mut self, json: &str) -> anyhow::Result<()> {
+        for (address, account) in state_test::from_json(json)? {
+            let code_hash = self.store_code(&account.code)?;
+            self.set_account(
+                address,
+                Some(Account::new(account.nonce, account.balance, code_hash)),
+            );
+            for (slot, value) in account.storage {
+                self.set_storage(address, slot, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Up to `limit` accounts in order of their address hash from `start`,
+    // with the proofs of `start` and of the last account, as served in snap
+    // sync. The proof nodes are deduplicated. See `proof::verify_range`,
+    // whose leaves are the accounts encoded with their storage root.
+    pub fn account_range_proof(
+        &mut self,
+        start: H256,
+        limit: usize,
+    ) -> anyhow::Result<AccountRange> {
+        let root = self.state_root()?;
+        let start = PackedKey(start.0);
+        let leaves = leaves_from(&self.tx, ACCOUNT_TRIE_PREFIX, &start, limit)?;
+        let mut proof = proof_at_root(&self.tx, ACCOUNT_TRIE_PREFIX, root, &start)?;
+        if let Some((last, _)) = leaves.last() {
+            for node in proof_at_root(&self.tx, ACCOUNT_TRIE_PREFIX, root, last)? {
+                if !proof.contains(&node) {
+                    proof.push(node);
+                }
+            }
+        }
+        let accounts = leaves
+            .iter()
+            .map(|(key, leaf)| Ok((H256(key.0), account_from_leaf(leaf)?)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok((accounts, proof))
+    }
+
+    // The RLP of the account trie's root node, whose hash is `state_root`,
+    // e.g. to check it against another client. Flushes like `state_root`.
+    pub fn root_node_rlp(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.state_root()?;
+        root_node_rlp(&self.tx, ACCOUNT_TRIE_PREFIX)
+    }
+
+    // The account leaf at or below the nibble path `nibbles` of the account
+    // trie, as its address hash and RLP, or `None` if the path leads to a
+    // branch or to nothing. For comparing the trie with another client's when
+    // the state roots differ. Flushes like `state_root`.
+    pub fn account_at_path(&mut self, nibbles: &[u8]) -> anyhow::Result<Option<(H256, Vec<u8>)>> {
+        self.state_root()?;
+        let leaf = leaf_at_path(&self.tx, ACCOUNT_TRIE_PREFIX, nibbles)?;
+        Ok(leaf.map(|(key, value)| (H256(key.0), value.to_vec())))
+    }
+
+    // The nodes of the account trie on the path to the account, from the
+    // root, e.g. to find where two tries diverge. Flushes like `state_root`.
+    pub fn trie_path(&mut self, address: H160) -> anyhow::Result<Vec<TrieStep>> {
+        self.state_root()?;
+        trie_path(&self.tx, ACCOUNT_TRIE_PREFIX, &get_packed_key(address))
+    }
+
+    fn account_leaf_at(
+        &self,
+        root: H256,
+        address: H160,
+    ) -> anyhow::Result<Option<SmallVec<[u8; 36]>>> {
+        get_at_root(
+            &self.tx,
+            ACCOUNT_TRIE_PREFIX,
+            root,
+            &get_packed_key(address),
+        )
+    }
+
+    // Nodes written by this transaction record `epoch`, so that those not
+    // written since can be removed with `Db::prune_older_than`. The epoch
+    // does not affect the state root.
+    #[cfg(feature = "node-epochs")]
+    pub fn set_epoch(&mut self, epoch: u8) {
+        self.epoch = Some(epoch);
+    }
+
+    // Touches the account, as a call with zero value does. A missing account
+    // is created empty. Only touched accounts are removed by
+    // `prune_empty_accounts`, so the EIP-161 rules follow from touching
+    // every account an operation reaches and pruning after it.
+    pub fn touch_account(&mut self, address: H160) -> anyhow::Result<()> {
+        if !self.accounts.contains_key(&address) {
+            let account = self
+                .try_account(address)?
+                .unwrap_or_else(|| Account::eoa(0, U256::zero()));
+            self.accounts.insert(address, Some(account));
+        }
+        Ok(())
+    }
+
+    pub fn prune_empty_accounts(&mut self) {
+        for account in self.accounts.values_mut() {
+            if matches!(account, Some(account) if account.is_empty()) {
+                *account = None;
+            }
+        }
+    }
+
+    pub fn set_storage(&mut self, address: H160, key: H256, value: U256) -> anyhow::Result<()> {
+        if let Some(map) = self.storage.get_mut(&address) {
+            map.insert(key, value);
+            Ok(())
+        } else {
+            let exists = match self.accounts.get(&address) {
+                // A pending account needs no reload
+                Some(account) => account.is_some(),
+                None => {
+                    let account = self.try_account(address)?;
+                    let exists = account.is_some();
+                    if exists {
+                        self.set_account(address, account);
+                    }
+                    exists
+                }
+            };
+            anyhow::ensure!(
+                exists,
+                "Attempted to set storage on non-existent account {:?}",
+                address
+            );
+            let mut map = HashMap::new();
+            map.insert(key, value);
+            self.storage.insert(address, map);
+            Ok(())
+        }
+    }
+
+    pub fn storage(&self, address: H160, key: H256) -> anyhow::Result<U256> {
+        if let Some(map) = self.storage.get(&address) {
+            if let Some(val) = map.get(&key) {
+                return Ok(*val);
+            }
+        }
+        if self.destroyed_storage.contains(&address) {
+            return Ok(U256::zero());
+        }
+        let db_key = storage_key(address, key);
+        match self.tx.get(&db_key)? {
+            None => Ok(U256::zero()),
+            Some(data) => unmarshal_storage(&data),
+        }
+    }
+
+    pub fn set_storage_h256(
+        &mut self,
+        address: H160,
+        key: H256,
+        value: H256,
+    ) -> anyhow::Result<()> {
+        self.set_storage(address, key, U256::from_big_endian(value.as_bytes()))
+    }
+
+    // As `set_storage`, returning the value it overwrites, e.g. the current
+    // value for SSTORE gas. The value from before the transaction is not
+    // kept.
+    pub fn update_storage(
+        &mut self,
+        address: H160,
+        key: H256,
+        value: U256,
+    ) -> anyhow::Result<U256> {
+        let previous = self.storage(address, key)?;
+        self.set_storage(address, key, value)?;
+        Ok(previous)
+    }
+
+    // As `storage`, but `None` if the account does not exist, pending changes
+    // included, rather than zero
+    pub fn checked_storage(&mut self, address: H160, key: H256) -> anyhow::Result<Option<U256>> {
+        if self.try_account(address)?.is_none() {
+            return Ok(None);
+        }
+        self.storage(address, key).map(Some)
+    }
+
+    pub fn storage_h256(&self, address: H160, key: H256) -> anyhow::Result<H256> {
+        let mut res = H256::zero();
+        self.storage(address, key)?
+            .to_big_endian(res.as_bytes_mut());
+        Ok(res)
+    }
+
+    // Deletes every storage slot of the account, e.g. for SELFDESTRUCT. It is
+    // idempotent, and does nothing for an address without storage, such as
+    // one that was never an account. The account itself is left alone. If
+    // it is missing but storage remains, a delete of it is queued so the
+    // storage is cleared on flush. Deleting a missing key writes nothing.
+    pub fn destroy_storage(&mut self, address: H160) -> anyhow::Result<()> {
+        let trie_prefix = storage_trie_prefix(&get_internal_key(address));
+        if !self.storage.contains_key(&address) && self.tx.get(&trie_prefix)?.is_none() {
+            // Account has no storage, do nothing
+            return Ok(());
+        }
+        self.destroyed_storage.insert(address);
+        self.storage.remove(&address);
+        if !self.accounts.contains_key(&address) {
+            let account = self.try_account(address)?;
+            self.set_account(address, account);
+        };
+        Ok(())
+    }
+
+    // Destroys the account's storage like `destroy_storage`, but deletes at
+    // most `batch_size` keys per MDBX transaction, committing in between, so
+    // that clearing a very large contract does not exhaust the transaction.
+    // The batches are committed, so the transaction must have nothing
+    // uncommitted when it is called. The last batch and the update of the
+    // account are committed by the caller's commit. Until then the database
+    // holds a marker, and if the call or the transaction does not complete,
+    // opening the database finishes destroying the storage.
+    pub fn destroy_storage_in_batches(
+        &mut self,
+        address: H160,
+        batch_size: usize,
+    ) -> anyhow::Result<()> {
+        if let Some(marker) = self.metadata(DESTROYING_STORAGE)? {
+            let (pending, _) = parse_destroy_marker(&marker)?;
+            anyhow::ensure!(
+                pending == address,
+                "Destroying the storage of {:?} was interrupted, reopen the database to finish it",
+                pending
+            );
+        }
+        anyhow::ensure!(
+            !self.has_pending_changes() && !self.tx.has_uncommitted_writes(),
+            "destroy_storage_in_batches commits, the transaction must have nothing uncommitted"
+        );
+        let mut marker = address.as_bytes().to_vec();
+        marker.extend_from_slice(&(batch_size as u64).to_be_bytes());
+        self.set_metadata(DESTROYING_STORAGE, &marker)?;
+
+        let db_prefix = account_key(address);
+        while !self.tx.clear_prefix_bounded(&db_prefix, batch_size)? {
+            self.tx.commit_and_continue()?;
+        }
+        let trie_prefix = storage_trie_prefix(&get_internal_key(address));
+        while !self.tx.clear_prefix_bounded(&trie_prefix, batch_size)? {
+            self.tx.commit_and_continue()?;
+        }
+        self.tx.delete(&trie_prefix)?;
+        self.tx.delete(&metadata_key(DESTROYING_STORAGE))?;
+        self.walked_storage.insert(address);
+        self.storage_roots.insert(address, *EMPTY_TRIE_ROOT);
+        if !self.accounts.contains_key(&address) {
+            let account = self.try_account(address)?;
+            self.set_account(address, account);
+        }
+        Ok(())
+    }
+
+    pub fn state_root(&mut self) -> anyhow::Result<H256> {
+        Ok(self.state_root_detailed()?.root)
+    }
+
+    // Whether anything is waiting for `state_root`, without reading the
+    // database. Unlike `state_changed`, writes of the value already stored
+    // count.
+    pub fn has_pending_changes(&self) -> bool {
+        !self.accounts.is_empty()
+            || !self.storage.is_empty()
+            || !self.destroyed_storage.is_empty()
+            || !self.walked_storage.is_empty()
+            || !self.hashed_accounts.is_empty()
+            || !self.hashed_storage.is_empty()
+    }
+
+    // Whether the pending changes would change the state root, compared to
+    // the state as last flushed by `state_root` or committed, see
+    // `drop_noop_writes`. Changes made by hash cannot be compared and count
+    // as changes.
+    pub fn state_changed(&mut self) -> anyhow::Result<bool> {
+        self.drop_noop_writes()?;
+        // Every account left differs, or has storage changes
+        Ok(!self.accounts.is_empty()
+            || !self.hashed_accounts.is_empty()
+            || !self.hashed_storage.is_empty())
+    }
+
+    // Drops the pending writes of the value already stored, and destroying
+    // storage that was never flushed, so that they are neither written nor
+    // walked. A block that re-sets unchanged values then writes nothing.
+    fn drop_noop_writes(&mut self) -> anyhow::Result<()> {
+        let mut destroyed_noops = Vec::new();
+        for address in &self.destroyed_storage {
+            let trie_prefix = storage_trie_prefix(&get_internal_key(*address));
+            if self.tx.get(&trie_prefix)?.is_none() {
+                destroyed_noops.push(*address);
+            }
+        }
+        for address in destroyed_noops {
+            self.destroyed_storage.remove(&address);
+        }
+
+        let mut slot_noops = Vec::new();
+        for (address, slots) in &self.storage {
+            let destroyed = self.destroyed_storage.contains(address);
+            for (key, value) in slots {
+                let stored = if destroyed {
+                    U256::zero()
+                } else {
+                    match self.tx.get(&storage_key(*address, *key))? {
+                        None => U256::zero(),
+                        Some(data) => unmarshal_storage(&data)?,
+                    }
+                };
+                if stored == *value {
+                    slot_noops.push((*address, *key));
+                }
+            }
+        }
+        for (address, key) in slot_noops {
+            if let Some(slots) = self.storage.get_mut(&address) {
+                slots.remove(&key);
+                if slots.is_empty() {
+                    self.storage.remove(&address);
+                }
+            }
+        }
+
+        // An account with storage changes stays, its storage root is
+        // computed through it
+        let mut account_noops = Vec::new();
+        for (address, account) in &self.accounts {
+            if self.storage.contains_key(address)
+                || self.destroyed_storage.contains(address)
+                || self.walked_storage.contains(address)
+            {
+                continue;
+            }
+            let stored = match self.tx.get(&account_key(*address))? {
+                None => None,
+                Some(data) => Some(Account::unmarshal(&data)?),
+            };
+            if stored == *account {
+                account_noops.push(*address);
+            }
+        }
+        for address in account_noops {
+            self.accounts.remove(&address);
+        }
+        Ok(())
+    }
+
+    // As `state_root`, but keeps a copy of the flushed changes, see
+    // `last_changeset`
+    pub fn state_root_retaining(&mut self) -> anyhow::Result<H256> {
+        let changeset = Changeset {
+            accounts: self.accounts.clone(),
+            storage: self.storage.clone(),
+            destroyed_storage: self.destroyed_storage.clone(),
+            hashed_accounts: self.hashed_accounts.clone(),
+            hashed_storage: self.hashed_storage.clone(),
+        };
+        let root = self.state_root()?;
+        self.last_changeset = Some(changeset);
+        Ok(root)
+    }
+
+    // The changes flushed by the last `state_root_retaining`, if any
+    pub fn last_changeset(&self) -> Option<&Changeset> {
+        self.last_changeset.as_ref()
+    }
+
+    pub fn state_root_detailed(&mut self) -> anyhow::Result<StateRootSummary> {
+        let pending = self.last_root.is_none() || self.has_pending_changes();
+        let summary = self.state_root_unflushed()?;
+        if pending {
+            self.tx.flush()?;
+        }
+        Ok(summary)
+    }
+
+    // As `state_root_detailed`, leaving the writes in the cache
+    fn state_root_unflushed(&mut self) -> anyhow::Result<StateRootSummary> {
+        if let Some(root) = self.last_root.filter(|_| !self.has_pending_changes()) {
+            return Ok(StateRootSummary {
+                root,
+                accounts_flushed: 0,
+                storage_tries_touched: 0,
+                storage_tries_walked: 0,
+                nodes_written: 0,
+                nodes_deleted: 0,
+            });
+        }
+        self.drop_noop_writes()?;
+        for (address, account) in self.accounts.iter() {
+            let key = account_key(*address);
+            match account {
+                Some(account) => {
+                    self.tx.put(&key, &account.marshal())?;
+                }
+                None => self.tx.delete(&key)?,
+            };
+            if self.record_preimages {
+                self.tx
+                    .put(&preimage_key(keccak256(address)), address.as_bytes())?;
+            }
+        }
+
+        let accounts: Vec<_> = std::mem::take(&mut self.accounts).into_iter().collect();
+        let hashed_accounts: Vec<_> = std::mem::take(&mut self.hashed_accounts)
+            .into_iter()
+            .collect();
+        let storage_tries_touched = accounts
+            .iter()
+            .filter(|(address, _)| {
+                self.storage.contains_key(address) || self.destroyed_storage.contains(address)
+            })
+            .count()
+            + self.hashed_storage.len();
+        let mut storage_tries = Vec::with_capacity(accounts.len() + hashed_accounts.len());
+        // Storage written by hash changes the trie of the account hashing to
+        // it, whose known root is then stale
+        if !self.hashed_storage.is_empty() {
+            let hashed_storage = &self.hashed_storage;
+            self.storage_roots
+                .retain(|address, _| !hashed_storage.contains_key(&keccak256(address)));
+        }
+        // The storage roots already known, `None` for those still to walk
+        let mut known_roots = Vec::with_capacity(accounts.len());
+        for (address, _) in &accounts {
+            let unchanged =
+                !self.storage.contains_key(address) && !self.destroyed_storage.contains(address);
+            match self.storage_roots.get(address) {
+                Some(root) if unchanged => known_roots.push(Some(*root)),
+                _ => {
+                    known_roots.push(None);
+                    storage_tries.push(self.prepare_storage_trie(address)?);
+                }
+            }
+        }
+        for (addr_hash, _) in &hashed_accounts {
+            storage_tries.push(self.prepare_hashed_storage_trie(*addr_hash));
+        }
+        let mut summary = StateRootSummary {
+            root: H256::zero(),
+            accounts_flushed: known_roots.len() + hashed_accounts.len(),
+            storage_tries_touched,
+            storage_tries_walked: storage_tries.len(),
+            nodes_written: 0,
+            nodes_deleted: 0,
+        };
+        let mut walked = walk_storage_tries(&self.tx, storage_tries, self.epoch)?.into_iter();
+        let storage_roots = known_roots
+            .into_iter()
+            .chain(std::iter::repeat_n(None, hashed_accounts.len()))
+            .map(|known| match known {
+                Some(root) => (root, StagedWrites::default()),
+                None => walked.next().unwrap(),
+            });
+
+        let addresses: Vec<H160> = accounts.iter().map(|(address, _)| *address).collect();
+        let leaves = accounts
+            .into_iter()
+            .map(|(address, account)| (get_packed_key(address), account))
+            .chain(
+                hashed_accounts
+                    .into_iter()
+                    .map(|(addr_hash, account)| (PackedKey::from(addr_hash), account)),
+            );
+        let mut dirty_list = Vec::new();
+        for (i, ((internal_address, account), (storage_root, writes))) in
+            leaves.zip(storage_roots).enumerate()
+        {
+            summary.nodes_written += writes.num_puts();
+            summary.nodes_deleted += writes.num_deletes();
+            self.tx.apply(writes);
+            if let Some(address) = addresses.get(i) {
+                self.storage_roots.insert(*address, storage_root);
+            }
+            if let Some(account) = account {
+                let leaf = account_leaf(&account, storage_root);
+                dirty_list.push((internal_address, Some(SmallVec::from_slice(&leaf))));
+            } else {
+                dirty_list.push((internal_address, None));
+            }
+        }
+        sort_dirty_list(&mut dirty_list);
+
+        let mut walker: Walker =
+            Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list, &self.tx).with_epoch(self.epoch);
+        summary.root = walker.root()?;
+        let writes = walker.into_writes();
+        summary.nodes_written += writes.num_puts();
+        summary.nodes_deleted += writes.num_deletes();
+        self.tx.apply(writes);
+
+        self.walked_storage.clear();
+        assert!(self.accounts.is_empty());
+        assert!(self.storage.is_empty());
+        assert!(self.destroyed_storage.is_empty());
+        assert!(self.hashed_accounts.is_empty());
+        assert!(self.hashed_storage.is_empty());
+
+        self.last_root = Some(summary.root);
+        Ok(summary)
+    }
+
+    // Flushes the pending changes like `state_root` and returns them, e.g. to
+    // notify an indexer before commit. Changes are ordered by address, and for
+    // each address a `Destroyed` comes first, then the `Account`, then its
+    // `Storage` by slot. Changes already flushed by an earlier `state_root`
+    // and those made with `set_account_by_hash` are not returned.
+    pub fn drain_pending(&mut self) -> anyhow::Result<impl Iterator<Item = PendingChange>> {
+        let addresses: BTreeSet<H160> = self
+            .accounts
+            .keys()
+            .chain(self.storage.keys())
+            .chain(self.destroyed_storage.iter())
+            .copied()
+            .collect();
+        let mut changes = Vec::new();
+        for address in addresses {
+            if self.destroyed_storage.contains(&address) {
+                changes.push(PendingChange::Destroyed { address });
+            }
+            if let Some(account) = self.accounts.get(&address) {
+                changes.push(PendingChange::Account {
+                    address,
+                    new: account.clone(),
+                });
+            }
+            if let Some(slots) = self.storage.get(&address) {
+                let mut slots: Vec<_> = slots.iter().map(|(slot, value)| (*slot, *value)).collect();
+                slots.sort_unstable_by_key(|(slot, _)| *slot);
+                changes.extend(
+                    slots
+                        .into_iter()
+                        .map(|(slot, value)| PendingChange::Storage {
+                            address,
+                            slot,
+                            value,
+                        }),
+                );
+            }
+        }
+        self.state_root()?;
+        Ok(changes.into_iter())
+    }
+
+    // Re-applies changes returned by `drain_pending`, e.g. to replay a block
+    // captured from another database with the same starting state. The
+    // resulting state root matches the one the changes were drained from.
+    pub fn apply_changes(
+        &mut self,
+        changes: impl IntoIterator<Item = PendingChange>,
+    ) -> anyhow::Result<()> {
+        for change in changes {
+            match change {
+                PendingChange::Destroyed { address } => self.destroy_storage(address)?,
+                PendingChange::Account { address, new } => self.set_account(address, new),
+                PendingChange::Storage {
+                    address,
+                    slot,
+                    value,
+                } => {
+                    // As `set_storage`, but allowed after the account is
+                    // deleted, as `drain_pending` may return that
+                    if !self.accounts.contains_key(&address) {
+                        let account = self.try_account(address)?;
+                        self.set_account(address, account);
+                    }
+                    self.storage.entry(address).or_default().insert(slot, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Computes the state root first, so that pending changes are counted
+    pub fn trie_stats(&mut self) -> anyhow::Result<TrieStats> {
+        self.state_root()?;
+        trie_stats(&self.tx)
+    }
+
+    // Flushes the account's pending slots and walks its storage trie.
+    // `state_root` reuses the root rather than walking again while the
+    // storage is unchanged, so calling this for contracts as they finish
+    // spreads the storage work over a block instead of leaving it all to
+    // `state_root`. Changing the storage afterwards is allowed, the trie is
+    // then walked again.
+    pub fn storage_root(&mut self, address: &H160) -> anyhow::Result<H256> {
+        self.walked_storage.insert(*address);
+        let (trie_prefix, dirty_storage) = self.prepare_storage_trie(address)?;
+        let mut walker = Walker::new(&trie_prefix, dirty_storage, &self.tx).with_epoch(self.epoch);
+        let root = walker.root()?;
+        let writes = walker.into_writes();
+        self.tx.apply(writes);
+        self.storage_roots.insert(*address, root);
+        Ok(root)
+    }
+
+    // The value of the account's leaf in the state trie, as `state_root` would
+    // build it with the account's current storage root, or `None` if the
+    // account does not exist
+    pub fn account_leaf_rlp(&mut self, address: H160) -> anyhow::Result<Option<Vec<u8>>> {
+        let account = match self.try_account(address)? {
+            None => return Ok(None),
+            Some(account) => account,
+        };
+        // Only pending slots need a walk, which `state_root` then reuses
+        let storage_root =
+            if self.storage.contains_key(&address) || self.destroyed_storage.contains(&address) {
+                self.storage_root(&address)?
+            } else {
+                self.unchanged_storage_root(&address)?
+            };
+        Ok(Some(account_leaf(&account, storage_root)))
+    }
+
+    // The root of a storage trie with no pending slots, read without writing
+    // anything, so the transaction is left without pending changes
+    fn unchanged_storage_root(&mut self, address: &H160) -> anyhow::Result<H256> {
+        if let Some(root) = self.storage_roots.get(address) {
+            return Ok(*root);
+        }
+        let trie_prefix = storage_trie_prefix(&get_internal_key(address));
+        let root = Walker::new(&trie_prefix, Vec::new(), &self.tx).root()?;
+        self.storage_roots.insert(*address, root);
+        Ok(root)
+    }
+
+    // Writes the account's storage slots and returns its trie prefix and
+    // dirty list, ready to be walked.
+    fn prepare_storage_trie(&mut self, address: &H160) -> anyhow::Result<(Vec<u8>, DirtyList)> {
+        if self.destroyed_storage.remove(address) {
+            self.tx.clear_prefix(&account_key(*address))?;
+            let trie_prefix = storage_trie_prefix(&get_internal_key(address));
+            self.tx.clear_prefix(&trie_prefix)?;
+            self.tx.delete(&trie_prefix)?;
+        }
+
+        let mut storage = self.storage.remove(address).unwrap_or_default();
+        let mut dirty_storage: DirtyList = Vec::new();
+        for (key, value) in storage.drain() {
+            let db_key = storage_key(*address, key);
+            if value.is_zero() {
+                self.tx.delete(&db_key)?
+            } else {
+                self.tx.put(&db_key, &marshal_storage(value))?;
+            }
+
+            let packed_key = get_packed_key(key);
+            if self.record_preimages {
+                self.tx
+                    .put(&preimage_key(H256(packed_key.0)), key.as_bytes())?;
+            }
+            if value.is_zero() {
+                dirty_storage.push((packed_key, None));
+            } else {
+                dirty_storage.push((packed_key, Some(SmallVec::from_slice(&rlp::encode(&value)))));
+            }
+        }
+        sort_dirty_list(&mut dirty_storage);
+
+        let trie_prefix = storage_trie_prefix(&get_internal_key(address));
+        Ok((trie_prefix, dirty_storage))
+    }
+
+    // As `prepare_storage_trie`, but the slots only go into the trie.
+    fn prepare_hashed_storage_trie(&mut self, addr_hash: H256) -> (Vec<u8>, DirtyList) {
+        let storage = self.hashed_storage.remove(&addr_hash).unwrap_or_default();
+        let mut dirty_storage: DirtyList = storage
+            .into_iter()
+            .map(|(slot_hash, value)| {
+                if value.is_zero() {
+                    (PackedKey::from(slot_hash), None)
+                } else {
+                    (
+                        PackedKey::from(slot_hash),
+                        Some(SmallVec::from_slice(&rlp::encode(&value))),
+                    )
+                }
+            })
+            .collect();
+        sort_dirty_list(&mut dirty_storage);
+
+        let trie_prefix = storage_trie_prefix(&PackedKey::from(addr_hash).unpack());
+        (trie_prefix, dirty_storage)
+    }
+
+    pub fn commit_and_continue(&mut self) -> anyhow::Result<H256> {
+        let root = self.root_to_commit()?;
+        self.tx.commit_and_continue()?;
+        Ok(root)
+    }
+
+    // As `commit_and_continue`, but a commit that fails, e.g. with the map
+    // full, keeps the pending writes, which can still be read and committed
+    // again, see `BackendTransaction::try_commit`. A failure computing the
+    // state root rolls back as `commit` does.
+    pub fn try_commit(&mut self) -> anyhow::Result<H256> {
+        let root = self
+            .state_root_unflushed()
+            .inspect_err(|_| self.tx.abort())?
+            .root;
+        self.tx.try_commit()?;
+        Ok(root)
+    }
+
+    // Returns the state root that was committed. A failed commit persists
+    // nothing, the transaction is rolled back.
+    pub fn commit(mut self) -> anyhow::Result<H256> {
+        let root = self.root_to_commit()?;
+        self.tx.commit()?;
+        Ok(root)
+    }
+
+    // As `commit`, also reporting the keys written and deleted since the
+    // transaction began or last committed, and how long the commit took
+    pub fn commit_reported(mut self) -> anyhow::Result<(H256, CommitReport)> {
+        let root = self.root_to_commit()?;
+        let report = self.tx.commit_reported()?;
+        Ok((root, report))
+    }
+
+    // Flushing the state can fail like the commit itself, e.g. when the map
+    // is full, so it also rolls back on error
+    fn root_to_commit(&mut self) -> anyhow::Result<H256> {
+        self.state_root().inspect_err(|_| self.tx.abort())
+    }
+
+    pub fn rollback(self) -> anyhow::Result<()> {
+        self.tx.rollback()
+    }
+
+    // Ends a block from Spurious Dragon (EIP-161) on, once its changes are
+    // set. The order matters: touched empty accounts are pruned first, since
+    // they must be missing from the trie, then the storage roots and the
+    // account root are computed, and the transaction commits if the root
+    // matches `expected_root`, when given, and is rolled back otherwise, see
+    // `commit_if_root`. Returns the committed root.
+    pub fn apply_block(mut self, expected_root: Option<H256>) -> anyhow::Result<H256> {
+        self.prune_empty_accounts();
+        match expected_root {
+            None => self.commit(),
+            Some(expected) => {
+                self.commit_if_root(expected)?;
+                Ok(expected)
+            }
+        }
+    }
+
+    // As `apply_block`, without committing, to import a range of blocks
+    // atomically in one transaction. Each block's changes are flushed into
+    // the transaction, so the next block builds on them, and `commit`
+    // persists the whole range. On a mismatch this fails with
+    // `StateRootMismatch` and the block's changes are already flushed, so
+    // the transaction should be rolled back.
+    pub fn finalize_block(&mut self, expected_root: Option<H256>) -> anyhow::Result<H256> {
+        self.prune_empty_accounts();
+        self.state_root_matching(expected_root)
+    }
+
+    // Commits only if the state root matches `expected`, e.g. the root in a
+    // block header. Otherwise the transaction is rolled back.
+    pub fn commit_if_root(mut self, expected: H256) -> anyhow::Result<()> {
+        match self.state_root_matching(Some(expected)) {
+            Ok(_) => self.tx.commit(),
+            Err(err) => {
+                self.tx.rollback()?;
+                Err(err)
+            }
+        }
+    }
+
+    // The state root, or `StateRootMismatch` if it is not `expected`. The
+    // changed accounts are gathered before the flush, no-op writes left out.
+    fn state_root_matching(&mut self, expected: Option<H256>) -> anyhow::Result<H256> {
+        self.drop_noop_writes()?;
+        let mut changed_accounts: Vec<H160> = self
+            .accounts
+            .keys()
+            .chain(self.storage.keys())
+            .chain(&self.destroyed_storage)
+            .chain(&self.walked_storage)
+            .copied()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        changed_accounts.sort_by_cached_key(|address| keccak256(address));
+        let got = self.state_root()?;
+        match expected {
+            Some(expected) if got != expected => Err(StateRootMismatch {
+                expected,
+                got,
+                changed_accounts,
+            }
+            .into()),
+            _ => Ok(got),
+        }
+    }
+}
+
+const PARALLEL_STORAGE_TRIES: usize = 64;
+
+const MISMATCH_ACCOUNTS_SHOWN: usize = 10;
+
+const IMPORT_FLUSH_INTERVAL: usize = 100_000;
+
+// A zero code hash would otherwise only be caught by a debug assertion when
+// the account is marshaled, deep in `state_root`
+fn check_code_hash(account: &Option<Account>) {
+    if let Some(account) = account {
+        assert!(
+            !account.code_hash.is_zero(),
+            "Account code hash is zero, use EMPTY_CODE_HASH for an account without code"
+        );
+    }
+}
+
+fn parse_version(version: &[u8]) -> anyhow::Result<u32> {
+    std::str::from_utf8(version)
+        .ok()
+        .and_then(|version| version.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid DB_VERSION {:?}", version))
+}
+
+fn parse_destroy_marker(marker: &[u8]) -> anyhow::Result<(H160, usize)> {
+    anyhow::ensure!(
+        marker.len() == 28,
+        "Invalid destroy_storage_in_batches marker {:?}",
+        marker
+    );
+    let batch_size = u64::from_be_bytes(marker[20..].try_into().unwrap());
+    Ok((H160::from_slice(&marker[..20]), batch_size as usize))
+}
+
+// The value of an account trie leaf
+fn account_leaf(account: &Account, storage_root: H256) -> Vec<u8> {
+    let mut s = RlpStream::new_list(4);
+    s.append(&account.nonce)
+        .append(&account.balance)
+        .append(&storage_root)
+        .append(&account.code_hash);
+    s.out().to_vec()
+}
+
+// Decodes the value of an account trie leaf
+fn account_from_leaf(leaf: &[u8]) -> anyhow::Result<Account> {
+    let rlp = rlp::Rlp::new(leaf);
+    Ok(Account {
+        nonce: rlp.val_at(0)?,
+        balance: rlp.val_at(1)?,
+        code_hash: rlp.val_at(3)?,
+    })
+}
+
+fn verify_code(code_hash: H256, code: Option<&[u8]>) -> Result<(), DbError> {
+    match code {
+        Some(code) if keccak256(code) != code_hash => Err(DbError::CorruptCode { hash: code_hash }),
+        _ => Ok(()),
+    }
+}
+
+// Merges the accounts stored in the transaction with the pending ones, which
+// take precedence.
+struct AccountIter<'a, 'db> {
+    entries: PrefixIter<'a, 'db>,
+    stored: Option<(H160, Account)>,
+    pending: std::iter::Peekable<std::vec::IntoIter<(H160, Option<Account>)>>,
+}
+
+impl<'a, 'db> AccountIter<'a, 'db> {
+    fn next_stored(&mut self) -> anyhow::Result<Option<(H160, Account)>> {
+        while let Some(entry) = self.entries.next() {
+            let (key, value) = entry?;
+            if let Some(address) = account_key_address(&key) {
+                self.entries.seek(&storage_end_key(address));
+                return Ok(Some((address, Account::unmarshal(&value)?)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, 'db> Iterator for AccountIter<'a, 'db> {
+    type Item = anyhow::Result<(H160, Account)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.stored.is_none() {
+                match self.next_stored() {
+                    Ok(stored) => self.stored = stored,
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            let order = match (&self.stored, self.pending.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((stored, _)), Some((pending, _))) => stored.cmp(pending),
+            };
+            match order {
+                Ordering::Less => return self.stored.take().map(Ok),
+                Ordering::Equal => self.stored = None,
+                Ordering::Greater => {}
+            }
+            // A pending `None` is a deleted account
+            if let (address, Some(account)) = self.pending.next().unwrap() {
+                return Some(Ok((address, account)));
+            }
+        }
+    }
+}
+
+// Storage tries occupy disjoint key ranges, so they can be walked
+// concurrently against a shared transaction.
+fn walk_storage_tries(
+    tx: &BackendTransaction,
+    storage_tries: Vec<(Vec<u8>, DirtyList)>,
+    epoch: Option<u8>,
+) -> anyhow::Result<Vec<(H256, StagedWrites)>> {
+    // A single walker is reset for each trie of a chunk
+    let walk = |storage_tries: Vec<(Vec<u8>, DirtyList)>| {
+        let (trie_prefixes, dirty_lists): (Vec<_>, Vec<_>) = storage_tries.into_iter().unzip();
+        let mut walker: Option<Walker> = None;
+        let mut res = Vec::with_capacity(trie_prefixes.len());
+        for (trie_prefix, dirty_storage) in trie_prefixes.iter().zip(dirty_lists) {
+            let walker = match &mut walker {
+                Some(walker) => {
+                    walker.reset(trie_prefix, dirty_storage);
+                    walker
+                }
+                None => {
+                    walker.insert(Walker::new(trie_prefix, dirty_storage, tx).with_epoch(epoch))
+                }
+            };
+            res.push((walker.root()?, walker.take_writes()));
+        }
+        anyhow::Ok(res)
+    };
+    let num_threads = std::thread::available_parallelism().map_or(1, |x| x.get());
+    if num_threads == 1 || storage_tries.len() < PARALLEL_STORAGE_TRIES {
+        return walk(storage_tries);
+    }
+
+    let chunk_size = storage_tries.len() / num_threads + 1;
+    let mut storage_tries = storage_tries.into_iter();
+    let mut chunks = Vec::new();
+    loop {
+        let chunk: Vec<_> = storage_tries.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        chunks.push(chunk);
+    }
+    std::thread::scope(|scope| {
+        let walk = &walk;
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || walk(chunk)))
+            .collect();
+        let mut res = Vec::new();
+        for handle in handles {
+            res.extend(handle.join().unwrap()?);
+        }
+        Ok(res)
+    })
+}
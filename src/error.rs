@@ -0,0 +1,50 @@
+use ethereum_types::H256;
+
+// Errors a caller may want to react to, others are reported through
+// `anyhow`. Recover them with `anyhow::Error::downcast_ref`.
+#[derive(Debug)]
+pub enum DbError {
+    // The database reached the maximum size of its memory map
+    MapFull,
+    // The trie nodes of this root have been overwritten
+    RootUnavailable(H256),
+    // Stored code does not hash to the code hash it is stored under
+    CorruptCode { hash: H256 },
+    Mdbx(libmdbx::Error),
+}
+
+impl From<libmdbx::Error> for DbError {
+    fn from(err: libmdbx::Error) -> Self {
+        match err {
+            libmdbx::Error::MapFull => DbError::MapFull,
+            err => DbError::Mdbx(err),
+        }
+    }
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::MapFull => write!(
+                f,
+                "Database map is full, reopen with a larger `DbOptions::max_size`"
+            ),
+            DbError::RootUnavailable(root) => {
+                write!(f, "Trie nodes of root {:?} are no longer stored", root)
+            }
+            DbError::CorruptCode { hash } => {
+                write!(f, "Code stored under hash {:?} is corrupt", hash)
+            }
+            DbError::Mdbx(err) => write!(f, "MDBX error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::MapFull | DbError::RootUnavailable(_) | DbError::CorruptCode { .. } => None,
+            DbError::Mdbx(err) => Some(err),
+        }
+    }
+}
@@ -0,0 +1,359 @@
+// Proof of concept of storing trie nodes keyed by hash rather than by path.
+//
+// Each node is stored under `node_key(keccak256(rlp(node)))`, including nodes
+// short enough to be embedded in their parent, so a node can always be found
+// from its reference in the parent. Identical subtrees share storage, and a
+// node's key never changes when the trie is restructured above it, whereas
+// the path keyed layout has to move subtrees on a split or raise. A node's
+// key is also exactly what `GetNodeData` asks for.
+//
+// Nothing is ever deleted. Pruning needs a reference count per node: every
+// write increments the counts of the node's children, removing a node
+// decrements them and deletes any that reach zero, recursively. The counts
+// must be updated in the same transaction as the nodes, and because subtrees
+// are shared a count can only be decremented once the old root is no longer
+// wanted, e.g. after some number of blocks. The path keyed layout avoids all
+// of this, since writing a node at a path overwrites the old one.
+
+use arrayvec::ArrayVec;
+use ethereum_types::H256;
+use smallvec::SmallVec;
+
+use crate::backend::BackendTransaction;
+use crate::keys::node_key;
+use crate::structs::{InternalNode, NibbleList};
+use crate::util::{common_prefix, keccak256};
+use crate::walk::{DirtyList, EMPTY_TRIE_ROOT};
+
+#[derive(Default)]
+enum Child {
+    #[default]
+    Empty,
+    Stored(ArrayVec<u8, 32>),
+    Loaded(Box<Node>),
+}
+
+enum Node {
+    Leaf {
+        rest_of_key: NibbleList,
+        value: SmallVec<[u8; 36]>,
+    },
+    Branch {
+        extension_nibbles: NibbleList,
+        children: Box<[Child; 16]>,
+    },
+}
+
+fn reference_key(reference: &[u8]) -> Vec<u8> {
+    if reference.len() == 32 {
+        node_key(H256::from_slice(reference))
+    } else {
+        node_key(keccak256(reference))
+    }
+}
+
+fn load(tx: &BackendTransaction, reference: &[u8]) -> anyhow::Result<Node> {
+    let data = tx
+        .get(&reference_key(reference))?
+        .ok_or_else(|| anyhow::anyhow!("Missing trie node {:?}", reference))?;
+    Ok(match InternalNode::unmarshal(&data)? {
+        InternalNode::Leaf { rest_of_key, value } => Node::Leaf { rest_of_key, value },
+        InternalNode::Branch {
+            extension_nibbles,
+            subnodes,
+        } => Node::Branch {
+            extension_nibbles,
+            children: Box::new(subnodes.map(|subnode| {
+                if subnode.is_empty() {
+                    Child::Empty
+                } else {
+                    Child::Stored(subnode)
+                }
+            })),
+        },
+    })
+}
+
+fn resolve(tx: &BackendTransaction, child: Child) -> anyhow::Result<Option<Node>> {
+    Ok(match child {
+        Child::Empty => None,
+        Child::Stored(reference) => Some(load(tx, &reference)?),
+        Child::Loaded(node) => Some(*node),
+    })
+}
+
+fn make_leaf(key: &[u8], value: SmallVec<[u8; 36]>) -> anyhow::Result<Node> {
+    Ok(Node::Leaf {
+        rest_of_key: NibbleList::try_from(key)?,
+        value,
+    })
+}
+
+fn make_branch(
+    extension_nibbles: &[u8],
+    (index0, node0): (u8, Node),
+    (index1, node1): (u8, Node),
+) -> anyhow::Result<Node> {
+    let mut children: Box<[Child; 16]> = Default::default();
+    children[index0 as usize] = Child::Loaded(Box::new(node0));
+    children[index1 as usize] = Child::Loaded(Box::new(node1));
+    Ok(Node::Branch {
+        extension_nibbles: NibbleList::try_from(extension_nibbles)?,
+        children,
+    })
+}
+
+fn update(
+    tx: &BackendTransaction,
+    node: Option<Node>,
+    key: &[u8],
+    new_value: Option<SmallVec<[u8; 36]>>,
+) -> anyhow::Result<Option<Node>> {
+    Ok(match (node, new_value) {
+        (None, None) => None,
+        (None, Some(new_value)) => Some(make_leaf(key, new_value)?),
+        (Some(Node::Leaf { rest_of_key, value }), new_value) => {
+            let common_prefix_len = common_prefix(&rest_of_key, key);
+            if common_prefix_len == rest_of_key.len() {
+                new_value.map(|new_value| Node::Leaf {
+                    rest_of_key,
+                    value: new_value,
+                })
+            } else if let Some(new_value) = new_value {
+                Some(make_branch(
+                    &key[..common_prefix_len],
+                    (
+                        rest_of_key[common_prefix_len],
+                        make_leaf(&rest_of_key[common_prefix_len + 1..], value)?,
+                    ),
+                    (
+                        key[common_prefix_len],
+                        make_leaf(&key[common_prefix_len + 1..], new_value)?,
+                    ),
+                )?)
+            } else {
+                Some(Node::Leaf { rest_of_key, value })
+            }
+        }
+        (
+            Some(Node::Branch {
+                extension_nibbles,
+                mut children,
+            }),
+            new_value,
+        ) => {
+            let common_prefix_len = common_prefix(&extension_nibbles, key);
+            if common_prefix_len < extension_nibbles.len() {
+                match new_value {
+                    None => Some(Node::Branch {
+                        extension_nibbles,
+                        children,
+                    }),
+                    Some(new_value) => {
+                        let branch = Node::Branch {
+                            extension_nibbles: NibbleList::try_from(
+                                &extension_nibbles[common_prefix_len + 1..],
+                            )?,
+                            children,
+                        };
+                        Some(make_branch(
+                            &key[..common_prefix_len],
+                            (extension_nibbles[common_prefix_len], branch),
+                            (
+                                key[common_prefix_len],
+                                make_leaf(&key[common_prefix_len + 1..], new_value)?,
+                            ),
+                        )?)
+                    }
+                }
+            } else {
+                let index = key[extension_nibbles.len()] as usize;
+                let child = resolve(tx, std::mem::take(&mut children[index]))?;
+                if let Some(child) =
+                    update(tx, child, &key[extension_nibbles.len() + 1..], new_value)?
+                {
+                    children[index] = Child::Loaded(Box::new(child));
+                }
+                collapse(tx, extension_nibbles, children)?
+            }
+        }
+    })
+}
+
+// Prefixes `nibbles ++ [index]` onto a node
+fn prepend(index: u8, node: Node, nibbles: &[u8]) -> anyhow::Result<Node> {
+    let mut prefix = NibbleList::try_from(nibbles)?;
+    prefix.push(index);
+    Ok(match node {
+        Node::Leaf { rest_of_key, value } => {
+            prefix.try_extend_from_slice(&rest_of_key)?;
+            Node::Leaf {
+                rest_of_key: prefix,
+                value,
+            }
+        }
+        Node::Branch {
+            extension_nibbles,
+            children,
+        } => {
+            prefix.try_extend_from_slice(&extension_nibbles)?;
+            Node::Branch {
+                extension_nibbles: prefix,
+                children,
+            }
+        }
+    })
+}
+
+// A branch left with a single child is merged into it
+fn collapse(
+    tx: &BackendTransaction,
+    extension_nibbles: NibbleList,
+    mut children: Box<[Child; 16]>,
+) -> anyhow::Result<Option<Node>> {
+    let mut remaining = children
+        .iter()
+        .enumerate()
+        .filter(|(_, child)| !matches!(child, Child::Empty))
+        .map(|(i, _)| i);
+    Ok(match (remaining.next(), remaining.next()) {
+        (None, _) => None,
+        (Some(index), None) => {
+            let child = resolve(tx, std::mem::take(&mut children[index]))?.unwrap();
+            Some(prepend(index as u8, child, &extension_nibbles)?)
+        }
+        (Some(_), Some(_)) => Some(Node::Branch {
+            extension_nibbles,
+            children,
+        }),
+    })
+}
+
+// Writes every loaded node and returns the reference to `node`
+fn write(tx: &mut BackendTransaction, node: Node) -> anyhow::Result<ArrayVec<u8, 32>> {
+    let node = match node {
+        Node::Leaf { rest_of_key, value } => InternalNode::Leaf { rest_of_key, value },
+        Node::Branch {
+            extension_nibbles,
+            children,
+        } => {
+            let mut subnodes: [ArrayVec<u8, 32>; 16] = Default::default();
+            for (subnode, child) in subnodes.iter_mut().zip(*children) {
+                *subnode = match child {
+                    Child::Empty => ArrayVec::new(),
+                    Child::Stored(reference) => reference,
+                    Child::Loaded(child) => write(tx, *child)?,
+                };
+            }
+            InternalNode::Branch {
+                extension_nibbles,
+                subnodes,
+            }
+        }
+    };
+    let reference = node.encode();
+    tx.put(&reference_key(&reference), &node.marshal())?;
+    Ok(reference)
+}
+
+// Applies `dirty_list` to the hash keyed trie with root `root` and returns
+// the new root. The old root remains readable.
+pub fn update_root(
+    tx: &mut BackendTransaction,
+    root: H256,
+    dirty_list: DirtyList,
+) -> anyhow::Result<H256> {
+    let mut node = if root == *EMPTY_TRIE_ROOT {
+        None
+    } else {
+        Some(load(tx, root.as_bytes())?)
+    };
+    for (key, value) in dirty_list {
+        node = update(tx, node, &key.unpack(), value)?;
+    }
+    Ok(match node {
+        None => *EMPTY_TRIE_ROOT,
+        Some(node) => {
+            let reference = write(tx, node)?;
+            if reference.len() < 32 {
+                keccak256(reference)
+            } else {
+                H256::from_slice(&reference)
+            }
+        }
+    })
+}
+
+// Looks up the value stored under `key` in the trie with root `root`
+pub fn get(
+    tx: &BackendTransaction,
+    root: H256,
+    key: &[u8],
+) -> anyhow::Result<Option<SmallVec<[u8; 36]>>> {
+    if root == *EMPTY_TRIE_ROOT {
+        return Ok(None);
+    }
+    let mut node = load(tx, root.as_bytes())?;
+    let mut key = key;
+    loop {
+        match node {
+            Node::Leaf { rest_of_key, value } => {
+                return Ok((rest_of_key.as_slice() == key).then_some(value));
+            }
+            Node::Branch {
+                extension_nibbles,
+                mut children,
+            } => {
+                if !key.starts_with(&extension_nibbles) {
+                    return Ok(None);
+                }
+                let index = key[extension_nibbles.len()] as usize;
+                key = &key[extension_nibbles.len() + 1..];
+                node = match resolve(tx, std::mem::take(&mut children[index]))? {
+                    None => return Ok(None),
+                    Some(child) => child,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::keys::ACCOUNT_TRIE_PREFIX;
+    use crate::structs::get_packed_key;
+    use crate::walk::{sort_dirty_list, Walker};
+
+    #[test]
+    fn test_matches_path_keyed() {
+        let backend = Backend::memory().unwrap();
+        let mut tx = backend.begin_mut().unwrap();
+        let mut root = *EMPTY_TRIE_ROOT;
+        for batch in 0..20u32 {
+            let mut dirty_list: DirtyList = (0..50u32)
+                .map(|i| {
+                    let key = get_packed_key(((batch * 7 + i) % 300).to_be_bytes());
+                    if (batch + i) % 3 == 0 {
+                        (key, None)
+                    } else {
+                        (key, Some(SmallVec::from_slice(&[batch as u8, i as u8])))
+                    }
+                })
+                .collect();
+            sort_dirty_list(&mut dirty_list);
+            root = update_root(&mut tx, root, dirty_list.clone()).unwrap();
+
+            let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list.clone(), &tx);
+            let expected = walker.root().unwrap();
+            let writes = walker.into_writes();
+            tx.apply(writes);
+            assert_eq!(root, expected);
+
+            for (key, value) in dirty_list {
+                assert_eq!(get(&tx, root, &key.unpack()).unwrap(), value);
+            }
+        }
+    }
+}
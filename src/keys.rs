@@ -0,0 +1,162 @@
+// Builders for database keys. The first byte of every key selects its
+// `Table`. Accounts and storage share `STATE_PREFIX` and are told apart by
+// length: an account key is the prefix and a 20 byte address, a storage key
+// additionally has a 32 byte slot.
+
+use ethereum_types::{H160, H256};
+
+use crate::backend::{Table, MAX_KEY_LEN};
+use crate::structs::nibble_list_to_key;
+
+pub const METADATA_PREFIX: u8 = Table::Metadata as u8;
+pub const STATE_PREFIX: u8 = Table::State as u8;
+pub const TRIE_PREFIX: u8 = Table::Trie as u8;
+pub const CODE_PREFIX: u8 = Table::Code as u8;
+pub const NODES_PREFIX: u8 = Table::Nodes as u8;
+pub const PREIMAGES_PREFIX: u8 = Table::Preimages as u8;
+pub const RAW_PREFIX: u8 = Table::Raw as u8;
+
+// Room left after the prefix and namespace
+pub const MAX_RAW_KEY_LEN: usize = MAX_KEY_LEN - 2;
+
+// Prefix of the account trie's nodes
+pub const ACCOUNT_TRIE_PREFIX: &[u8] = &[TRIE_PREFIX];
+
+pub fn metadata_key(key: &[u8]) -> Vec<u8> {
+    let mut db_key = Vec::with_capacity(1 + key.len());
+    db_key.push(METADATA_PREFIX);
+    db_key.extend_from_slice(key);
+    db_key
+}
+
+pub fn account_key(address: H160) -> Vec<u8> {
+    let mut db_key = Vec::with_capacity(21);
+    db_key.push(STATE_PREFIX);
+    db_key.extend_from_slice(address.as_bytes());
+    db_key
+}
+
+// Every storage key of `address` starts with its account key
+pub fn storage_key(address: H160, slot: H256) -> Vec<u8> {
+    let mut db_key = Vec::with_capacity(53);
+    db_key.push(STATE_PREFIX);
+    db_key.extend_from_slice(address.as_bytes());
+    db_key.extend_from_slice(slot.as_bytes());
+    db_key
+}
+
+// The address of an account key, `None` for a storage key
+pub fn account_key_address(db_key: &[u8]) -> Option<H160> {
+    match db_key {
+        [STATE_PREFIX, address @ ..] if address.len() == 20 => Some(H160::from_slice(address)),
+        _ => None,
+    }
+}
+
+// The address owning a storage key, `None` for an account key
+pub fn storage_key_address(db_key: &[u8]) -> Option<H160> {
+    match db_key {
+        [STATE_PREFIX, rest @ ..] if rest.len() == 52 => Some(H160::from_slice(&rest[..20])),
+        _ => None,
+    }
+}
+
+// Sorts after every storage key of `address` and before the next account
+pub fn storage_end_key(address: H160) -> Vec<u8> {
+    let mut db_key = storage_key(address, H256::repeat_byte(0xff));
+    db_key.push(0);
+    db_key
+}
+
+pub fn code_key(code_hash: H256) -> Vec<u8> {
+    let mut db_key = Vec::with_capacity(33);
+    db_key.push(CODE_PREFIX);
+    db_key.extend_from_slice(code_hash.as_bytes());
+    db_key
+}
+
+// Prefix of a storage trie's nodes, `internal_key` is the nibbles of the
+// hashed address
+pub fn storage_trie_prefix(internal_key: &[u8]) -> Vec<u8> {
+    debug_assert_eq!(internal_key.len(), 64, "Storage trie prefix is not a hash");
+    let mut trie_prefix = Vec::with_capacity(1 + internal_key.len());
+    trie_prefix.push(TRIE_PREFIX);
+    trie_prefix.extend_from_slice(internal_key);
+    trie_prefix
+}
+
+// Key of the node at path `nibbles` in the trie with prefix `trie_prefix`
+pub fn trie_key(trie_prefix: &[u8], nibbles: &[u8]) -> Vec<u8> {
+    let mut db_key = trie_prefix.to_vec();
+    db_key.extend_from_slice(&nibble_list_to_key(nibbles));
+    debug_assert!(
+        db_key.len() <= MAX_KEY_LEN,
+        "Trie key of {} bytes at depth {}",
+        db_key.len(),
+        nibbles.len()
+    );
+    db_key
+}
+
+pub fn node_key(node_hash: H256) -> Vec<u8> {
+    let mut db_key = Vec::with_capacity(33);
+    db_key.push(NODES_PREFIX);
+    db_key.extend_from_slice(node_hash.as_bytes());
+    db_key
+}
+
+pub fn preimage_key(hash: H256) -> Vec<u8> {
+    let mut db_key = Vec::with_capacity(33);
+    db_key.push(PREIMAGES_PREFIX);
+    db_key.extend_from_slice(hash.as_bytes());
+    db_key
+}
+
+// Fails if the key does not fit alongside the prefix and `namespace`
+pub fn raw_key(namespace: u8, key: &[u8]) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        key.len() <= MAX_RAW_KEY_LEN,
+        "Raw key of {} bytes exceeds the limit of {}",
+        key.len(),
+        MAX_RAW_KEY_LEN
+    );
+    let mut db_key = Vec::with_capacity(2 + key.len());
+    db_key.push(RAW_PREFIX);
+    db_key.push(namespace);
+    db_key.extend_from_slice(key);
+    Ok(db_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::get_internal_key;
+
+    #[test]
+    fn test_key_layout() {
+        let address = H160::repeat_byte(0xaa);
+        let slot = H256::repeat_byte(0xbb);
+        assert_eq!(account_key(address).len(), 21);
+        assert_eq!(storage_key(address, slot).len(), 53);
+        assert!(storage_key(address, slot).starts_with(&account_key(address)));
+        assert_eq!(account_key_address(&account_key(address)), Some(address));
+        assert_eq!(account_key_address(&storage_key(address, slot)), None);
+        assert_eq!(
+            storage_key_address(&storage_key(address, slot)),
+            Some(address)
+        );
+        assert_eq!(storage_key_address(&account_key(address)), None);
+        assert!(storage_end_key(address) > storage_key(address, slot));
+        assert!(storage_end_key(address) < account_key(H160::repeat_byte(0xab)));
+        assert_eq!(code_key(slot)[0], CODE_PREFIX);
+        assert_eq!(metadata_key(b"version"), b"\0version");
+        assert_eq!(raw_key(7, b"k").unwrap(), [RAW_PREFIX, 7, b'k']);
+        assert!(raw_key(7, &[0; MAX_RAW_KEY_LEN + 1]).is_err());
+
+        let trie_prefix = storage_trie_prefix(&get_internal_key(address));
+        assert_eq!(trie_prefix.len(), 65);
+        assert!(trie_prefix.starts_with(ACCOUNT_TRIE_PREFIX));
+        assert_eq!(trie_key(&trie_prefix, &[]), trie_prefix);
+        assert_eq!(trie_key(ACCOUNT_TRIE_PREFIX, &[1, 2, 3]), [2, 0x12, 0x30]);
+    }
+}
@@ -0,0 +1,508 @@
+// Verification of Merkle proofs. A proof is the list of RLP encoded trie
+// nodes on the path from the root to a key, as returned by `eth_getProof`.
+// Nodes shorter than 32 bytes are embedded in their parent and are not
+// listed separately.
+
+use anyhow::Context;
+use arrayvec::ArrayVec;
+use ethereum_types::{H160, H256, U256};
+use rlp::Rlp;
+use smallvec::SmallVec;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::slice;
+
+use crate::db::MutableTransaction;
+
+use crate::trie_core::{
+    common_prefix, extension_ref, hp_decode_nibble_list, InternalNode, NibbleList, PackedKey,
+};
+use crate::util::{keccak256, to_hex, EMPTY_CODE_HASH};
+use crate::walk::EMPTY_TRIE_ROOT;
+
+// What `eth_getProof` returns for an account and its slots
+struct EthProof {
+    address: H160,
+    account_proof: Vec<Vec<u8>>,
+    balance: U256,
+    code_hash: H256,
+    nonce: u64,
+    storage_hash: H256,
+    // Key, value and proof of each slot
+    storage_proof: Vec<(H256, U256, Vec<Vec<u8>>)>,
+}
+
+// The proofs of `account_proof_json`, checked against the current root
+fn eth_get_proof(
+    tx: &mut MutableTransaction,
+    address: H160,
+    storage_keys: &[H256],
+) -> anyhow::Result<EthProof> {
+    let root = tx.state_root()?;
+    let account_proof = tx.account_proof(root, address)?;
+    let (nonce, balance, storage_hash, code_hash): (u64, U256, H256, H256) =
+        match verify_proof(root, keccak256(address).as_bytes(), &account_proof)? {
+            None => (0, U256::zero(), *EMPTY_TRIE_ROOT, *EMPTY_CODE_HASH),
+            Some(leaf) => {
+                let rlp = Rlp::new(&leaf);
+                (
+                    rlp.val_at(0)?,
+                    rlp.val_at(1)?,
+                    rlp.val_at(2)?,
+                    rlp.val_at(3)?,
+                )
+            }
+        };
+    let mut storage_proof = Vec::with_capacity(storage_keys.len());
+    for key in storage_keys {
+        let proof = tx.storage_proof(root, address, *key)?;
+        let value = verify_storage_proof(storage_hash, *key, &proof)?;
+        storage_proof.push((*key, value, proof));
+    }
+    Ok(EthProof {
+        address,
+        account_proof,
+        balance,
+        code_hash,
+        nonce,
+        storage_hash,
+        storage_proof,
+    })
+}
+
+// The result of `eth_getProof` for the account and its slots `storage_keys`
+// in the current state, as JSON text. Without the `json` feature the crate
+// has no JSON dependency, so this writes it directly, as `state_test` does,
+// see `account_proof_value` for a parsed value. Quantities are minimal hex
+// and the proofs are lists of hex nodes. A missing account has the empty
+// code hash and storage root, and an empty `accountProof` if the state is
+// empty. Pending changes are flushed first.
+pub fn account_proof_json(
+    tx: &mut MutableTransaction,
+    address: H160,
+    storage_keys: &[H256],
+) -> anyhow::Result<String> {
+    let proof = eth_get_proof(tx, address, storage_keys)?;
+    let mut res = String::new();
+    write!(
+        res,
+        "{{\"address\":\"{:?}\",\"accountProof\":{},\"balance\":\"0x{:x}\",\"codeHash\":\"{:?}\",\"nonce\":\"0x{:x}\",\"storageHash\":\"{:?}\",\"storageProof\":[",
+        proof.address,
+        hex_list(&proof.account_proof),
+        proof.balance,
+        proof.code_hash,
+        proof.nonce,
+        proof.storage_hash
+    )?;
+    for (i, (key, value, nodes)) in proof.storage_proof.iter().enumerate() {
+        if i > 0 {
+            res.push(',');
+        }
+        write!(
+            res,
+            "{{\"key\":\"{:?}\",\"value\":\"0x{:x}\",\"proof\":{}}}",
+            key,
+            value,
+            hex_list(nodes)
+        )?;
+    }
+    res.push_str("]}");
+    Ok(res)
+}
+
+// As `account_proof_json`, as a `serde_json::Value`
+#[cfg(feature = "json")]
+pub fn account_proof_value(
+    tx: &mut MutableTransaction,
+    address: H160,
+    storage_keys: &[H256],
+) -> anyhow::Result<serde_json::Value> {
+    let proof = eth_get_proof(tx, address, storage_keys)?;
+    let hex_nodes = |nodes: &[Vec<u8>]| -> Vec<String> {
+        nodes
+            .iter()
+            .map(|node| format!("0x{}", to_hex(node)))
+            .collect()
+    };
+    let storage_proof: Vec<_> = proof
+        .storage_proof
+        .iter()
+        .map(|(key, value, nodes)| {
+            serde_json::json!({
+                "key": format!("{:?}", key),
+                "value": format!("0x{:x}", value),
+                "proof": hex_nodes(nodes),
+            })
+        })
+        .collect();
+    Ok(serde_json::json!({
+        "address": format!("{:?}", proof.address),
+        "accountProof": hex_nodes(&proof.account_proof),
+        "balance": format!("0x{:x}", proof.balance),
+        "codeHash": format!("{:?}", proof.code_hash),
+        "nonce": format!("0x{:x}", proof.nonce),
+        "storageHash": format!("{:?}", proof.storage_hash),
+        "storageProof": storage_proof,
+    }))
+}
+
+fn hex_list(nodes: &[Vec<u8>]) -> String {
+    let items: Vec<_> = nodes
+        .iter()
+        .map(|node| format!("\"0x{}\"", to_hex(node)))
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+// Proof nodes already checked against their hash, shared between the proofs
+// of `verify_many`
+type Verified<'a> = HashMap<H256, &'a [u8]>;
+
+// Returns the value under `key` in the trie with root `root`, or `None` if
+// the proof shows there is none. `key` is the path in the trie, so for the
+// state and storage tries it is the hash of the address or slot.
+pub fn verify_proof(root: H256, key: &[u8], proof: &[Vec<u8>]) -> anyhow::Result<Option<Vec<u8>>> {
+    verify(root, key, proof, &mut HashMap::new())
+}
+
+// Returns the value of the storage slot `slot` in the storage trie with root
+// `storage_root`, zero if the proof shows there is none. The storage root is
+// that of the account, as proved by its own proof.
+pub fn verify_storage_proof(
+    storage_root: H256,
+    slot: H256,
+    proof: &[Vec<u8>],
+) -> anyhow::Result<U256> {
+    match verify_proof(storage_root, keccak256(slot).as_bytes(), proof)? {
+        None => Ok(U256::zero()),
+        Some(leaf) => decode_storage_value(&leaf),
+    }
+}
+
+// The value of a storage slot from its leaf in the storage trie, which holds
+// the RLP of the value. Zero slots are deleted from the trie, so a leaf
+// holding zero is invalid.
+pub fn decode_storage_value(leaf: &[u8]) -> anyhow::Result<U256> {
+    let value: U256 = rlp::decode(leaf)?;
+    anyhow::ensure!(!value.is_zero(), "Storage leaf holds zero");
+    Ok(value)
+}
+
+// Verifies each `(key, proof)` pair as `verify_proof`, failing on the first
+// invalid proof. Nodes shared between the proofs, such as the top of the
+// trie, are only hashed once.
+pub fn verify_many(
+    root: H256,
+    items: &[(Vec<u8>, Vec<Vec<u8>>)],
+) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+    let mut verified = HashMap::new();
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, (key, proof))| {
+            verify(root, key, proof, &mut verified)
+                .with_context(|| format!("Proof {} is invalid", i))
+        })
+        .collect()
+}
+
+fn verify<'a>(
+    root: H256,
+    key: &[u8],
+    proof: &'a [Vec<u8>],
+    verified: &mut Verified<'a>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    if root == *EMPTY_TRIE_ROOT && proof.is_empty() {
+        return Ok(None);
+    }
+    let nibbles: Vec<u8> = key
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0F])
+        .collect();
+    let mut proof = proof.iter();
+    let mut node = next_node(root, &mut proof, verified)?;
+    let mut depth = 0;
+    let value = loop {
+        let child = match node.prototype()? {
+            rlp::Prototype::Data(0) => break None,
+            rlp::Prototype::List(2) => {
+                let (path, is_leaf) = hp_decode_nibble_list(node.at(0)?.data()?)?;
+                if is_leaf {
+                    break (nibbles[depth..] == path[..])
+                        .then(|| node.at(1)?.data().map(|value| value.to_vec()))
+                        .transpose()?;
+                }
+                anyhow::ensure!(!path.is_empty(), "Extension node has no nibbles");
+                if !nibbles[depth..].starts_with(&path) {
+                    break None;
+                }
+                depth += path.len();
+                node.at(1)?
+            }
+            rlp::Prototype::List(17) => {
+                if depth == nibbles.len() {
+                    let value = node.at(16)?.data()?;
+                    break (!value.is_empty()).then(|| value.to_vec());
+                }
+                depth += 1;
+                node.at(nibbles[depth - 1] as usize)?
+            }
+            _ => anyhow::bail!("Invalid trie node"),
+        };
+        node = if child.is_list() {
+            child
+        } else {
+            match child.data()? {
+                [] => break None,
+                hash if hash.len() == 32 => {
+                    next_node(H256::from_slice(hash), &mut proof, verified)?
+                }
+                _ => anyhow::bail!("Invalid node reference"),
+            }
+        };
+    };
+    anyhow::ensure!(proof.next().is_none(), "Proof has unused nodes");
+    Ok(value)
+}
+
+// Takes the next node of the proof, which must have hash `hash`
+fn next_node<'a>(
+    hash: H256,
+    proof: &mut slice::Iter<'a, Vec<u8>>,
+    verified: &mut Verified<'a>,
+) -> anyhow::Result<Rlp<'a>> {
+    let node = proof
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Proof is missing node {:?}", hash))?;
+    if verified.get(&hash) != Some(&node.as_slice()) {
+        anyhow::ensure!(
+            keccak256(node) == hash,
+            "Proof node does not match {:?}",
+            hash
+        );
+        verified.insert(hash, node);
+    }
+    Ok(Rlp::new(node))
+}
+
+// Verifies that `leaves`, sorted by key, are every leaf of the trie with
+// root `root` from `start` up to the last of them, as in snap sync. `proof`
+// holds the nodes on the paths to `start` and to the last leaf, in any
+// order. With no leaves, it shows there are none from `start` on. Returns
+// whether the trie has more leaves after the last.
+pub fn verify_range(
+    root: H256,
+    start: H256,
+    leaves: &[(H256, Vec<u8>)],
+    proof: &[Vec<u8>],
+) -> anyhow::Result<bool> {
+    anyhow::ensure!(
+        leaves.windows(2).all(|pair| pair[0].0 < pair[1].0),
+        "Leaves are not in key order"
+    );
+    anyhow::ensure!(
+        leaves.first().is_none_or(|(key, _)| *key >= start),
+        "Leaves start before the range"
+    );
+    if root == *EMPTY_TRIE_ROOT {
+        anyhow::ensure!(leaves.is_empty(), "Leaves of an empty trie");
+        return Ok(false);
+    }
+    let nodes: Verified = proof
+        .iter()
+        .map(|node| (keccak256(node), node.as_slice()))
+        .collect();
+    let range = Range {
+        start: PackedKey(start.0).unpack(),
+        last: match leaves.last() {
+            Some((key, _)) => PackedKey(key.0).unpack(),
+            None => [0x0F; 64].into(),
+        },
+    };
+    // The trie is rebuilt from everything outside the range, as shown by the
+    // proof, and the leaves inside it
+    let mut items = Vec::new();
+    let root_node = nodes
+        .get(&root)
+        .ok_or_else(|| anyhow::anyhow!("Proof is missing node {:?}", root))?;
+    collect_outside(
+        &nodes,
+        Rlp::new(root_node),
+        &mut NibbleList::new(),
+        &range,
+        &mut items,
+    )?;
+    let has_more = items
+        .iter()
+        .any(|item| item.path()[..] > range.last[..item.path().len()]);
+    for (key, value) in leaves {
+        items.push(RangeItem::Leaf(PackedKey(key.0).unpack(), value));
+    }
+    items.sort_by(|a, b| a.path().cmp(b.path()));
+    let root_ref = build_ref(&items, 0)?;
+    let rebuilt_root = if root_ref.len() == 32 {
+        H256::from_slice(&root_ref)
+    } else {
+        keccak256(&root_ref)
+    };
+    anyhow::ensure!(rebuilt_root == root, "Range does not match the root");
+    Ok(has_more)
+}
+
+// The keys from `start` to `last` inclusive
+struct Range {
+    start: NibbleList,
+    last: NibbleList,
+}
+
+enum Position {
+    Outside,
+    Inside,
+    Straddles,
+}
+
+impl Range {
+    // Where the keys starting with `path` are relative to the range
+    fn position(&self, path: &[u8]) -> Position {
+        let len = path.len();
+        if *path < self.start[..len] || *path > self.last[..len] {
+            return Position::Outside;
+        }
+        let after_start = *path > self.start[..len] || self.start[len..].iter().all(|n| *n == 0);
+        let before_last = *path < self.last[..len] || self.last[len..].iter().all(|n| *n == 0x0F);
+        if after_start && before_last {
+            Position::Inside
+        } else {
+            Position::Straddles
+        }
+    }
+}
+
+// Part of a trie being rebuilt by `verify_range`
+enum RangeItem<'a> {
+    Leaf(NibbleList, &'a [u8]),
+    // A subtree at a branch slot, by its reference
+    Subtree(NibbleList, &'a [u8]),
+}
+
+impl RangeItem<'_> {
+    fn path(&self) -> &NibbleList {
+        match self {
+            RangeItem::Leaf(path, _) | RangeItem::Subtree(path, _) => path,
+        }
+    }
+}
+
+// Collects the leaves and subtrees below `node` at `path` outside the range
+fn collect_outside<'a>(
+    nodes: &Verified<'a>,
+    node: Rlp<'a>,
+    path: &mut NibbleList,
+    range: &Range,
+    items: &mut Vec<RangeItem<'a>>,
+) -> anyhow::Result<()> {
+    match node.prototype()? {
+        rlp::Prototype::List(2) => {
+            let (nibbles, is_leaf) = hp_decode_nibble_list(node.at(0)?.data()?)?;
+            let len = path.len();
+            path.try_extend_from_slice(&nibbles)?;
+            if is_leaf {
+                anyhow::ensure!(path.is_full(), "Leaf key has the wrong length");
+                if let Position::Outside = range.position(path) {
+                    items.push(RangeItem::Leaf(path.clone(), node.at(1)?.data()?));
+                }
+            } else {
+                anyhow::ensure!(!nibbles.is_empty(), "Extension node has no nibbles");
+                collect_child(nodes, node.at(1)?, path, range, items)?;
+            }
+            path.truncate(len);
+        }
+        rlp::Prototype::List(17) => {
+            for i in 0..16 {
+                let child = node.at(i)?;
+                if child.is_empty() {
+                    continue;
+                }
+                path.try_push(i as u8)?;
+                collect_child(nodes, child, path, range, items)?;
+                path.pop();
+            }
+        }
+        _ => anyhow::bail!("Invalid trie node"),
+    }
+    Ok(())
+}
+
+fn collect_child<'a>(
+    nodes: &Verified<'a>,
+    child: Rlp<'a>,
+    path: &mut NibbleList,
+    range: &Range,
+    items: &mut Vec<RangeItem<'a>>,
+) -> anyhow::Result<()> {
+    let node_ref = if child.is_list() {
+        child.as_raw()
+    } else {
+        child.data()?
+    };
+    match range.position(path) {
+        Position::Inside => Ok(()),
+        Position::Outside => {
+            items.push(RangeItem::Subtree(path.clone(), node_ref));
+            Ok(())
+        }
+        Position::Straddles => {
+            let node = if child.is_list() {
+                child
+            } else {
+                anyhow::ensure!(node_ref.len() == 32, "Invalid node reference");
+                let hash = H256::from_slice(node_ref);
+                Rlp::new(
+                    nodes
+                        .get(&hash)
+                        .ok_or_else(|| anyhow::anyhow!("Proof is missing node {:?}", hash))?,
+                )
+            };
+            collect_outside(nodes, node, path, range, items)
+        }
+    }
+}
+
+// The reference to the trie made of `items`, sorted by path, which share
+// their first `depth` nibbles
+fn build_ref(items: &[RangeItem], depth: usize) -> anyhow::Result<ArrayVec<u8, 32>> {
+    match items {
+        [] => Ok(ArrayVec::new()),
+        [RangeItem::Leaf(key, value)] => Ok(InternalNode::Leaf {
+            rest_of_key: key[depth..].try_into()?,
+            value: SmallVec::from_slice(value),
+        }
+        .encode()),
+        [RangeItem::Subtree(path, node_ref)] if path.len() == depth => Ok((*node_ref).try_into()?),
+        [RangeItem::Subtree(path, node_ref)] => Ok(extension_ref(&path[depth..], node_ref)),
+        _ => {
+            let first = items[0].path();
+            let branch_depth =
+                depth + common_prefix(&first[depth..], &items[items.len() - 1].path()[depth..]);
+            anyhow::ensure!(
+                items.iter().all(|item| item.path().len() > branch_depth),
+                "Range overlaps the proof"
+            );
+            let mut subnodes: [ArrayVec<u8, 32>; 16] = Default::default();
+            let mut rest = items;
+            for (nibble, subnode) in subnodes.iter_mut().enumerate() {
+                let len = rest
+                    .iter()
+                    .take_while(|item| item.path()[branch_depth] as usize == nibble)
+                    .count();
+                *subnode = build_ref(&rest[..len], branch_depth + 1)?;
+                rest = &rest[len..];
+            }
+            Ok(InternalNode::Branch {
+                extension_nibbles: first[depth..branch_depth].try_into()?,
+                subnodes,
+            }
+            .encode())
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// The account maps of the Ethereum state tests, such as their `pre` and
+// `post` sections:
+//
+// { "0x<address>": { "balance": "0x..", "code": "0x..", "nonce": "0x..",
+//                    "storage": { "0x<slot>": "0x<value>" } } }
+//
+// Only this shape is parsed, objects of strings, so a whole test file has to
+// be split with a JSON library first.
+
+use ethereum_types::{H160, H256, U256};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::util::to_hex;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestAccount {
+    pub balance: U256,
+    pub code: Vec<u8>,
+    pub nonce: u64,
+    pub storage: BTreeMap<H256, U256>,
+}
+
+pub type TestState = BTreeMap<H160, TestAccount>;
+
+// Balances and nonces are written as hex quantities, storage slots and values
+// as 32 byte hex
+pub fn to_json(state: &TestState) -> String {
+    let mut res = String::from("{");
+    for (i, (address, account)) in state.iter().enumerate() {
+        if i > 0 {
+            res.push(',');
+        }
+        write!(
+            res,
+            "\"{:?}\":{{\"balance\":\"0x{:x}\",\"code\":\"0x{}\",\"nonce\":\"0x{:x}\",\"storage\":{{",
+            address,
+            account.balance,
+            to_hex(&account.code),
+            account.nonce
+        )
+        .unwrap();
+        for (j, (slot, value)) in account.storage.iter().enumerate() {
+            if j > 0 {
+                res.push(',');
+            }
+            write!(res, "\"{:?}\":\"0x{:064x}\"", slot, value).unwrap();
+        }
+        res.push_str("}}");
+    }
+    res.push('}');
+    res
+}
+
+pub fn from_json(json: &str) -> anyhow::Result<TestState> {
+    let mut parser = Parser(json.as_bytes());
+    let mut state = TestState::new();
+    for (address, fields) in parser.object(|parser| parser.object(Parser::value))? {
+        let mut account = TestAccount::default();
+        for (name, value) in fields {
+            match (name.as_str(), value) {
+                ("balance", Value::String(value)) => account.balance = quantity(&value)?,
+                ("code", Value::String(value)) => account.code = from_hex(&value)?,
+                ("nonce", Value::String(value)) => {
+                    account.nonce = u64::try_from(quantity(&value)?)
+                        .map_err(|_| anyhow::anyhow!("Nonce {} is too large", value))?
+                }
+                ("storage", Value::Object(slots)) => {
+                    for (slot, value) in slots {
+                        let value = match value {
+                            Value::String(value) => quantity(&value)?,
+                            Value::Object(_) => anyhow::bail!("Storage value is not a string"),
+                        };
+                        account.storage.insert(h256(quantity(&slot)?), value);
+                    }
+                }
+                (name, _) => anyhow::bail!("Unexpected account field {:?}", name),
+            }
+        }
+        let address = H160::from_str(&address)
+            .map_err(|_| anyhow::anyhow!("Invalid address {:?}", address))?;
+        state.insert(address, account);
+    }
+    parser.skip_whitespace();
+    anyhow::ensure!(parser.0.is_empty(), "Trailing characters after the state");
+    Ok(state)
+}
+
+fn from_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let digits = strip_0x(hex)?;
+    anyhow::ensure!(digits.len() % 2 == 0, "Odd length hex {:?}", hex);
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| anyhow::anyhow!("Invalid hex {:?}", hex))
+        })
+        .collect()
+}
+
+// Leading zeros are allowed, and "0x" is zero
+fn quantity(hex: &str) -> anyhow::Result<U256> {
+    let digits = strip_0x(hex)?;
+    if digits.is_empty() {
+        return Ok(U256::zero());
+    }
+    U256::from_str_radix(digits, 16).map_err(|_| anyhow::anyhow!("Invalid quantity {:?}", hex))
+}
+
+fn strip_0x(hex: &str) -> anyhow::Result<&str> {
+    hex.strip_prefix("0x")
+        .ok_or_else(|| anyhow::anyhow!("Hex {:?} does not start with 0x", hex))
+}
+
+fn h256(value: U256) -> H256 {
+    let mut res = H256::zero();
+    value.to_big_endian(res.as_bytes_mut());
+    res
+}
+
+enum Value {
+    String(String),
+    Object(Vec<(String, Value)>),
+}
+
+struct Parser<'a>(&'a [u8]);
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let [b' ' | b'\t' | b'\n' | b'\r', rest @ ..] = self.0 {
+            self.0 = rest;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> anyhow::Result<()> {
+        self.skip_whitespace();
+        match self.0 {
+            [first, rest @ ..] if *first == byte => {
+                self.0 = rest;
+                Ok(())
+            }
+            _ => anyhow::bail!("Expected {:?} in state JSON", byte as char),
+        }
+    }
+
+    fn value(&mut self) -> anyhow::Result<Value> {
+        self.skip_whitespace();
+        Ok(match self.0.first() {
+            Some(b'{') => Value::Object(self.object(Parser::value)?),
+            _ => Value::String(self.string()?),
+        })
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        self.expect(b'"')?;
+        let len = self
+            .0
+            .iter()
+            .position(|byte| *byte == b'"')
+            .ok_or_else(|| anyhow::anyhow!("Unterminated string in state JSON"))?;
+        let (string, rest) = self.0.split_at(len);
+        // Hex strings need no escapes
+        anyhow::ensure!(!string.contains(&b'\\'), "Escapes are not supported");
+        self.0 = &rest[1..];
+        Ok(std::str::from_utf8(string)?.to_string())
+    }
+
+    fn object<T>(
+        &mut self,
+        mut value: impl FnMut(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Vec<(String, T)>> {
+        self.expect(b'{')?;
+        let mut res = Vec::new();
+        self.skip_whitespace();
+        if let [b'}', rest @ ..] = self.0 {
+            self.0 = rest;
+            return Ok(res);
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            res.push((key, value(self)?));
+            self.skip_whitespace();
+            match self.0 {
+                [b',', rest @ ..] => self.0 = rest,
+                [b'}', rest @ ..] => {
+                    self.0 = rest;
+                    return Ok(res);
+                }
+                _ => anyhow::bail!("Expected ',' or '}}' in state JSON"),
+            }
+        }
+    }
+}
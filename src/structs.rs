@@ -1,215 +1,59 @@
-use arrayvec::ArrayVec;
+use anyhow::Context;
 use ethereum_types::{H256, U256};
-use rlp::RlpStream;
-use smallvec::SmallVec;
 
-use crate::util::{keccak256, EMPTY_CODE_HASH};
+use crate::trie_core::slice_at;
+pub use crate::trie_core::{
+    get_internal_key, get_packed_key, hp_encode_nibble_list, marshal_nibble_list,
+    marshaled_nibble_list_len, nibble_list_to_key, unmarshal_nibble_list, DbValue, InternalNode,
+    NibbleList, PackedKey,
+};
+use crate::util::{to_hex, EMPTY_CODE_HASH};
 
-pub type DbValue = SmallVec<[u8; 64]>;
-pub type NibbleList = ArrayVec<u8, 64>;
-
-pub fn marshal_nibble_list(nibbles: &[u8]) -> ArrayVec<u8, 33> {
-    let mut res = ArrayVec::new();
-    res.push(nibbles.len() as u8);
-    for i in 0..nibbles.len() / 2 {
-        res.push((nibbles[i * 2] << 4) + nibbles[i * 2 + 1]);
-    }
-    if nibbles.len() % 2 == 1 {
-        res.push(nibbles.last().unwrap() << 4);
+impl From<H256> for PackedKey {
+    fn from(hash: H256) -> Self {
+        Self(hash.0)
     }
-    res
 }
 
-pub fn unmarshal_nibble_list(data: &[u8]) -> (NibbleList, usize) {
-    let nibbles_len = data[0] as usize;
-    let mut nibble_list = NibbleList::new();
-    for i in 1..nibbles_len / 2 + 1 {
-        nibble_list.push(data[i] >> 4);
-        nibble_list.push(data[i] & 0x0F);
-    }
-    if nibbles_len % 2 == 1 {
-        nibble_list.push(data[nibbles_len / 2 + 1] >> 4);
-    }
-    (nibble_list, (data[0] as usize + 1) / 2 + 1)
-}
+// A marshaled account starts with the nonce length, which is at most 8. A
+// first byte with this bit set is instead a flag carrying the format version
+// in its low bits, and the account follows. Version 0 is the current format
+// and is written without a flag.
+pub const ACCOUNT_VERSION_FLAG: u8 = 0x80;
 
-pub fn nibble_list_to_key(nibbles: &[u8]) -> ArrayVec<u8, 64> {
-    let mut res = ArrayVec::new();
-    let mut terminal_zeros = nibbles.iter().rev().take_while(|x| **x == 0).count();
-    for i in 0..nibbles.len() / 2 {
-        res.push((nibbles[i * 2] << 4) + nibbles[i * 2 + 1]);
-    }
-    if nibbles.len() % 2 == 1 {
-        res.push(nibbles.last().unwrap() << 4);
-        if *nibbles.last().unwrap() == 0 {
-            terminal_zeros -= 1;
-        }
-    }
-    // Should be `.div_ceil()`
-    for _ in 0..(terminal_zeros + 1) / 2 {
-        res.push(0);
-    }
-    res
-}
-
-pub fn hp_encode_nibble_list(nibble_list: &[u8], is_leaf: bool) -> ArrayVec<u8, 33> {
-    let mut res = ArrayVec::new();
-    if nibble_list.len() % 2 == 0 {
-        res.push((2 * is_leaf as u8) << 4);
-        for i in 0..nibble_list.len() / 2 {
-            res.push((nibble_list[i * 2] << 4) + nibble_list[i * 2 + 1]);
-        }
-    } else {
-        res.push(((2 * (is_leaf as u8) + 1) << 4) + nibble_list[0]);
-        for i in 0..nibble_list.len() / 2 {
-            res.push((nibble_list[i * 2 + 1] << 4) + nibble_list[i * 2 + 2]);
-        }
-    }
-    res
-}
-
-pub fn get_internal_key(bytes: impl AsRef<[u8]>) -> NibbleList {
-    let hash = keccak256(bytes);
-    let mut res = NibbleList::new();
-    for byte in hash.as_bytes() {
-        res.push(byte >> 4);
-        res.push(byte & 0x0F);
-    }
-    res
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Account {
+    pub nonce: u64,
+    pub balance: U256,
+    pub code_hash: H256,
 }
 
-fn hash_if_long(data: &[u8]) -> ArrayVec<u8, 32> {
-    if data.len() < 32 {
-        ArrayVec::try_from(data).unwrap()
-    } else {
-        keccak256(data).as_ref().try_into().unwrap()
+// An empty account, see `is_empty`
+impl Default for Account {
+    fn default() -> Self {
+        Self::eoa(0, U256::zero())
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum InternalNode {
-    Leaf {
-        rest_of_key: NibbleList,
-        value: SmallVec<[u8; 36]>,
-    },
-    Branch {
-        extension_nibbles: NibbleList,
-        subnodes: [ArrayVec<u8, 32>; 16],
-    },
-}
-
-impl InternalNode {
-    pub fn marshal(&self) -> DbValue {
-        let mut res = DbValue::new();
-        match self {
-            Self::Leaf { rest_of_key, value } => {
-                res.push(0);
-                res.extend_from_slice(&marshal_nibble_list(rest_of_key));
-                res.extend_from_slice(value);
-            }
-            Self::Branch {
-                extension_nibbles,
-                subnodes,
-            } => {
-                res.push(1);
-                res.extend_from_slice(&marshal_nibble_list(extension_nibbles));
-                let mut subnodes_mask: u16 = 0;
-                for (i, subnode) in subnodes.iter().enumerate() {
-                    if !subnode.is_empty() {
-                        subnodes_mask |= 1 << i
-                    }
-                }
-                res.extend_from_slice(&subnodes_mask.to_be_bytes());
-                for subnode in subnodes {
-                    if !subnode.is_empty() {
-                        res.push(subnode.len() as u8);
-                        res.extend_from_slice(subnode);
-                    }
-                }
-            }
+impl Account {
+    pub fn new(nonce: u64, balance: U256, code_hash: H256) -> Self {
+        Self {
+            nonce,
+            balance,
+            code_hash,
         }
-        res
     }
 
-    pub fn unmarshal(data: &[u8]) -> Self {
-        if data[0] == 0 {
-            let (rest_of_key, bytes_consumed) = unmarshal_nibble_list(&data[1..]);
-            Self::Leaf {
-                rest_of_key,
-                value: SmallVec::from_slice(&data[1 + bytes_consumed..]),
-            }
-        } else {
-            let (extension_nibbles, mut bytes_consumed) = unmarshal_nibble_list(&data[1..]);
-            bytes_consumed += 1;
-            let mut subnodes: [ArrayVec<u8, 32>; 16] = Default::default();
-            let subnode_mask =
-                u16::from_be_bytes(data[bytes_consumed..bytes_consumed + 2].try_into().unwrap());
-            bytes_consumed += 2;
-            for (i, subnode) in subnodes.iter_mut().enumerate() {
-                if subnode_mask & (1 << i) != 0 {
-                    let len = data[bytes_consumed] as usize;
-                    bytes_consumed += 1;
-                    *subnode =
-                        ArrayVec::try_from(&data[bytes_consumed..bytes_consumed + len]).unwrap();
-                    bytes_consumed += len;
-                }
-            }
-            Self::Branch {
-                extension_nibbles,
-                subnodes,
-            }
-        }
+    // An account without code
+    pub fn eoa(nonce: u64, balance: U256) -> Self {
+        Self::new(nonce, balance, *EMPTY_CODE_HASH)
     }
 
-    pub fn encode(&self) -> ArrayVec<u8, 32> {
-        match self {
-            Self::Leaf { rest_of_key, value } => {
-                let mut s = RlpStream::new_list(2);
-                s.append(&hp_encode_nibble_list(rest_of_key, true).as_slice())
-                    .append(&value.as_slice());
-                hash_if_long(&s.out())
-            }
-            Self::Branch {
-                extension_nibbles,
-                subnodes,
-            } => {
-                let mut s = RlpStream::new_list(17);
-                for subnode in subnodes {
-                    if subnode.is_empty() {
-                        s.append_empty_data()
-                    } else if subnode.len() < 32 {
-                        s.append_raw(subnode.as_slice(), 1)
-                    } else {
-                        s.append(&subnode.as_slice())
-                    };
-                }
-                s.append_empty_data();
-                let branch_node = hash_if_long(&s.out());
-                if !extension_nibbles.is_empty() {
-                    let mut s = RlpStream::new_list(2);
-                    if branch_node.len() < 32 {
-                        s.append_raw(branch_node.as_slice(), 1);
-                    } else {
-                        s.append(&hp_encode_nibble_list(extension_nibbles, false).as_slice())
-                            .append(&branch_node.as_slice());
-                    }
-                    hash_if_long(&s.out())
-                } else {
-                    branch_node
-                }
-            }
-        }
+    // EIP-161: an account with no nonce, balance or code is empty
+    pub fn is_empty(&self) -> bool {
+        self.nonce == 0 && self.balance.is_zero() && self.code_hash == *EMPTY_CODE_HASH
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Account {
-    pub nonce: u64,
-    pub balance: U256,
-    pub code_hash: H256,
-}
-
-impl Account {
     pub fn marshal(&self) -> DbValue {
         debug_assert_ne!(self.code_hash, H256::zero());
         let mut res = DbValue::new();
@@ -227,26 +71,43 @@ impl Account {
         res
     }
 
-    pub fn unmarshal(data: &[u8]) -> Self {
+    // Errors, rather than panicking, on a malformed record, e.g. one cut short
+    pub fn unmarshal(data: &[u8]) -> anyhow::Result<Self> {
+        Self::unmarshal_fields(data)
+            .with_context(|| format!("Malformed account record {}", record_prefix(data)))
+    }
+
+    fn unmarshal_fields(mut data: &[u8]) -> anyhow::Result<Self> {
+        if slice_at(data, 0, 1)?[0] & ACCOUNT_VERSION_FLAG != 0 {
+            let version = data[0] & !ACCOUNT_VERSION_FLAG;
+            anyhow::ensure!(version == 0, "Unsupported account version {}", version);
+            data = &data[1..];
+        }
         let mut nonce_data = [0; 8];
-        let nonce_len = data[0] as usize;
-        nonce_data[8 - nonce_len..].copy_from_slice(&data[1..1 + nonce_len]);
+        let nonce_len = slice_at(data, 0, 1)?[0] as usize;
+        anyhow::ensure!(nonce_len <= 8, "Nonce is too long");
+        nonce_data[8 - nonce_len..].copy_from_slice(slice_at(data, 1, nonce_len)?);
         let mut bytes_consumed = 1 + nonce_len;
-        let balance_len = data[bytes_consumed] as usize;
+        let balance_len = slice_at(data, bytes_consumed, 1)?[0] as usize;
+        anyhow::ensure!(balance_len <= 32, "Balance is too long");
         let mut balance_data = [0; 32];
-        balance_data[32 - balance_len..]
-            .copy_from_slice(&data[bytes_consumed + 1..bytes_consumed + 1 + balance_len]);
+        balance_data[32 - balance_len..].copy_from_slice(slice_at(
+            data,
+            bytes_consumed + 1,
+            balance_len,
+        )?);
         bytes_consumed += 1 + balance_len;
-        let code_hash = if data.len() == bytes_consumed {
-            *EMPTY_CODE_HASH
-        } else {
-            H256::from_slice(&data[bytes_consumed..bytes_consumed + 32])
+        let code_hash = match data.len() - bytes_consumed {
+            0 => *EMPTY_CODE_HASH,
+            32 => H256::from_slice(&data[bytes_consumed..]),
+            _ => anyhow::bail!("Code hash has the wrong length"),
         };
-        Self {
+        anyhow::ensure!(!code_hash.is_zero(), "Code hash is zero");
+        Ok(Self {
             nonce: u64::from_be_bytes(nonce_data),
             balance: U256::from_big_endian(&balance_data),
             code_hash,
-        }
+        })
     }
 }
 
@@ -256,61 +117,145 @@ pub fn marshal_storage(value: U256) -> DbValue {
     DbValue::from_slice(&buf[(value.leading_zeros() / 8) as usize..])
 }
 
-pub fn unmarshal_storage(data: &[u8]) -> U256 {
+pub fn unmarshal_storage(data: &[u8]) -> anyhow::Result<U256> {
+    anyhow::ensure!(
+        data.len() <= 32,
+        "Storage value is too long, record {}",
+        record_prefix(data)
+    );
     let mut buf = [0; 32];
     buf[32 - data.len()..].copy_from_slice(data);
-    U256::from_big_endian(&buf)
+    Ok(U256::from_big_endian(&buf))
+}
+
+// The start of a malformed record, for error messages
+fn record_prefix(data: &[u8]) -> String {
+    let shown = data.len().min(8);
+    let mut res = format!("0x{}", to_hex(&data[..shown]));
+    if shown < data.len() {
+        res.push_str("..");
+    }
+    res
+}
+
+// Fuzzing entry point. The first byte of `bytes` selects a format and the
+// rest is decoded as it. Malformed input is fine, but anything that decodes
+// must survive being marshaled and decoded again unchanged.
+pub fn roundtrip_check(bytes: &[u8]) {
+    let (format, data) = match bytes.split_first() {
+        None => return,
+        Some(x) => x,
+    };
+    match format {
+        0 => {
+            if let Ok(account) = Account::unmarshal(data) {
+                assert_eq!(Account::unmarshal(&account.marshal()).unwrap(), account);
+            }
+        }
+        1 => {
+            if let Ok(node) = InternalNode::unmarshal(data) {
+                assert_eq!(InternalNode::unmarshal(&node.marshal()).unwrap(), node);
+                assert_eq!(node.marshal().len(), node.marshaled_len());
+            }
+        }
+        2 => {
+            if let Ok((nibble_list, _)) = unmarshal_nibble_list(data) {
+                let marshaled = marshal_nibble_list(&nibble_list);
+                assert_eq!(unmarshal_nibble_list(&marshaled).unwrap().0, nibble_list);
+                assert_eq!(marshaled.len(), marshaled_nibble_list_len(&nibble_list));
+            }
+        }
+        3 => {
+            if let Ok(value) = unmarshal_storage(data) {
+                assert_eq!(unmarshal_storage(&marshal_storage(value)).unwrap(), value);
+            }
+        }
+        _ => {}
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    static NIBBLE_LIST_TESTS: &[&[u8]] = &[&[], &[1, 2, 3], &[1, 2, 3, 4]];
+    use crate::util::keccak256;
 
     #[test]
-    fn test_nibble_list() {
-        for test in NIBBLE_LIST_TESTS {
-            let nibble_list = ArrayVec::try_from(*test).unwrap();
-            assert_eq!(
-                unmarshal_nibble_list(&marshal_nibble_list(&nibble_list)).0,
-                nibble_list
-            )
-        }
+    fn test_account_version() {
+        let account = Account {
+            nonce: 0x1234,
+            balance: U256::from(1) << 100,
+            code_hash: keccak256(b"code"),
+        };
+        let data = account.marshal();
+        assert!(data[0] & ACCOUNT_VERSION_FLAG == 0);
+        let mut flagged = vec![ACCOUNT_VERSION_FLAG];
+        flagged.extend_from_slice(&data);
+        assert_eq!(Account::unmarshal(&data).unwrap(), account);
+        assert_eq!(Account::unmarshal(&flagged).unwrap(), account);
+        assert!(Account::unmarshal(&[ACCOUNT_VERSION_FLAG | 1, 0, 0]).is_err());
     }
 
-    static HP_ENCODE_TESTS: &[(&[u8], bool, &[u8])] = &[
-        (&[1, 2, 3], true, &[49, 35]),
-        (&[1, 2, 3], false, &[17, 35]),
-        (&[1, 2, 3, 4], true, &[32, 18, 52]),
-        (&[1, 2, 3, 4], false, &[0, 18, 52]),
-    ];
-
     #[test]
-    fn test_hp_encode() {
-        for test in HP_ENCODE_TESTS {
-            assert_eq!(&hp_encode_nibble_list(test.0, test.1), test.2);
+    fn test_truncated_records() {
+        let account = Account::new(1, U256::from(0x1234), keccak256(b"code"));
+        let data = account.marshal();
+        // Cutting off the whole code hash leaves an account without code
+        let without_code = data.len() - 32;
+        for len in (0..data.len()).filter(|len| *len != without_code) {
+            let err = Account::unmarshal(&data[..len]).unwrap_err();
+            assert!(err.to_string().starts_with("Malformed account record 0x"));
         }
+        let err = Account::unmarshal(&data[..without_code + 31]).unwrap_err();
+        // The nonce, balance and start of the code hash
+        let message = err.to_string();
+        assert!(message.starts_with("Malformed account record 0x0101021234"));
+        assert!(message.ends_with(".."));
+        assert_eq!(format!("{:#}", err).matches("wrong length").count(), 1);
+        assert!(Account::unmarshal(&[]).is_err());
+
+        assert!(unmarshal_storage(&[]).unwrap().is_zero());
+        let err = unmarshal_storage(&[0xff; 33]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Storage value is too long, record 0xffffffffffffffff.."
+        );
     }
 
     #[test]
-    fn test_internal_node() {
-        let mut subnodes: [ArrayVec<u8, 32>; 16] = Default::default();
-        subnodes[0] = [1, 1, 1].as_slice().try_into().unwrap();
-        let internal_node = InternalNode::Branch {
-            extension_nibbles: [1, 2, 3].as_slice().try_into().unwrap(),
-            subnodes,
-        };
+    fn test_account_constructors() {
+        assert!(Account::default().is_empty());
         assert_eq!(
-            internal_node,
-            InternalNode::unmarshal(&internal_node.marshal())
+            Account::eoa(1, U256::one()),
+            Account::new(1, U256::one(), *EMPTY_CODE_HASH)
         );
+        let accounts: std::collections::HashSet<_> =
+            [Account::default(), Account::eoa(0, U256::zero())].into();
+        assert_eq!(accounts.len(), 1);
+    }
+
+    #[test]
+    fn test_roundtrip_check() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..100_000 {
+            let len = rng.gen_range(0..80);
+            let mut bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            if let Some(format) = bytes.first_mut() {
+                *format %= 4;
+            }
+            // Small lengths make valid encodings likely
+            if let Some(byte) = bytes.get_mut(1) {
+                *byte %= 40;
+            }
+            roundtrip_check(&bytes);
+        }
     }
 
     #[test]
     fn test_marshal_storage() {
         for i in 1..2_000_000 {
             let value = U256::from(i);
-            assert_eq!(value, unmarshal_storage(&marshal_storage(value)));
+            assert_eq!(value, unmarshal_storage(&marshal_storage(value)).unwrap());
         }
     }
 }
@@ -0,0 +1,26 @@
+// Fixtures for building tries of a chosen shape, e.g. accounts whose keys
+// share a prefix to force an extension node.
+
+use ethereum_types::H160;
+
+use crate::structs::get_packed_key;
+
+// Addresses whose hash, their key in the state trie, starts with `nibbles`.
+// They are found by trying the addresses 0, 1, 2, ... in turn, which takes
+// 16^n hashes per address for a prefix of n nibbles, so prefixes of more
+// than about 7 nibbles are impractical.
+pub fn addresses_with_internal_prefix(nibbles: &[u8]) -> impl Iterator<Item = H160> + '_ {
+    assert!(
+        nibbles.len() <= 64 && nibbles.iter().all(|nibble| *nibble < 16),
+        "Not a key prefix: {:?}",
+        nibbles
+    );
+    (0..)
+        .map(H160::from_low_u64_be)
+        .filter(move |address| get_packed_key(address).starts_with(nibbles))
+}
+
+// The first of `addresses_with_internal_prefix`
+pub fn address_with_internal_prefix(nibbles: &[u8]) -> H160 {
+    addresses_with_internal_prefix(nibbles).next().unwrap()
+}
@@ -0,0 +1,619 @@
+// The trie node format and nibble handling, with no dependency on `std` or
+// the database so they can be embedded on their own.
+
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+use rlp::RlpStream;
+use sha3::{Digest, Keccak256};
+use smallvec::SmallVec;
+
+// The hash used for trie nodes and keys. Ethereum uses `Keccak`, other
+// implementations allow for environments with their own hashing.
+pub trait Hasher {
+    fn hash_into(data: &[u8], out: &mut [u8; 32]);
+}
+
+pub struct Keccak;
+
+impl Hasher for Keccak {
+    fn hash_into(data: &[u8], out: &mut [u8; 32]) {
+        out.copy_from_slice(&Keccak256::digest(data));
+    }
+}
+
+pub type DbValue = SmallVec<[u8; 64]>;
+pub type NibbleList = ArrayVec<u8, 64>;
+
+// The length is stored in a single byte, and keys are at most 64 nibbles, so
+// longer lists cannot be marshaled
+pub fn marshal_nibble_list(nibbles: &[u8]) -> ArrayVec<u8, 33> {
+    debug_assert!(nibbles.len() <= 64, "Nibble list is too long");
+    let mut res = ArrayVec::new();
+    res.push(nibbles.len() as u8);
+    for i in 0..nibbles.len() / 2 {
+        res.push((nibbles[i * 2] << 4) + nibbles[i * 2 + 1]);
+    }
+    if nibbles.len() % 2 == 1 {
+        res.push(nibbles.last().unwrap() << 4);
+    }
+    res
+}
+
+pub fn marshaled_nibble_list_len(nibbles: &[u8]) -> usize {
+    1 + nibbles.len() / 2 + nibbles.len() % 2
+}
+
+// Returns `len` bytes of `data` from `start`, or an error if it is too short
+pub(crate) fn slice_at(data: &[u8], start: usize, len: usize) -> anyhow::Result<&[u8]> {
+    data.get(start..start + len)
+        .ok_or_else(|| anyhow::anyhow!("Marshaled data is truncated"))
+}
+
+pub fn unmarshal_nibble_list(data: &[u8]) -> anyhow::Result<(NibbleList, usize)> {
+    let nibbles_len = *slice_at(data, 0, 1)?.first().unwrap() as usize;
+    anyhow::ensure!(nibbles_len <= 64, "Nibble list is too long");
+    let bytes = slice_at(data, 1, nibbles_len / 2 + nibbles_len % 2)?;
+    let mut nibble_list = NibbleList::new();
+    for byte in &bytes[..nibbles_len / 2] {
+        nibble_list.push(byte >> 4);
+        nibble_list.push(byte & 0x0F);
+    }
+    if nibbles_len % 2 == 1 {
+        nibble_list.push(bytes[nibbles_len / 2] >> 4);
+    }
+    Ok((nibble_list, bytes.len() + 1))
+}
+
+pub fn nibble_list_to_key(nibbles: &[u8]) -> ArrayVec<u8, 64> {
+    let mut res = ArrayVec::new();
+    let mut terminal_zeros = nibbles.iter().rev().take_while(|x| **x == 0).count();
+    for i in 0..nibbles.len() / 2 {
+        res.push((nibbles[i * 2] << 4) + nibbles[i * 2 + 1]);
+    }
+    if nibbles.len() % 2 == 1 {
+        res.push(nibbles.last().unwrap() << 4);
+        if *nibbles.last().unwrap() == 0 {
+            terminal_zeros -= 1;
+        }
+    }
+    // Should be `.div_ceil()`
+    for _ in 0..(terminal_zeros + 1) / 2 {
+        res.push(0);
+    }
+    res
+}
+
+pub fn hp_encode_nibble_list(nibble_list: &[u8], is_leaf: bool) -> ArrayVec<u8, 33> {
+    let mut res = ArrayVec::new();
+    if nibble_list.len() % 2 == 0 {
+        res.push((2 * is_leaf as u8) << 4);
+        for i in 0..nibble_list.len() / 2 {
+            res.push((nibble_list[i * 2] << 4) + nibble_list[i * 2 + 1]);
+        }
+    } else {
+        res.push(((2 * (is_leaf as u8) + 1) << 4) + nibble_list[0]);
+        for i in 0..nibble_list.len() / 2 {
+            res.push((nibble_list[i * 2 + 1] << 4) + nibble_list[i * 2 + 2]);
+        }
+    }
+    res
+}
+
+// The inverse of `hp_encode_nibble_list`, also returning whether the
+// nibbles are the key of a leaf
+pub fn hp_decode_nibble_list(data: &[u8]) -> anyhow::Result<(NibbleList, bool)> {
+    let (first, rest) = data
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("Encoded nibble list is empty"))?;
+    anyhow::ensure!(first >> 4 < 4, "Invalid nibble list flag {}", first >> 4);
+    let is_leaf = first & 0x20 != 0;
+    let mut res = NibbleList::new();
+    if first & 0x10 != 0 {
+        res.push(first & 0x0F);
+    } else {
+        anyhow::ensure!(first & 0x0F == 0, "Invalid nibble list padding");
+    }
+    anyhow::ensure!(res.len() + rest.len() * 2 <= 64, "Nibble list is too long");
+    for byte in rest {
+        res.push(byte >> 4);
+        res.push(byte & 0x0F);
+    }
+    Ok((res, is_leaf))
+}
+
+// A full length (64 nibble) trie key packed two nibbles per byte. Used for
+// the walker's dirty lists, where the keys are always complete hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PackedKey(pub [u8; 32]);
+
+impl PackedKey {
+    pub fn from_nibbles(nibbles: &[u8]) -> Self {
+        assert_eq!(nibbles.len(), 64);
+        let mut res = [0; 32];
+        for (i, byte) in res.iter_mut().enumerate() {
+            *byte = (nibbles[i * 2] << 4) + nibbles[i * 2 + 1];
+        }
+        Self(res)
+    }
+
+    pub fn nibble(&self, index: usize) -> u8 {
+        let byte = self.0[index / 2];
+        if index % 2 == 1 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        }
+    }
+
+    pub fn matches_at(&self, offset: usize, nibbles: &[u8]) -> bool {
+        offset + nibbles.len() <= 64
+            && nibbles
+                .iter()
+                .enumerate()
+                .all(|(i, nibble)| self.nibble(offset + i) == *nibble)
+    }
+
+    pub fn starts_with(&self, nibbles: &[u8]) -> bool {
+        self.matches_at(0, nibbles)
+    }
+
+    pub fn unpack(&self) -> NibbleList {
+        let mut res = NibbleList::new();
+        for byte in self.0 {
+            res.push(byte >> 4);
+            res.push(byte & 0x0F);
+        }
+        res
+    }
+}
+
+pub fn get_packed_key(bytes: impl AsRef<[u8]>) -> PackedKey {
+    let mut res = [0; 32];
+    Keccak::hash_into(bytes.as_ref(), &mut res);
+    PackedKey(res)
+}
+
+pub fn get_internal_key(bytes: impl AsRef<[u8]>) -> NibbleList {
+    let mut res = NibbleList::new();
+    for byte in get_packed_key(bytes).0 {
+        res.push(byte >> 4);
+        res.push(byte & 0x0F);
+    }
+    res
+}
+
+fn hash_if_long<H: Hasher>(data: &[u8]) -> ArrayVec<u8, 32> {
+    if data.len() < 32 {
+        ArrayVec::try_from(data).unwrap()
+    } else {
+        let mut res = [0; 32];
+        H::hash_into(data, &mut res);
+        ArrayVec::from(res)
+    }
+}
+
+// A marshaled node starts with its type, 0 for a leaf and 1 for a branch. A
+// type byte with this bit set is followed by the epoch in which the node was
+// last written, see `InternalNode::marshal_with_epoch`. The epoch is database
+// metadata only and does not affect `InternalNode::encode`.
+pub const NODE_EPOCH_FLAG: u8 = 0x80;
+
+// The epoch recorded in a marshaled node, `None` if it has none
+pub fn node_epoch(data: &[u8]) -> Option<u8> {
+    match data {
+        [node_type, epoch, ..] if node_type & NODE_EPOCH_FLAG != 0 => Some(*epoch),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InternalNode {
+    Leaf {
+        rest_of_key: NibbleList,
+        value: SmallVec<[u8; 36]>,
+    },
+    Branch {
+        extension_nibbles: NibbleList,
+        subnodes: [ArrayVec<u8, 32>; 16],
+    },
+}
+
+impl InternalNode {
+    pub fn marshaled_len(&self) -> usize {
+        match self {
+            Self::Leaf { rest_of_key, value } => {
+                1 + marshaled_nibble_list_len(rest_of_key) + value.len()
+            }
+            Self::Branch {
+                extension_nibbles,
+                subnodes,
+            } => {
+                3 + marshaled_nibble_list_len(extension_nibbles)
+                    + subnodes
+                        .iter()
+                        .filter(|subnode| !subnode.is_empty())
+                        .map(|subnode| 1 + subnode.len())
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    pub fn marshal(&self) -> DbValue {
+        let mut res = DbValue::with_capacity(self.marshaled_len());
+        match self {
+            Self::Leaf { rest_of_key, value } => {
+                res.push(0);
+                res.extend_from_slice(&marshal_nibble_list(rest_of_key));
+                res.extend_from_slice(value);
+            }
+            Self::Branch {
+                extension_nibbles,
+                subnodes,
+            } => {
+                res.push(1);
+                res.extend_from_slice(&marshal_nibble_list(extension_nibbles));
+                let mut subnodes_mask: u16 = 0;
+                for (i, subnode) in subnodes.iter().enumerate() {
+                    if !subnode.is_empty() {
+                        subnodes_mask |= 1 << i
+                    }
+                }
+                res.extend_from_slice(&subnodes_mask.to_be_bytes());
+                for subnode in subnodes {
+                    if !subnode.is_empty() {
+                        res.push(subnode.len() as u8);
+                        res.extend_from_slice(subnode);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    // As `marshal`, recording the epoch in which the node was written, see
+    // `node_epoch`. The epoch is not part of the node and `unmarshal` drops it.
+    pub fn marshal_with_epoch(&self, epoch: u8) -> DbValue {
+        let mut res = self.marshal();
+        res[0] |= NODE_EPOCH_FLAG;
+        res.insert(1, epoch);
+        res
+    }
+
+    pub fn unmarshal(data: &[u8]) -> anyhow::Result<Self> {
+        let node_type = slice_at(data, 0, 1)?[0];
+        if node_type & NODE_EPOCH_FLAG != 0 {
+            slice_at(data, 1, 1)?;
+            return Self::unmarshal_node(node_type & !NODE_EPOCH_FLAG, &data[2..]);
+        }
+        Self::unmarshal_node(node_type, &data[1..])
+    }
+
+    // Decodes the node from the data following its type byte
+    fn unmarshal_node(node_type: u8, data: &[u8]) -> anyhow::Result<Self> {
+        Ok(match node_type {
+            0 => {
+                let (rest_of_key, bytes_consumed) = unmarshal_nibble_list(data)?;
+                Self::Leaf {
+                    rest_of_key,
+                    value: SmallVec::from_slice(&data[bytes_consumed..]),
+                }
+            }
+            1 => {
+                let (extension_nibbles, mut bytes_consumed) = unmarshal_nibble_list(data)?;
+                let mut subnodes: [ArrayVec<u8, 32>; 16] = Default::default();
+                let subnode_mask =
+                    u16::from_be_bytes(slice_at(data, bytes_consumed, 2)?.try_into().unwrap());
+                bytes_consumed += 2;
+                for (i, subnode) in subnodes.iter_mut().enumerate() {
+                    if subnode_mask & (1 << i) != 0 {
+                        let len = slice_at(data, bytes_consumed, 1)?[0] as usize;
+                        bytes_consumed += 1;
+                        anyhow::ensure!(len != 0, "Empty subnode in branch mask");
+                        anyhow::ensure!(len <= 32, "Subnode is too long");
+                        *subnode =
+                            ArrayVec::try_from(slice_at(data, bytes_consumed, len)?).unwrap();
+                        bytes_consumed += len;
+                    }
+                }
+                anyhow::ensure!(
+                    bytes_consumed == data.len(),
+                    "Trailing data after branch node"
+                );
+                Self::Branch {
+                    extension_nibbles,
+                    subnodes,
+                }
+            }
+            node_type => anyhow::bail!("Unknown node type {}", node_type),
+        })
+    }
+
+    pub fn encode(&self) -> ArrayVec<u8, 32> {
+        self.encode_with::<Keccak>()
+    }
+
+    // As `encode`, hashing with `H`
+    pub fn encode_with<H: Hasher>(&self) -> ArrayVec<u8, 32> {
+        match self {
+            Self::Leaf { rest_of_key, value } => {
+                hash_if_long::<H>(leaf_rlp(rest_of_key, value).as_raw())
+            }
+            Self::Branch {
+                extension_nibbles,
+                subnodes,
+            } => {
+                let branch_node = hash_if_long::<H>(branch_rlp(subnodes).as_raw());
+                if !extension_nibbles.is_empty() {
+                    hash_if_long::<H>(extension_rlp(extension_nibbles, &branch_node).as_raw())
+                } else {
+                    branch_node
+                }
+            }
+        }
+    }
+
+    // The RLP of the trie nodes this node is made of, as they appear in
+    // proofs. A branch with extension nibbles is an extension node followed
+    // by the branch node it references.
+    pub fn rlp(&self) -> ArrayVec<Vec<u8>, 2> {
+        self.rlp_with::<Keccak>()
+    }
+
+    // As `rlp`, hashing with `H`
+    pub fn rlp_with<H: Hasher>(&self) -> ArrayVec<Vec<u8>, 2> {
+        let mut res = ArrayVec::new();
+        match self {
+            Self::Leaf { rest_of_key, value } => {
+                res.push(leaf_rlp(rest_of_key, value).as_raw().to_vec());
+            }
+            Self::Branch {
+                extension_nibbles,
+                subnodes,
+            } => {
+                let branch_node = branch_rlp(subnodes);
+                if !extension_nibbles.is_empty() {
+                    let branch_ref = hash_if_long::<H>(branch_node.as_raw());
+                    res.push(
+                        extension_rlp(extension_nibbles, &branch_ref)
+                            .as_raw()
+                            .to_vec(),
+                    );
+                }
+                res.push(branch_node.as_raw().to_vec());
+            }
+        }
+        res
+    }
+}
+
+fn leaf_rlp(rest_of_key: &[u8], value: &[u8]) -> RlpStream {
+    let mut s = RlpStream::new_list(2);
+    s.append(&hp_encode_nibble_list(rest_of_key, true).as_slice())
+        .append(&value);
+    s
+}
+
+fn branch_rlp(subnodes: &[ArrayVec<u8, 32>; 16]) -> RlpStream {
+    let mut s = RlpStream::new_list(17);
+    for subnode in subnodes {
+        append_ref(&mut s, subnode);
+    }
+    s.append_empty_data();
+    s
+}
+
+fn extension_rlp(extension_nibbles: &[u8], branch_ref: &[u8]) -> RlpStream {
+    let mut s = RlpStream::new_list(2);
+    s.append(&hp_encode_nibble_list(extension_nibbles, false).as_slice());
+    append_ref(&mut s, branch_ref);
+    s
+}
+
+// The reference to an extension node over the node referenced by `node_ref`
+#[cfg(feature = "std")]
+pub(crate) fn extension_ref(extension_nibbles: &[u8], node_ref: &[u8]) -> ArrayVec<u8, 32> {
+    hash_if_long::<Keccak>(extension_rlp(extension_nibbles, node_ref).as_raw())
+}
+
+// Appends a reference as returned by `InternalNode::encode`, short nodes are
+// embedded and the rest are referenced by hash
+fn append_ref(s: &mut RlpStream, node_ref: &[u8]) {
+    if node_ref.is_empty() {
+        s.append_empty_data();
+    } else if node_ref.len() < 32 {
+        s.append_raw(node_ref, 1);
+    } else {
+        s.append(&node_ref);
+    }
+}
+
+pub fn common_prefix(xs: &[u8], ys: &[u8]) -> usize {
+    // Compare 8 bytes at a time, the first differing byte is found from the
+    // lowest set bit of the XOR of the little endian words.
+    let len = xs.len().min(ys.len());
+    let mut i = 0;
+    while i + 8 <= len {
+        let x = u64::from_le_bytes(xs[i..i + 8].try_into().unwrap());
+        let y = u64::from_le_bytes(ys[i..i + 8].try_into().unwrap());
+        let diff = x ^ y;
+        if diff != 0 {
+            return i + (diff.trailing_zeros() / 8) as usize;
+        }
+        i += 8;
+    }
+    i + xs[i..len]
+        .iter()
+        .zip(&ys[i..len])
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    static NIBBLE_LIST_TESTS: &[&[u8]] = &[&[], &[1, 2, 3], &[1, 2, 3, 4]];
+
+    #[test]
+    fn test_nibble_list() {
+        for test in NIBBLE_LIST_TESTS {
+            let nibble_list = ArrayVec::try_from(*test).unwrap();
+            assert_eq!(
+                unmarshal_nibble_list(&marshal_nibble_list(&nibble_list))
+                    .unwrap()
+                    .0,
+                nibble_list
+            )
+        }
+    }
+
+    static HP_ENCODE_TESTS: &[(&[u8], bool, &[u8])] = &[
+        (&[1, 2, 3], true, &[49, 35]),
+        (&[1, 2, 3], false, &[17, 35]),
+        (&[1, 2, 3, 4], true, &[32, 18, 52]),
+        (&[1, 2, 3, 4], false, &[0, 18, 52]),
+    ];
+
+    #[test]
+    fn test_nibble_list_limit() {
+        let nibbles = [0x0F; 65];
+        let marshaled = marshal_nibble_list(&nibbles[..64]);
+        assert_eq!((marshaled.len(), marshaled[0]), (33, 64));
+        assert_eq!(
+            unmarshal_nibble_list(&marshaled).unwrap(),
+            (ArrayVec::try_from(&nibbles[..64]).unwrap(), 33)
+        );
+        let mut too_long = marshaled.to_vec();
+        too_long[0] = 65;
+        too_long.push(0xF0);
+        assert!(unmarshal_nibble_list(&too_long).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_nibble_list_too_long() {
+        marshal_nibble_list(&[0x0F; 65]);
+    }
+
+    #[test]
+    fn test_hp_encode() {
+        for test in HP_ENCODE_TESTS {
+            assert_eq!(&hp_encode_nibble_list(test.0, test.1), test.2);
+            let (nibble_list, is_leaf) = hp_decode_nibble_list(test.2).unwrap();
+            assert_eq!((&nibble_list[..], is_leaf), (test.0, test.1));
+        }
+    }
+
+    #[test]
+    fn test_packed_key() {
+        let keys: Vec<NibbleList> = (0u8..50).map(|i| get_internal_key([i])).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let packed = PackedKey::from_nibbles(key);
+            assert_eq!(packed.unpack(), *key);
+            assert_eq!(packed, get_packed_key([i as u8]));
+            for i in 0..64 {
+                assert_eq!(packed.nibble(i), key[i]);
+                assert!(packed.starts_with(&key[..i]));
+                assert!(packed.matches_at(i, &key[i..]));
+            }
+        }
+        for x in &keys {
+            for y in &keys {
+                assert_eq!(
+                    PackedKey::from_nibbles(x).cmp(&PackedKey::from_nibbles(y)),
+                    x.cmp(y)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_internal_node() {
+        let mut subnodes: [ArrayVec<u8, 32>; 16] = Default::default();
+        subnodes[0] = [1, 1, 1].as_slice().try_into().unwrap();
+        let internal_node = InternalNode::Branch {
+            extension_nibbles: [1, 2, 3].as_slice().try_into().unwrap(),
+            subnodes,
+        };
+        assert_eq!(
+            internal_node,
+            InternalNode::unmarshal(&internal_node.marshal()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_marshaled_len() {
+        let mut subnodes: [ArrayVec<u8, 32>; 16] = Default::default();
+        subnodes[0] = [1, 1, 1].as_slice().try_into().unwrap();
+        subnodes[7] = [2; 32].as_slice().try_into().unwrap();
+        let nodes = [
+            InternalNode::Branch {
+                extension_nibbles: [1, 2, 3].as_slice().try_into().unwrap(),
+                subnodes: subnodes.clone(),
+            },
+            InternalNode::Branch {
+                extension_nibbles: NibbleList::new(),
+                subnodes,
+            },
+            InternalNode::Leaf {
+                rest_of_key: [1, 2, 3, 4].as_slice().try_into().unwrap(),
+                value: SmallVec::from_slice(&[5; 36]),
+            },
+            InternalNode::Leaf {
+                rest_of_key: [1; 63].as_slice().try_into().unwrap(),
+                value: SmallVec::from_slice(&[1]),
+            },
+        ];
+        for node in nodes {
+            assert_eq!(node.marshal().len(), node.marshaled_len());
+        }
+    }
+
+    #[test]
+    fn test_node_epoch() {
+        let mut subnodes: [ArrayVec<u8, 32>; 16] = Default::default();
+        subnodes[3] = [3; 32].as_slice().try_into().unwrap();
+        subnodes[9] = [1, 2].as_slice().try_into().unwrap();
+        let nodes = [
+            InternalNode::Branch {
+                extension_nibbles: [4, 5].as_slice().try_into().unwrap(),
+                subnodes,
+            },
+            InternalNode::Leaf {
+                rest_of_key: [1, 2, 3].as_slice().try_into().unwrap(),
+                value: SmallVec::from_slice(&[6; 40]),
+            },
+        ];
+        for node in nodes {
+            assert_eq!(node_epoch(&node.marshal()), None);
+            let data = node.marshal_with_epoch(7);
+            assert_eq!(node_epoch(&data), Some(7));
+            assert_eq!(InternalNode::unmarshal(&data).unwrap(), node);
+            assert!(InternalNode::unmarshal(&data[..1]).is_err());
+        }
+    }
+
+    fn naive_common_prefix(xs: &[u8], ys: &[u8]) -> usize {
+        xs.iter().zip(ys).take_while(|(x, y)| x == y).count()
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let xs: Vec<u8> = (0..64).map(|i| (i * 7 % 16) as u8).collect();
+        for len in 0..=64 {
+            for diff_at in 0..=len {
+                let mut ys = xs[..len].to_vec();
+                if diff_at < len {
+                    ys[diff_at] ^= 0x0F;
+                }
+                for x_len in [diff_at, len, 64] {
+                    assert_eq!(
+                        common_prefix(&xs[..x_len], &ys),
+                        naive_common_prefix(&xs[..x_len], &ys)
+                    );
+                }
+            }
+        }
+    }
+}
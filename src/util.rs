@@ -1,13 +1,55 @@
 use ethereum_types::H256;
 use once_cell::sync::Lazy;
-use sha3::{Digest, Keccak256};
+
+pub use crate::trie_core::common_prefix;
+use crate::trie_core::{Hasher, Keccak};
 
 pub static EMPTY_CODE_HASH: Lazy<H256> = Lazy::new(|| keccak256(&[]));
 
 pub fn keccak256(data: impl AsRef<[u8]>) -> H256 {
-    H256::from_slice(&Keccak256::digest(data.as_ref()))
+    let mut res = H256::zero();
+    keccak256_into(data, &mut res.0);
+    res
+}
+
+// The code hash `MutableTransaction::store_code` would return, without
+// storing the code
+pub fn code_hash(code: &[u8]) -> H256 {
+    if code.is_empty() {
+        *EMPTY_CODE_HASH
+    } else {
+        keccak256(code)
+    }
+}
+
+pub fn keccak256_into(data: impl AsRef<[u8]>, out: &mut [u8; 32]) {
+    Keccak::hash_into(data.as_ref(), out);
 }
 
-pub fn common_prefix(xs: &[u8], ys: &[u8]) -> usize {
-    xs.iter().zip(ys).take_while(|(x, y)| x == y).count()
+// Lowercase hex without a "0x" prefix
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keccak256_into() {
+        let mut out = [0xFF; 32];
+        keccak256_into(b"abc", &mut out);
+        assert_eq!(out, keccak256(b"abc").0);
+        assert_eq!(
+            format!("{:x}", keccak256([])),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+    }
+
+    #[test]
+    fn test_code_hash() {
+        assert_eq!(code_hash(&[]), *EMPTY_CODE_HASH);
+        assert_eq!(code_hash(&[]), keccak256([]));
+        assert_eq!(code_hash(&[0x60, 0x00]), keccak256([0x60, 0x00]));
+    }
 }
@@ -2,53 +2,396 @@ use arrayvec::ArrayVec;
 use ethereum_types::H256;
 use once_cell::sync::Lazy;
 use smallvec::SmallVec;
+use std::cmp::Reverse;
 use std::str::FromStr;
 use std::vec::Vec;
 
-use crate::backend::BackendTransaction;
-use crate::structs::{nibble_list_to_key, InternalNode, NibbleList};
+use crate::backend::{BackendTransaction, StagedWrites};
+use crate::error::DbError;
+use crate::keys::{storage_trie_prefix, trie_key, ACCOUNT_TRIE_PREFIX};
+use crate::structs::{InternalNode, NibbleList, PackedKey};
 use crate::util::{common_prefix, keccak256};
 
 pub static EMPTY_TRIE_ROOT: Lazy<H256> = Lazy::new(|| {
     H256::from_str("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421").unwrap()
 });
 
+pub type DirtyList = Vec<(PackedKey, Option<SmallVec<[u8; 36]>>)>;
+
+// Sorts a dirty list into the order expected by `Walker::new`. If a key
+// appears more than once, the entry that was pushed last wins.
+pub fn sort_dirty_list(dirty_list: &mut DirtyList) {
+    dirty_list.reverse();
+    dirty_list.sort_by_key(|x| Reverse(x.0));
+    dirty_list.dedup_by_key(|x| x.0);
+}
+
+// Keys are popped from the end, so they must be strictly descending
+fn check_sorted(dirty_list: &DirtyList) {
+    debug_assert!(
+        dirty_list.windows(2).all(|pair| pair[0].0 > pair[1].0),
+        "dirty_list is not sorted in strictly descending order"
+    );
+}
+
+// The root of a trie whose root node has the reference `root_ref`, an empty
+// reference meaning an empty trie
+fn root_hash(root_ref: &[u8]) -> H256 {
+    if root_ref.is_empty() {
+        *EMPTY_TRIE_ROOT
+    } else if root_ref.len() < 32 {
+        keccak256(root_ref)
+    } else {
+        H256::from_slice(root_ref)
+    }
+}
+
+// Reads the value under `key` in the trie with prefix `trie_prefix` as of
+// `root`. Nodes are stored by path and overwritten when the trie changes, so
+// every node on the way is checked against the reference its parent holds,
+// and `DbError::RootUnavailable` is returned if the nodes of `root` are gone.
+pub fn get_at_root(
+    tx: &BackendTransaction,
+    trie_prefix: &[u8],
+    root: H256,
+    key: &PackedKey,
+) -> anyhow::Result<Option<SmallVec<[u8; 36]>>> {
+    walk_at_root(tx, trie_prefix, root, key, |_| ())
+}
+
+// The RLP of the current root node of the trie with prefix `trie_prefix`,
+// whose hash is the root even if it is shorter than 32 bytes. An empty trie
+// has the empty string as its root node.
+pub fn root_node_rlp(tx: &BackendTransaction, trie_prefix: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(match tx.get(&trie_key(trie_prefix, &[]))? {
+        None => rlp::NULL_RLP.to_vec(),
+        Some(data) => InternalNode::unmarshal(&data)?.rlp().swap_remove(0),
+    })
+}
+
+// Returns the proof of the value under `key` as of `root`, in the format of
+// `eth_getProof`, see `proof::verify_proof`. Fails as `get_at_root` does.
+pub fn proof_at_root(
+    tx: &BackendTransaction,
+    trie_prefix: &[u8],
+    root: H256,
+    key: &PackedKey,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut proof = Vec::new();
+    walk_at_root(tx, trie_prefix, root, key, |node| {
+        // Nodes shorter than 32 bytes are embedded in their parent, apart
+        // from the root which is always hashed
+        for node_rlp in node.rlp() {
+            if node_rlp.len() >= 32 || proof.is_empty() {
+                proof.push(node_rlp);
+            }
+        }
+    })?;
+    Ok(proof)
+}
+
+// Returns up to `limit` leaves of the trie with prefix `trie_prefix`, in key
+// order from `start`
+pub fn leaves_from(
+    tx: &BackendTransaction,
+    trie_prefix: &[u8],
+    start: &PackedKey,
+    limit: usize,
+) -> anyhow::Result<Vec<(PackedKey, SmallVec<[u8; 36]>)>> {
+    LeafIter::new(tx, trie_prefix, start).take(limit).collect()
+}
+
+// The leaf reached by following `nibbles` down the trie with prefix
+// `trie_prefix`, with its full key, if the path ends at or inside a leaf
+// rather than a branch or a missing node
+pub fn leaf_at_path(
+    tx: &BackendTransaction,
+    trie_prefix: &[u8],
+    nibbles: &[u8],
+) -> anyhow::Result<Option<(PackedKey, SmallVec<[u8; 36]>)>> {
+    anyhow::ensure!(nibbles.len() <= 64, "Path of {} nibbles", nibbles.len());
+    anyhow::ensure!(nibbles.iter().all(|x| *x < 16), "Invalid nibble in path");
+    let mut path = NibbleList::new();
+    loop {
+        let node = match tx.get(&trie_key(trie_prefix, &path))? {
+            None => return Ok(None),
+            Some(data) => InternalNode::unmarshal(&data)?,
+        };
+        let rest = &nibbles[path.len()..];
+        match node {
+            InternalNode::Leaf { rest_of_key, value } => {
+                if !rest_of_key.starts_with(rest) {
+                    return Ok(None);
+                }
+                path.try_extend_from_slice(&rest_of_key)?;
+                return Ok(Some((PackedKey::from_nibbles(&path), value)));
+            }
+            InternalNode::Branch {
+                extension_nibbles,
+                subnodes,
+            } => {
+                if rest.len() <= extension_nibbles.len() || !rest.starts_with(&extension_nibbles) {
+                    return Ok(None);
+                }
+                let i = rest[extension_nibbles.len()];
+                if subnodes[i as usize].is_empty() {
+                    return Ok(None);
+                }
+                path.try_extend_from_slice(&extension_nibbles)?;
+                path.try_push(i)?;
+            }
+        }
+    }
+}
+
+// A node on the path to a key, see `trie_path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrieStep {
+    // `index` is the subnode taken after the extension, `None` if the key
+    // leaves the extension
+    Branch {
+        extension_nibbles: Vec<u8>,
+        index: Option<u8>,
+    },
+    // The leaf found where the key leads, which may be another key's
+    Leaf {
+        rest_of_key: Vec<u8>,
+    },
+}
+
+// The nodes from the root towards `key` in the trie with prefix
+// `trie_prefix`. The path ends early at a branch whose chosen subnode is
+// empty, and is empty if the trie is.
+pub fn trie_path(
+    tx: &BackendTransaction,
+    trie_prefix: &[u8],
+    key: &PackedKey,
+) -> anyhow::Result<Vec<TrieStep>> {
+    let nibbles = key.unpack();
+    let mut depth = 0;
+    let mut path = Vec::new();
+    while let Some(data) = tx.get(&trie_key(trie_prefix, &nibbles[..depth]))? {
+        match InternalNode::unmarshal(&data)? {
+            InternalNode::Leaf { rest_of_key, .. } => {
+                path.push(TrieStep::Leaf {
+                    rest_of_key: rest_of_key.to_vec(),
+                });
+                break;
+            }
+            InternalNode::Branch {
+                extension_nibbles,
+                subnodes,
+            } => {
+                let index = nibbles[depth..]
+                    .starts_with(&extension_nibbles)
+                    .then(|| nibbles[depth + extension_nibbles.len()]);
+                path.push(TrieStep::Branch {
+                    extension_nibbles: extension_nibbles.to_vec(),
+                    index,
+                });
+                match index {
+                    Some(i) if !subnodes[i as usize].is_empty() => {
+                        depth += extension_nibbles.len() + 1;
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+    Ok(path)
+}
+
+// Iterates in key order over the leaves of the trie with prefix
+// `trie_prefix` from `start`, reading the nodes as it goes
+pub struct LeafIter<'a, 'txn> {
+    tx: &'a BackendTransaction<'txn>,
+    trie_prefix: Vec<u8>,
+    start: NibbleList,
+    // Paths of the nodes still to visit, the next one last
+    stack: Vec<NibbleList>,
+}
+
+impl<'a, 'txn> LeafIter<'a, 'txn> {
+    pub fn new(tx: &'a BackendTransaction<'txn>, trie_prefix: &[u8], start: &PackedKey) -> Self {
+        Self {
+            tx,
+            trie_prefix: trie_prefix.to_vec(),
+            start: start.unpack(),
+            stack: vec![NibbleList::new()],
+        }
+    }
+
+    fn next_leaf(&mut self) -> anyhow::Result<Option<(PackedKey, SmallVec<[u8; 36]>)>> {
+        while let Some(mut path) = self.stack.pop() {
+            let node = match self.tx.get(&trie_key(&self.trie_prefix, &path))? {
+                None => continue,
+                Some(data) => InternalNode::unmarshal(&data)?,
+            };
+            match node {
+                InternalNode::Leaf { rest_of_key, value } => {
+                    path.try_extend_from_slice(&rest_of_key)?;
+                    if path >= self.start {
+                        return Ok(Some((PackedKey::from_nibbles(&path), value)));
+                    }
+                }
+                InternalNode::Branch {
+                    extension_nibbles,
+                    subnodes,
+                } => {
+                    path.try_extend_from_slice(&extension_nibbles)?;
+                    for (i, subnode) in subnodes.iter().enumerate().rev() {
+                        let mut child = path.clone();
+                        child.try_push(i as u8)?;
+                        // Subtrees entirely before `start` are skipped
+                        if !subnode.is_empty() && child[..] >= self.start[..child.len()] {
+                            self.stack.push(child);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for LeafIter<'_, '_> {
+    type Item = anyhow::Result<(PackedKey, SmallVec<[u8; 36]>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.next_leaf().transpose();
+        if let Some(Err(_)) = res {
+            self.stack.clear();
+        }
+        res
+    }
+}
+
+// Calls `visit` on every node on the path to `key` as of `root`
+fn walk_at_root(
+    tx: &BackendTransaction,
+    trie_prefix: &[u8],
+    root: H256,
+    key: &PackedKey,
+    mut visit: impl FnMut(&InternalNode),
+) -> anyhow::Result<Option<SmallVec<[u8; 36]>>> {
+    if root == *EMPTY_TRIE_ROOT {
+        return Ok(None);
+    }
+    let nibbles = key.unpack();
+    let mut depth = 0;
+    // The reference to the next node, `None` for the root node
+    let mut expected: Option<ArrayVec<u8, 32>> = None;
+    loop {
+        let node = match tx.get(&trie_key(trie_prefix, &nibbles[..depth]))? {
+            None => return Err(DbError::RootUnavailable(root).into()),
+            Some(data) => InternalNode::unmarshal(&data)?,
+        };
+        let node_ref = node.encode();
+        let matches = match &expected {
+            None => root_hash(&node_ref) == root,
+            Some(expected) => node_ref == *expected,
+        };
+        if !matches {
+            return Err(DbError::RootUnavailable(root).into());
+        }
+        visit(&node);
+        match node {
+            InternalNode::Leaf { rest_of_key, value } => {
+                return Ok((rest_of_key[..] == nibbles[depth..]).then_some(value));
+            }
+            InternalNode::Branch {
+                extension_nibbles,
+                subnodes,
+            } => {
+                if !nibbles[depth..].starts_with(&extension_nibbles) {
+                    return Ok(None);
+                }
+                depth += extension_nibbles.len();
+                let subnode = &subnodes[nibbles[depth] as usize];
+                if subnode.is_empty() {
+                    return Ok(None);
+                }
+                expected = Some(subnode.clone());
+                depth += 1;
+            }
+        }
+    }
+}
+
+// The walker only reads from the transaction, its writes are staged and
+// must be applied by the caller with `BackendTransaction::apply`.
 pub struct Walker<'a, 'db, 'txn> {
     prefix: &'a [u8],
-    dirty_list: Vec<(NibbleList, Option<SmallVec<[u8; 36]>>)>,
-    tx: &'txn mut BackendTransaction<'db>,
+    dirty_list: DirtyList,
+    tx: &'txn BackendTransaction<'db>,
+    writes: StagedWrites,
     nibble_list: NibbleList,
+    epoch: Option<u8>,
 }
 
 impl<'db, 'txn, 'a> Walker<'a, 'db, 'txn> {
     pub fn new(
         trie_prefix: &'a [u8],
-        dirty_list: Vec<(NibbleList, Option<SmallVec<[u8; 36]>>)>,
-        tx: &'txn mut BackendTransaction<'db>,
+        dirty_list: DirtyList,
+        tx: &'txn BackendTransaction<'db>,
     ) -> Self {
+        check_sorted(&dirty_list);
         Walker {
             prefix: trie_prefix,
             dirty_list,
             tx,
+            writes: StagedWrites::default(),
             nibble_list: NibbleList::new(),
+            epoch: None,
         }
     }
 
+    // Records `epoch` in every node the walker writes, see
+    // `InternalNode::marshal_with_epoch`
+    pub fn with_epoch(mut self, epoch: Option<u8>) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    // Prepares the walker for another trie, so that a single walker can be
+    // used for many storage tries. The writes so far are kept, see
+    // `take_writes`.
+    pub fn reset(&mut self, trie_prefix: &'a [u8], dirty_list: DirtyList) {
+        check_sorted(&dirty_list);
+        self.prefix = trie_prefix;
+        self.dirty_list = dirty_list;
+        self.nibble_list.clear();
+    }
+
+    pub fn into_writes(self) -> StagedWrites {
+        self.writes
+    }
+
+    pub fn take_writes(&mut self) -> StagedWrites {
+        std::mem::take(&mut self.writes)
+    }
+
     pub fn root(&mut self) -> anyhow::Result<H256> {
-        let root_node = self.walk()?;
-        let root = self.write_node(root_node)?;
-        Ok(if root.is_empty() {
-            *EMPTY_TRIE_ROOT
-        } else if root.len() < 32 {
-            keccak256(root)
-        } else {
-            H256::from_slice(&root)
-        })
+        let root_node = self.get_node()?;
+        let root_ref = encode_ref(&root_node);
+        let root = self.walk_and_write(root_node, root_ref)?;
+        Ok(root_hash(&root))
     }
 
-    fn walk(&mut self) -> anyhow::Result<Option<InternalNode>> {
-        let node: Option<InternalNode> = self.get_node()?;
-        self.walk_node(node)
+    // Walks `node`, the node at `nibble_list` with reference `node_ref`, and
+    // writes it only if its reference changed. An unchanged node, e.g. when
+    // only absent keys are deleted, would be a wasted put or delete.
+    fn walk_and_write(
+        &mut self,
+        node: Option<InternalNode>,
+        node_ref: ArrayVec<u8, 32>,
+    ) -> anyhow::Result<ArrayVec<u8, 32>> {
+        let new_node = self.walk_node(node)?;
+        let new_ref = encode_ref(&new_node);
+        if new_ref != node_ref {
+            self.store_node(new_node)?;
+        }
+        Ok(new_ref)
     }
 
     fn walk_node(
@@ -80,7 +423,7 @@ impl<'db, 'txn, 'a> Walker<'a, 'db, 'txn> {
         Ok(match value {
             None => None,
             Some(value) => Some(InternalNode::Leaf {
-                rest_of_key: ArrayVec::try_from(&key[self.nibble_list.len()..]).unwrap(),
+                rest_of_key: ArrayVec::try_from(&key.unpack()[self.nibble_list.len()..]).unwrap(),
                 value,
             }),
         })
@@ -93,25 +436,32 @@ impl<'db, 'txn, 'a> Walker<'a, 'db, 'txn> {
     ) -> anyhow::Result<Option<InternalNode>> {
         let (key, new_value) = self.dirty_list.last().unwrap().clone();
         debug_assert!(key.starts_with(&self.nibble_list));
+        let key = key.unpack();
         let common_prefix_len = common_prefix(&rest_of_key, &key[self.nibble_list.len()..]);
-        Ok(if common_prefix_len == rest_of_key.len() {
-            // Both keys are the same
-            self.dirty_list.pop();
-            new_value.map(|new_value| InternalNode::Leaf {
-                rest_of_key,
-                value: new_value,
-            })
-        } else {
-            let leaf_node = Some(InternalNode::Leaf {
-                rest_of_key: ArrayVec::try_from(&rest_of_key[common_prefix_len + 1..]).unwrap(),
-                value,
-            });
-            self.make_branch(
-                NibbleList::try_from(&rest_of_key[..common_prefix_len])?,
-                rest_of_key[common_prefix_len],
-                leaf_node,
-            )?
-        })
+        Ok(
+            if common_prefix_len != rest_of_key.len() && new_value.is_none() {
+                // Deleting an absent key leaves the leaf alone
+                self.dirty_list.pop();
+                Some(InternalNode::Leaf { rest_of_key, value })
+            } else if common_prefix_len == rest_of_key.len() {
+                // Both keys are the same
+                self.dirty_list.pop();
+                new_value.map(|new_value| InternalNode::Leaf {
+                    rest_of_key,
+                    value: new_value,
+                })
+            } else {
+                let leaf_node = Some(InternalNode::Leaf {
+                    rest_of_key: ArrayVec::try_from(&rest_of_key[common_prefix_len + 1..]).unwrap(),
+                    value,
+                });
+                self.make_branch(
+                    NibbleList::try_from(&rest_of_key[..common_prefix_len])?,
+                    rest_of_key[common_prefix_len],
+                    leaf_node,
+                )?
+            },
+        )
     }
 
     fn split_extension(
@@ -121,7 +471,7 @@ impl<'db, 'txn, 'a> Walker<'a, 'db, 'txn> {
     ) -> anyhow::Result<Option<InternalNode>> {
         let common_prefix_length = common_prefix(
             &extension_nibbles,
-            &self.dirty_list.last().unwrap().0[self.nibble_list.len()..],
+            &self.dirty_list.last().unwrap().0.unpack()[self.nibble_list.len()..],
         );
         let (segment0, index, segment1) = (
             &extension_nibbles[..common_prefix_length],
@@ -173,10 +523,18 @@ impl<'db, 'txn, 'a> Walker<'a, 'db, 'txn> {
         extension_nibbles: NibbleList,
         mut subnodes: [ArrayVec<u8, 32>; 16],
     ) -> anyhow::Result<Option<InternalNode>> {
-        if self.dirty_list.last().map_or(false, |(k, _)| {
-            !k[self.nibble_list.len()..].starts_with(extension_nibbles.as_slice())
-        }) {
-            return self.split_extension(extension_nibbles, subnodes);
+        if let Some((k, value)) = self.dirty_list.last() {
+            if !k.matches_at(self.nibble_list.len(), &extension_nibbles) {
+                if value.is_some() {
+                    return self.split_extension(extension_nibbles, subnodes);
+                }
+                // Deleting an absent key leaves the branch alone
+                self.dirty_list.pop();
+                return Ok(Some(InternalNode::Branch {
+                    extension_nibbles,
+                    subnodes,
+                }));
+            }
         }
         self.nibble_list.try_extend_from_slice(&extension_nibbles)?;
         while self
@@ -185,10 +543,16 @@ impl<'db, 'txn, 'a> Walker<'a, 'db, 'txn> {
             .map_or(false, |(k, _)| k.starts_with(&self.nibble_list))
         {
             let (key, _) = self.dirty_list.last().unwrap();
-            let index = key[self.nibble_list.len()];
+            let index = key.nibble(self.nibble_list.len());
             self.nibble_list.push(index);
-            let subnode = self.walk()?;
-            subnodes[index as usize] = self.write_node(subnode)?;
+            let node_ref = std::mem::take(&mut subnodes[index as usize]);
+            // An empty slot has nothing to read
+            let subnode = if node_ref.is_empty() {
+                None
+            } else {
+                self.get_node()?
+            };
+            subnodes[index as usize] = self.walk_and_write(subnode, node_ref)?;
             self.nibble_list.pop();
         }
         self.nibble_list
@@ -230,26 +594,387 @@ impl<'db, 'txn, 'a> Walker<'a, 'db, 'txn> {
     }
 
     fn get_node(&self) -> anyhow::Result<Option<InternalNode>> {
-        let mut db_key = self.prefix.to_vec();
-        db_key.extend_from_slice(&nibble_list_to_key(&self.nibble_list));
-        Ok(self.tx.get(&db_key)?.map(|x| InternalNode::unmarshal(&x)))
+        let db_key = trie_key(self.prefix, &self.nibble_list);
+        Ok(match self.writes.get(&db_key) {
+            Some(value) => value.map(InternalNode::unmarshal).transpose()?,
+            None => self
+                .tx
+                .get(&db_key)?
+                .map(|x| InternalNode::unmarshal(&x))
+                .transpose()?,
+        })
     }
 
     fn write_node(&mut self, node: Option<InternalNode>) -> anyhow::Result<ArrayVec<u8, 32>> {
-        if let Some(InternalNode::Branch { subnodes, .. }) = node.clone() {
-            assert!(!subnodes.iter().all(|x| x.is_empty()));
+        let node_ref = encode_ref(&node);
+        self.store_node(node)?;
+        Ok(node_ref)
+    }
+
+    fn store_node(&mut self, node: Option<InternalNode>) -> anyhow::Result<()> {
+        // An empty branch must have been collapsed by `walk_branch`
+        if let Some(InternalNode::Branch { subnodes, .. }) = &node {
+            anyhow::ensure!(
+                subnodes.iter().any(|x| !x.is_empty()),
+                "Writing a branch with no children at {:?} in trie {:?}",
+                self.nibble_list,
+                self.prefix
+            );
         }
-        let mut db_key = self.prefix.to_vec();
-        db_key.extend_from_slice(&nibble_list_to_key(&self.nibble_list));
-        Ok(match node {
-            None => {
-                self.tx.delete(&db_key)?;
-                ArrayVec::new()
-            }
+        let db_key = trie_key(self.prefix, &self.nibble_list);
+        match node {
+            None => self.writes.delete(&db_key),
             Some(node) => {
-                self.tx.put(&db_key, &node.marshal())?;
-                node.encode()
+                let data = match self.epoch {
+                    None => node.marshal(),
+                    Some(epoch) => node.marshal_with_epoch(epoch),
+                };
+                self.writes.put(&db_key, &data)
             }
-        })
+        }
+    }
+}
+
+// The reference to `node` in its parent, empty for no node
+fn encode_ref(node: &Option<InternalNode>) -> ArrayVec<u8, 32> {
+    node.as_ref()
+        .map_or_else(ArrayVec::new, InternalNode::encode)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NodeCounts {
+    pub leaves: u64,
+    pub branches: u64,
+    // Longest nibble path at which a node is stored
+    pub max_depth: usize,
+}
+
+impl NodeCounts {
+    pub fn nodes(&self) -> u64 {
+        self.leaves + self.branches
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrieStats {
+    pub account_trie: NodeCounts,
+    // Summed over every storage trie
+    pub storage_tries: NodeCounts,
+}
+
+// Visits every node of the account trie and the storage tries beneath it.
+// This reads the whole trie, so it is slow on a large state.
+pub fn trie_stats(tx: &BackendTransaction) -> anyhow::Result<TrieStats> {
+    let mut stats = TrieStats::default();
+    let mut account_keys = Vec::new();
+    count_nodes(
+        tx,
+        ACCOUNT_TRIE_PREFIX,
+        &mut NibbleList::new(),
+        &mut stats.account_trie,
+        &mut |key, value| {
+            let storage_root: H256 = rlp::Rlp::new(value).val_at(2)?;
+            if storage_root != *EMPTY_TRIE_ROOT {
+                account_keys.push(key);
+            }
+            Ok(())
+        },
+    )?;
+    for key in account_keys {
+        let trie_prefix = storage_trie_prefix(&key);
+        count_nodes(
+            tx,
+            &trie_prefix,
+            &mut NibbleList::new(),
+            &mut stats.storage_tries,
+            &mut |_, _| Ok(()),
+        )?;
+    }
+    Ok(stats)
+}
+
+fn count_nodes(
+    tx: &BackendTransaction,
+    prefix: &[u8],
+    nibble_list: &mut NibbleList,
+    counts: &mut NodeCounts,
+    on_leaf: &mut impl FnMut(NibbleList, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let db_key = trie_key(prefix, nibble_list);
+    let node = match tx.get(&db_key)? {
+        None => return Ok(()),
+        Some(data) => InternalNode::unmarshal(&data)?,
+    };
+    counts.max_depth = counts.max_depth.max(nibble_list.len());
+    match node {
+        InternalNode::Leaf { rest_of_key, value } => {
+            counts.leaves += 1;
+            let mut key = nibble_list.clone();
+            key.try_extend_from_slice(&rest_of_key)?;
+            on_leaf(key, &value)?;
+        }
+        InternalNode::Branch {
+            extension_nibbles,
+            subnodes,
+        } => {
+            counts.branches += 1;
+            nibble_list.try_extend_from_slice(&extension_nibbles)?;
+            for (i, subnode) in subnodes.iter().enumerate() {
+                if !subnode.is_empty() {
+                    nibble_list.push(i as u8);
+                    count_nodes(tx, prefix, nibble_list, counts, on_leaf)?;
+                    nibble_list.pop();
+                }
+            }
+            nibble_list.truncate(nibble_list.len() - extension_nibbles.len());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::structs::{get_internal_key, get_packed_key};
+
+    #[test]
+    fn test_reset() {
+        let backend = Backend::memory().unwrap();
+        let mut tx = backend.begin_mut().unwrap();
+        let trie_prefixes: Vec<Vec<u8>> = (0u8..5)
+            .map(|i| storage_trie_prefix(&get_internal_key([i])))
+            .collect();
+        for round in 0u8..3 {
+            let dirty_lists: Vec<DirtyList> = (0u8..5)
+                .map(|i| {
+                    let mut dirty_list: DirtyList = (0..10 * i + 1)
+                        .map(|j| {
+                            let value = (j % 3 != round).then(|| SmallVec::from_slice(&[round, j]));
+                            (get_packed_key([i, j]), value)
+                        })
+                        .collect();
+                    sort_dirty_list(&mut dirty_list);
+                    dirty_list
+                })
+                .collect();
+            let fresh: Vec<_> = trie_prefixes
+                .iter()
+                .zip(dirty_lists.clone())
+                .map(|(trie_prefix, dirty_list)| {
+                    let mut walker = Walker::new(trie_prefix, dirty_list, &tx);
+                    (walker.root().unwrap(), walker.into_writes())
+                })
+                .collect();
+            let mut walker = Walker::new(&trie_prefixes[0], dirty_lists[0].clone(), &tx);
+            for (i, (trie_prefix, dirty_list)) in trie_prefixes.iter().zip(dirty_lists).enumerate()
+            {
+                if i > 0 {
+                    walker.reset(trie_prefix, dirty_list);
+                }
+                assert_eq!((walker.root().unwrap(), walker.take_writes()), fresh[i]);
+            }
+            for (_, writes) in fresh {
+                tx.apply(writes);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sort_dirty_list() {
+        let root = |dirty_list| {
+            let backend = Backend::memory().unwrap();
+            let tx = backend.begin_mut().unwrap();
+            Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list, &tx)
+                .root()
+                .unwrap()
+        };
+        let mut dirty_list = vec![
+            (get_packed_key([0]), Some(SmallVec::from_slice(&[1]))),
+            (get_packed_key([1]), Some(SmallVec::from_slice(&[2]))),
+            (get_packed_key([0]), None),
+            (get_packed_key([2]), Some(SmallVec::from_slice(&[3]))),
+            (get_packed_key([0]), Some(SmallVec::from_slice(&[4]))),
+        ];
+        sort_dirty_list(&mut dirty_list);
+        assert_eq!(dirty_list.len(), 3);
+        let mut expected = vec![
+            (get_packed_key([0]), Some(SmallVec::from_slice(&[4]))),
+            (get_packed_key([1]), Some(SmallVec::from_slice(&[2]))),
+            (get_packed_key([2]), Some(SmallVec::from_slice(&[3]))),
+        ];
+        expected.sort_unstable_by_key(|x| Reverse(x.0));
+        assert_eq!(dirty_list, expected);
+        assert_eq!(root(dirty_list), root(expected));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "dirty_list is not sorted")]
+    fn test_unsorted_dirty_list() {
+        let backend = Backend::memory().unwrap();
+        let tx = backend.begin_mut().unwrap();
+        let mut dirty_list = vec![
+            (get_packed_key([0]), Some(SmallVec::from_slice(&[1]))),
+            (get_packed_key([1]), Some(SmallVec::from_slice(&[1]))),
+        ];
+        dirty_list.sort_unstable_by_key(|x| x.0);
+        Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list, &tx);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "dirty_list is not sorted")]
+    fn test_duplicate_dirty_list() {
+        let backend = Backend::memory().unwrap();
+        let tx = backend.begin_mut().unwrap();
+        let dirty_list = vec![
+            (get_packed_key([0]), Some(SmallVec::from_slice(&[1]))),
+            (get_packed_key([0]), None),
+        ];
+        Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list, &tx);
+    }
+
+    #[test]
+    fn test_empty_dirty_list() {
+        let backend = Backend::memory().unwrap();
+        let mut tx = backend.begin_mut().unwrap();
+        let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, Vec::new(), &tx);
+        assert_eq!(walker.root().unwrap(), *EMPTY_TRIE_ROOT);
+        assert!(walker.into_writes().get(ACCOUNT_TRIE_PREFIX).is_none());
+
+        let mut dirty_list = (0..10)
+            .map(|i| (get_packed_key([i]), Some(SmallVec::from_slice(&[i]))))
+            .collect();
+        sort_dirty_list(&mut dirty_list);
+        let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list, &tx);
+        let root = walker.root().unwrap();
+        let writes = walker.into_writes();
+        tx.apply(writes);
+
+        let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, Vec::new(), &tx);
+        assert_eq!(walker.root().unwrap(), root);
+        let writes = walker.into_writes();
+        assert_eq!((writes.num_puts(), writes.num_deletes()), (0, 0));
+    }
+
+    #[test]
+    fn test_delete_absent_keys() {
+        let key = |prefix: &[u8]| {
+            let mut nibbles = [0; 64];
+            nibbles[..prefix.len()].copy_from_slice(prefix);
+            PackedKey::from_nibbles(&nibbles)
+        };
+        let backend = Backend::memory().unwrap();
+        let mut tx = backend.begin_mut().unwrap();
+        let deletes = |keys: &[&[u8]]| -> DirtyList {
+            let mut dirty_list: DirtyList = keys.iter().map(|k| (key(k), None)).collect();
+            sort_dirty_list(&mut dirty_list);
+            dirty_list
+        };
+        // An empty trie
+        let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, deletes(&[&[1], &[2]]), &tx);
+        assert_eq!(walker.root().unwrap(), *EMPTY_TRIE_ROOT);
+        assert_eq!(walker.into_writes(), StagedWrites::default());
+
+        // A single leaf
+        let root = apply(
+            &mut tx,
+            vec![(key(&[1, 2]), Some(SmallVec::from_slice(&[1])))],
+        );
+        let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, deletes(&[&[1, 3], &[2]]), &tx);
+        assert_eq!(walker.root().unwrap(), root);
+        assert_eq!(walker.into_writes(), StagedWrites::default());
+
+        // A branch with an extension, at an empty slot, diverging from the
+        // extension and diverging from a leaf
+        let root = apply(
+            &mut tx,
+            vec![
+                (key(&[1, 2, 5]), Some(SmallVec::from_slice(&[2]))),
+                (key(&[1, 2, 6, 1]), Some(SmallVec::from_slice(&[3]))),
+                (key(&[1, 2, 6, 2]), Some(SmallVec::from_slice(&[4]))),
+            ],
+        );
+        let absent: &[&[u8]] = &[
+            &[0],
+            &[1, 2, 7],
+            &[1, 3],
+            &[1, 2, 5, 1],
+            &[1, 2, 6, 3],
+            &[1, 2, 6],
+        ];
+        let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, deletes(absent), &tx);
+        assert_eq!(walker.root().unwrap(), root);
+        assert_eq!(walker.into_writes(), StagedWrites::default());
+
+        // Mixed with a real change, only the path to it is written
+        let mut dirty_list = deletes(absent);
+        dirty_list.push((key(&[1, 2, 6, 2]), Some(SmallVec::from_slice(&[5]))));
+        sort_dirty_list(&mut dirty_list);
+        let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list, &tx);
+        walker.root().unwrap();
+        let writes = walker.into_writes();
+        assert_eq!((writes.num_puts(), writes.num_deletes()), (3, 0));
+    }
+
+    // Applies `dirty_list` to the account trie, returning the new root
+    fn apply(tx: &mut BackendTransaction, mut dirty_list: DirtyList) -> H256 {
+        sort_dirty_list(&mut dirty_list);
+        let mut walker = Walker::new(ACCOUNT_TRIE_PREFIX, dirty_list, tx);
+        let root = walker.root().unwrap();
+        let writes = walker.into_writes();
+        tx.apply(writes);
+        root
+    }
+
+    #[test]
+    fn test_branch_collapse() {
+        let key = |prefix: &[u8]| {
+            let mut nibbles = [0; 64];
+            nibbles[..prefix.len()].copy_from_slice(prefix);
+            PackedKey::from_nibbles(&nibbles)
+        };
+        // Keys, then the keys deleted from them
+        type Keys<'a> = &'a [&'a [u8]];
+        let cases: &[(Keys, Keys)] = &[
+            // Root branch from 2 children to 0
+            (&[&[1], &[2]], &[&[1], &[2]]),
+            // Root branch from 2 children to 1, the leaf is raised
+            (&[&[1], &[2]], &[&[2]]),
+            // A leaf raised through an extension
+            (&[&[1, 2, 3, 4], &[1, 2, 3, 5], &[2]], &[&[1, 2, 3, 5]]),
+            // A branch raised, its extension merged with the one above
+            (
+                &[&[1, 2, 3, 4, 5, 6], &[1, 2, 3, 4, 5, 7], &[1, 2, 8]],
+                &[&[1, 2, 8]],
+            ),
+            // An inner branch from 2 children to 0
+            (&[&[1, 2, 3], &[1, 2, 4], &[5]], &[&[1, 2, 3], &[1, 2, 4]]),
+            // An inner branch and the root branch collapsing together
+            (&[&[1, 2, 3], &[1, 2, 4], &[5]], &[&[1, 2, 3], &[5]]),
+        ];
+        let value = SmallVec::from_slice(&[1; 40]);
+        for (keys, deleted) in cases {
+            let backend = Backend::memory().unwrap();
+            let mut tx = backend.begin_mut().unwrap();
+            let inserted = keys.iter().map(|k| (key(k), Some(value.clone())));
+            apply(&mut tx, inserted.collect());
+            let root = apply(&mut tx, deleted.iter().map(|k| (key(k), None)).collect());
+
+            let expected_backend = Backend::memory().unwrap();
+            let mut expected_tx = expected_backend.begin_mut().unwrap();
+            let remaining = keys
+                .iter()
+                .filter(|k| !deleted.contains(k))
+                .map(|k| (key(k), Some(value.clone())));
+            assert_eq!(root, apply(&mut expected_tx, remaining.collect()));
+            let nodes = |tx: &BackendTransaction| {
+                tx.iter_prefix(ACCOUNT_TRIE_PREFIX)
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .unwrap()
+            };
+            assert_eq!(nodes(&tx), nodes(&expected_tx), "{:?}", (keys, deleted));
+        }
     }
 }
@@ -1,4 +1,4 @@
-use ethereum_types::{Address, H256};
+use ethereum_types::{Address, H256, U256};
 use rlp::RlpStream;
 use std::collections::HashMap;
 use trie;
@@ -28,3 +28,38 @@ pub fn calc_root(contents: &HashMap<Address, Account>) -> H256 {
         .collect();
     H256::from_slice(&trie::build(&trie_items).0)
 }
+
+pub fn calc_storage_root(storage: &HashMap<H256, U256>) -> H256 {
+    let trie_items: HashMap<Vec<u8>, Vec<u8>> = storage
+        .iter()
+        .filter(|(_, value)| !value.is_zero())
+        .map(|(key, value)| {
+            (
+                keccak256(key).as_bytes().to_vec(),
+                rlp::encode(value).to_vec(),
+            )
+        })
+        .collect();
+    H256::from_slice(&trie::build(&trie_items).0)
+}
+
+pub fn calc_root_with_storage(
+    contents: &HashMap<Address, Account>,
+    storage: &HashMap<Address, HashMap<H256, U256>>,
+) -> H256 {
+    let trie_items: HashMap<Vec<u8>, Vec<u8>> = contents
+        .iter()
+        .map(|(address, account)| {
+            let storage_root = storage
+                .get(address)
+                .map_or(*EMPTY_TRIE_ROOT, calc_storage_root);
+            let mut s = RlpStream::new_list(4);
+            s.append(&account.nonce)
+                .append(&account.balance)
+                .append(&storage_root)
+                .append(&account.code_hash);
+            (keccak256(address).as_bytes().to_vec(), s.out().to_vec())
+        })
+        .collect();
+    H256::from_slice(&trie::build(&trie_items).0)
+}
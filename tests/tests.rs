@@ -2,7 +2,7 @@ pub mod check_trie;
 pub mod get_prefix;
 
 use ethereum_pyspec_db::*;
-use ethereum_types::{Address, U256};
+use ethereum_types::{Address, H160, H256, U256};
 use once_cell::sync::Lazy;
 use rand::{seq::IteratorRandom, Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
@@ -13,21 +13,9 @@ use crate::get_prefix::{get_address_from_index, get_address_with_prefix_nibbles}
 
 static LAZY_NONE: Lazy<Option<Account>> = Lazy::new(|| None);
 
-static ACCOUNT1: Lazy<Option<Account>> = Lazy::new(|| {
-    Some(Account {
-        nonce: 1,
-        balance: U256::zero(),
-        code_hash: *EMPTY_CODE_HASH,
-    })
-});
+static ACCOUNT1: Lazy<Option<Account>> = Lazy::new(|| Some(Account::eoa(1, U256::zero())));
 
-static ACCOUNT2: Lazy<Option<Account>> = Lazy::new(|| {
-    Some(Account {
-        nonce: 2,
-        balance: U256::zero(),
-        code_hash: *EMPTY_CODE_HASH,
-    })
-});
+static ACCOUNT2: Lazy<Option<Account>> = Lazy::new(|| Some(Account::eoa(2, U256::zero())));
 
 static TESTS: &[&[(&[u8], &Lazy<Option<Account>>)]] = &[
     &[],
@@ -71,8 +59,9 @@ fn with_memory<T>(f: impl for<'a> FnOnce(&'a mut Db) -> T) -> T {
 
 fn with_temp_db<T>(f: impl for<'a> FnOnce(&'a mut Db) -> T) -> T {
     let dir = tempfile::tempdir().unwrap();
-    let mut db = Db::memory().unwrap();
+    let mut db = Db::file(dir.path()).unwrap();
     let res = f(&mut db);
+    drop(db);
     dir.close().unwrap();
     res
 }
@@ -90,9 +79,8 @@ fn do_tests<'env>(db: &mut Db) {
                 trie_contents.remove(&address);
             }
         }
-        let state_root = txn.state_root().unwrap();
+        let state_root = txn.commit().unwrap();
         assert_eq!(state_root, check_trie::calc_root(&trie_contents));
-        txn.commit().unwrap();
     }
 }
 
@@ -132,11 +120,7 @@ fn do_random_tests(db: &mut Db) {
             }
         }
 
-        assert_eq!(
-            txn.state_root().unwrap(),
-            check_trie::calc_root(&trie_contents)
-        );
-        txn.commit().unwrap();
+        assert_eq!(txn.commit().unwrap(), check_trie::calc_root(&trie_contents));
     }
 }
 
@@ -166,3 +150,3167 @@ fn gen_op(
         }
     }
 }
+
+#[test]
+fn touch_account() {
+    let db = Db::memory().unwrap();
+    let touched = get_address_from_index(0);
+    let untouched = get_address_from_index(1);
+    let missing = get_address_from_index(2);
+    let empty = Account::eoa(0, U256::zero());
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(touched, Some(empty.clone()));
+    txn.set_account(untouched, Some(empty.clone()));
+    txn.commit().unwrap();
+
+    // Before EIP-161 touching creates the account
+    let mut txn = db.begin_mut().unwrap();
+    txn.touch_account(missing).unwrap();
+    assert_eq!(txn.try_account(missing).unwrap(), Some(empty.clone()));
+    txn.rollback().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.touch_account(touched).unwrap();
+    txn.touch_account(missing).unwrap();
+    txn.prune_empty_accounts();
+    assert_eq!(txn.try_account(touched).unwrap(), None);
+    assert_eq!(txn.try_account(untouched).unwrap(), Some(empty.clone()));
+    assert_eq!(txn.try_account(missing).unwrap(), None);
+
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(untouched, empty);
+    assert_eq!(
+        txn.state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+}
+
+#[test]
+fn prune_empty_accounts() {
+    let db = Db::memory().unwrap();
+    let address = get_address_from_index(0);
+    let other = get_address_from_index(1);
+    let funded = Account::eoa(0, U256::from(100));
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, Some(funded.clone()));
+    txn.set_account(other, ACCOUNT1.clone());
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    let debited = Account {
+        balance: U256::zero(),
+        ..funded
+    };
+    assert!(debited.is_empty());
+    assert!(!ACCOUNT1.as_ref().unwrap().is_empty());
+    txn.set_account(address, Some(debited));
+    txn.set_account(other, ACCOUNT1.clone());
+    txn.prune_empty_accounts();
+    assert_eq!(txn.try_account(address).unwrap(), None);
+
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(other, ACCOUNT1.clone().unwrap());
+    assert_eq!(
+        txn.state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+}
+
+#[test]
+fn file_db_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let address = get_address_from_index(0);
+    let state_root = {
+        let db = Db::file(dir.path()).unwrap();
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_account(address, ACCOUNT1.clone());
+        let code_hash = txn.store_code(b"code").unwrap();
+        txn.set_metadata(b"key", b"value").unwrap();
+        txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+        assert_eq!(
+            txn.code_from_hash(code_hash).unwrap().unwrap(),
+            &b"code"[..]
+        );
+        txn.commit().unwrap()
+    };
+    let db = Db::file(dir.path()).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+    assert_eq!(txn.storage(address, H256::zero()).unwrap(), U256::one());
+    assert_eq!(txn.metadata(b"key").unwrap().unwrap(), &b"value"[..]);
+    assert_eq!(
+        txn.code_from_hash(keccak256(b"code")).unwrap().unwrap(),
+        &b"code"[..]
+    );
+    assert_eq!(txn.state_root().unwrap(), state_root);
+}
+
+#[test]
+fn commit_and_continue() {
+    let dir0 = tempfile::tempdir().unwrap();
+    let dir1 = tempfile::tempdir().unwrap();
+    let db0 = Db::file(dir0.path()).unwrap();
+    let mut rng = ChaCha8Rng::seed_from_u64(1);
+    let mut trie_contents = HashMap::<Address, Account>::new();
+    let mut roots = Vec::new();
+    for _ in 0..20 {
+        let mut txn = db0.begin_mut().unwrap();
+        let (address, account) = gen_op(&trie_contents, &mut rng);
+        txn.set_account(address, account.clone());
+        if let Some(account) = &account {
+            trie_contents.insert(address, account.clone());
+        } else {
+            trie_contents.remove(&address);
+        }
+        roots.push((address, account, txn.commit().unwrap()));
+    }
+
+    {
+        let db1 = Db::file(dir1.path()).unwrap();
+        let mut txn = db1.begin_mut().unwrap();
+        for (address, account, root) in &roots {
+            txn.set_account(*address, account.clone());
+            assert_eq!(txn.commit_and_continue().unwrap(), *root);
+        }
+    }
+
+    let db1 = Db::file(dir1.path()).unwrap();
+    let mut txn = db1.begin_mut().unwrap();
+    assert_eq!(txn.state_root().unwrap(), roots.last().unwrap().2);
+    assert_eq!(
+        txn.state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+}
+
+#[test]
+fn read_transaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let address = get_address_from_index(0);
+    for db in [Db::memory().unwrap(), Db::file(dir.path()).unwrap()] {
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_account(address, ACCOUNT1.clone());
+        txn.set_storage(address, H256::zero(), U256::from(7))
+            .unwrap();
+        let code_hash = txn.store_code(b"code").unwrap();
+        txn.commit().unwrap();
+
+        let txn = db.begin_ro().unwrap();
+        assert_eq!(txn.lag().unwrap(), 0);
+        assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+        assert_eq!(txn.try_account(get_address_from_index(1)).unwrap(), None);
+        assert_eq!(txn.storage(address, H256::zero()).unwrap(), U256::from(7));
+        assert_eq!(
+            txn.storage(address, H256::repeat_byte(1)).unwrap(),
+            U256::zero()
+        );
+        assert_eq!(
+            txn.code_from_hash(code_hash).unwrap().unwrap(),
+            &b"code"[..]
+        );
+    }
+}
+
+#[test]
+fn storage_h256() {
+    let db = Db::memory().unwrap();
+    let address = get_address_from_index(0);
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    let mut small = H256::zero();
+    small.0[31] = 0x2a;
+    let mut leading_zeros = H256::zero();
+    leading_zeros.0[3] = 0x01;
+    let values = [H256::zero(), small, leading_zeros, H256::repeat_byte(0xff)];
+    for (i, value) in values.iter().enumerate() {
+        txn.set_storage_h256(address, H256::from_low_u64_be(i as u64), *value)
+            .unwrap();
+    }
+    assert_eq!(
+        txn.storage(address, H256::from_low_u64_be(1)).unwrap(),
+        U256::from(0x2a)
+    );
+    assert_eq!(
+        txn.storage(address, H256::from_low_u64_be(3)).unwrap(),
+        U256::MAX
+    );
+    txn.commit().unwrap();
+
+    let txn = db.begin_mut().unwrap();
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(
+            txn.storage_h256(address, H256::from_low_u64_be(i as u64))
+                .unwrap(),
+            *value
+        );
+    }
+}
+
+fn do_rollback_test(db: &mut Db) {
+    let address = get_address_from_index(0);
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(address, ACCOUNT1.clone().unwrap());
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    let state_root = txn.state_root().unwrap();
+    txn.set_account(address, ACCOUNT2.clone());
+    txn.set_account(get_address_from_index(1), ACCOUNT2.clone());
+    txn.set_storage(address, H256::zero(), U256::from(2))
+        .unwrap();
+    txn.set_storage(address, H256::repeat_byte(1), U256::from(3))
+        .unwrap();
+    let code_hash = txn.store_code(b"code").unwrap();
+    txn.set_metadata(b"key", b"value").unwrap();
+    assert_ne!(txn.state_root().unwrap(), state_root);
+    txn.destroy_storage(address).unwrap();
+    txn.state_root().unwrap();
+    txn.rollback().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+    assert_eq!(txn.try_account(get_address_from_index(1)).unwrap(), None);
+    assert_eq!(txn.storage(address, H256::zero()).unwrap(), U256::one());
+    assert_eq!(
+        txn.storage(address, H256::repeat_byte(1)).unwrap(),
+        U256::zero()
+    );
+    assert_eq!(txn.code_from_hash(code_hash).unwrap(), None);
+    assert_eq!(txn.metadata(b"key").unwrap(), None);
+    assert_eq!(txn.state_root().unwrap(), state_root);
+
+    txn.set_account(get_address_from_index(2), ACCOUNT2.clone());
+    trie_contents.insert(get_address_from_index(2), ACCOUNT2.clone().unwrap());
+    txn.set_account(address, None);
+    trie_contents.remove(&address);
+    assert_eq!(
+        txn.state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+}
+
+#[test]
+fn rollback() {
+    with_memory(do_rollback_test);
+    with_temp_db(do_rollback_test);
+}
+
+#[test]
+fn open_reports_created() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    assert!(Db::open_existing(&path).is_err());
+    assert!(!path.exists());
+    {
+        let (_, opened) = Db::open(&path).unwrap();
+        assert!(opened.created);
+    }
+    {
+        let (_, opened) = Db::open(&path).unwrap();
+        assert!(!opened.created);
+    }
+    Db::open_existing(&path).unwrap();
+}
+
+#[test]
+fn many_storage_tries() {
+    let db = Db::memory().unwrap();
+    let mut trie_contents = HashMap::new();
+    let mut storage = HashMap::new();
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..200 {
+        let address = get_address_from_index(i);
+        txn.set_account(address, ACCOUNT1.clone());
+        trie_contents.insert(address, ACCOUNT1.clone().unwrap());
+        let slots: &mut HashMap<H256, U256> = storage.entry(address).or_default();
+        for j in 0..(i % 5) {
+            let key = H256::from_low_u64_be(j as u64);
+            let value = U256::from(i * j + 1);
+            txn.set_storage(address, key, value).unwrap();
+            slots.insert(key, value);
+        }
+    }
+    assert_eq!(
+        txn.commit().unwrap(),
+        check_trie::calc_root_with_storage(&trie_contents, &storage)
+    );
+
+    let mut txn = db.begin_mut().unwrap();
+    for i in (0..200).step_by(3) {
+        let address = get_address_from_index(i);
+        txn.set_storage(address, H256::zero(), U256::zero())
+            .unwrap();
+        storage.get_mut(&address).unwrap().remove(&H256::zero());
+    }
+    assert_eq!(
+        txn.state_root().unwrap(),
+        check_trie::calc_root_with_storage(&trie_contents, &storage)
+    );
+}
+
+#[test]
+fn flush_overwrites_and_appends() {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let db = Db::file(dir.path()).unwrap();
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_metadata(b"a", b"1").unwrap();
+        txn.set_metadata(b"c", b"1").unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_metadata(b"b", b"2").unwrap();
+        txn.set_metadata(b"c", b"2").unwrap();
+        txn.set_metadata(b"d", b"2").unwrap();
+        txn.set_metadata(b"e", b"2").unwrap();
+        txn.commit().unwrap();
+    }
+    let db = Db::file(dir.path()).unwrap();
+    let txn = db.begin_mut().unwrap();
+    assert_eq!(txn.metadata(b"a").unwrap().unwrap(), &b"1"[..]);
+    for key in [b"b", b"c", b"d", b"e"] {
+        assert_eq!(txn.metadata(key).unwrap().unwrap(), &b"2"[..]);
+    }
+}
+
+#[test]
+fn restore_by_hash() {
+    let db = Db::memory().unwrap();
+    let restored = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let mut restore_txn = restored.begin_mut().unwrap();
+    for i in 0..50 {
+        let address = get_address_from_index(i);
+        let addr_hash = keccak256(address);
+        txn.set_account(address, ACCOUNT1.clone());
+        restore_txn.set_account_by_hash(addr_hash, ACCOUNT1.clone());
+        for j in 0..(i % 4) {
+            let key = H256::from_low_u64_be(j as u64);
+            let value = U256::from(i * j + 1);
+            txn.set_storage(address, key, value).unwrap();
+            restore_txn
+                .set_storage_by_hash(addr_hash, keccak256(key), value)
+                .unwrap();
+        }
+    }
+    assert!(restore_txn
+        .set_storage_by_hash(H256::zero(), H256::zero(), U256::one())
+        .is_err());
+    assert_eq!(txn.state_root().unwrap(), restore_txn.state_root().unwrap());
+    txn.commit().unwrap();
+    restore_txn.commit().unwrap();
+
+    // Changes by address apply on top of a by-hash restore
+    let mut txn = db.begin_mut().unwrap();
+    let mut restore_txn = restored.begin_mut().unwrap();
+    for i in (0..50).step_by(7) {
+        let address = get_address_from_index(i);
+        txn.set_account(address, ACCOUNT2.clone());
+        restore_txn.set_account(address, ACCOUNT2.clone());
+    }
+    assert_eq!(txn.state_root().unwrap(), restore_txn.state_root().unwrap());
+}
+
+fn do_commit_if_root_test(db: &mut Db) {
+    let address = get_address_from_index(0);
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(address, ACCOUNT1.clone().unwrap());
+    let expected = check_trie::calc_root(&trie_contents);
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.commit_if_root(expected).unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT2.clone());
+    txn.set_metadata(b"block", b"2").unwrap();
+    let err = txn.commit_if_root(expected).unwrap_err();
+    let mismatch = err.downcast_ref::<StateRootMismatch>().unwrap();
+    assert_eq!(mismatch.expected, expected);
+    assert_ne!(mismatch.got, expected);
+    assert_eq!(mismatch.changed_accounts, [address]);
+    assert!(err
+        .to_string()
+        .ends_with(&format!("changed accounts: [{:?}]", address)));
+
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+    assert_eq!(txn.metadata(b"block").unwrap(), None);
+    assert_eq!(txn.state_root().unwrap(), expected);
+}
+
+#[test]
+fn commit_if_root() {
+    with_memory(do_commit_if_root_test);
+    with_temp_db(do_commit_if_root_test);
+}
+
+#[test]
+fn set_storage_on_pending_account() {
+    let address = get_address_from_index(0);
+    let deleted = get_address_from_index(1);
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(deleted, ACCOUNT1.clone());
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    for i in 0..50 {
+        txn.set_storage(address, H256::from_low_u64_be(i), U256::from(i + 1))
+            .unwrap();
+    }
+    txn.set_account(deleted, None);
+    assert!(txn.set_storage(deleted, H256::zero(), U256::one()).is_err());
+    assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+    assert_eq!(
+        txn.storage(address, H256::from_low_u64_be(7)).unwrap(),
+        U256::from(8)
+    );
+
+    let reference = Db::memory().unwrap();
+    let mut reference_txn = reference.begin_mut().unwrap();
+    reference_txn.set_account(address, ACCOUNT1.clone());
+    reference_txn.commit().unwrap();
+    let mut reference_txn = reference.begin_mut().unwrap();
+    for i in 0..50 {
+        reference_txn
+            .set_storage(address, H256::from_low_u64_be(i), U256::from(i + 1))
+            .unwrap();
+    }
+    assert_eq!(
+        txn.state_root().unwrap(),
+        reference_txn.state_root().unwrap()
+    );
+}
+
+#[test]
+fn apply_block() {
+    let db = Db::memory().unwrap();
+    let address = get_address_from_index(0);
+    let emptied = get_address_from_index(1);
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(address, ACCOUNT1.clone().unwrap());
+    let expected = check_trie::calc_root(&trie_contents);
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_account(emptied, ACCOUNT2.clone());
+    txn.commit().unwrap();
+
+    // The emptied account is pruned before the root is computed
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(emptied, Some(Account::eoa(0, U256::zero())));
+    assert_eq!(txn.apply_block(Some(expected)).unwrap(), expected);
+
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.try_account(emptied).unwrap(), None);
+    txn.set_account(address, ACCOUNT2.clone());
+    let err = txn.apply_block(Some(expected)).unwrap_err();
+    assert!(err.downcast_ref::<StateRootMismatch>().is_some());
+
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+    txn.touch_account(get_address_from_index(2)).unwrap();
+    assert_eq!(txn.apply_block(None).unwrap(), expected);
+}
+
+#[test]
+fn import_rlp_accounts() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let code_hash = txn.store_code(b"code").unwrap();
+    for i in 0..100 {
+        let account = Account::new(i as u64, U256::from(i), code_hash);
+        txn.set_account(get_address_from_index(i), Some(account));
+    }
+    let root = txn.state_root().unwrap();
+    let mut export = Vec::new();
+    for i in 0..100 {
+        let address = get_address_from_index(i);
+        let mut s = rlp::RlpStream::new_list(2);
+        s.append(&keccak256(address));
+        s.append_raw(&txn.account_leaf_rlp(address).unwrap().unwrap(), 1);
+        let record = s.out();
+        export.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        export.extend_from_slice(&record);
+    }
+    drop(txn);
+
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.import_rlp_accounts(export.as_slice()).unwrap(), 100);
+    assert_eq!(txn.state_root().unwrap(), root);
+    assert!(txn
+        .import_rlp_accounts(&export[..export.len() - 1])
+        .is_err());
+    assert_eq!(txn.import_rlp_accounts(&[][..]).unwrap(), 0);
+    drop(txn);
+
+    // Storage is not part of the export
+    let address = get_address_from_index(0);
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+    let mut s = rlp::RlpStream::new_list(2);
+    s.append(&keccak256(address));
+    s.append_raw(&txn.account_leaf_rlp(address).unwrap().unwrap(), 1);
+    let record = s.out();
+    let mut export = (record.len() as u32).to_be_bytes().to_vec();
+    export.extend_from_slice(&record);
+    assert!(txn.import_rlp_accounts(export.as_slice()).is_err());
+}
+
+#[test]
+fn finalize_block() {
+    let set_block = |txn: &mut MutableTransaction, block: usize| {
+        for i in 0..5 {
+            let address = get_address_from_index(block * 3 + i);
+            txn.set_account(address, Some(Account::eoa(block as u64, U256::from(i + 1))));
+            txn.set_storage(address, H256::from_low_u64_be(block as u64), U256::one())
+                .unwrap();
+        }
+    };
+    let db = Db::memory().unwrap();
+    let mut roots = Vec::new();
+    for block in 0..10 {
+        let mut txn = db.begin_mut().unwrap();
+        set_block(&mut txn, block);
+        roots.push(txn.commit().unwrap());
+    }
+
+    with_temp_db(|db| {
+        let mut txn = db.begin_mut().unwrap();
+        for (block, root) in roots.iter().enumerate() {
+            set_block(&mut txn, block);
+            assert_eq!(txn.finalize_block(Some(*root)).unwrap(), *root);
+        }
+        assert_eq!(txn.commit().unwrap(), roots[9]);
+        assert_eq!(db.begin_mut().unwrap().state_root().unwrap(), roots[9]);
+    });
+
+    with_temp_db(|db| {
+        let mut txn = db.begin_mut().unwrap();
+        for block in 0..5 {
+            set_block(&mut txn, block);
+            txn.finalize_block(None).unwrap();
+        }
+        set_block(&mut txn, 5);
+        let err = txn.finalize_block(Some(roots[4])).unwrap_err();
+        let mismatch = err.downcast_ref::<StateRootMismatch>().unwrap();
+        let mut changed: Vec<_> = (15..20).map(get_address_from_index).collect();
+        changed.sort_by_key(|address| keccak256(address));
+        assert_eq!(mismatch.changed_accounts, changed);
+        txn.rollback().unwrap();
+        let mut txn = db.begin_mut().unwrap();
+        assert_eq!(txn.state_root().unwrap(), keccak256([0x80]));
+        assert_eq!(txn.account_count().unwrap(), 0);
+    });
+}
+
+#[test]
+fn root_node_rlp() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.root_node_rlp().unwrap(), vec![0x80]);
+    assert_eq!(keccak256([0x80]), txn.state_root().unwrap());
+    for i in 0..100 {
+        txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+        if [0, 1, 99].contains(&i) {
+            let node = txn.root_node_rlp().unwrap();
+            assert_eq!(keccak256(&node), txn.state_root().unwrap());
+            // A single account is a leaf, more a branch with 17 items
+            let items = rlp::Rlp::new(&node).item_count().unwrap();
+            assert_eq!(items, if i == 0 { 2 } else { 17 });
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "test-support")]
+fn address_with_internal_prefix() {
+    let prefix = [1, 2, 3, 4];
+    let address = test_support::address_with_internal_prefix(&prefix[..3]);
+    assert!(keccak256(address).as_bytes().starts_with(&[0x12]));
+    let addresses: Vec<H160> = test_support::addresses_with_internal_prefix(&prefix)
+        .take(2)
+        .collect();
+    assert_eq!(
+        addresses[0],
+        test_support::address_with_internal_prefix(&prefix)
+    );
+
+    // Two accounts sharing a prefix make a root extension node over it
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    for address in &addresses {
+        assert!(keccak256(address).as_bytes().starts_with(&[0x12, 0x34]));
+        txn.set_account(*address, ACCOUNT1.clone());
+    }
+    let node = txn.root_node_rlp().unwrap();
+    let node = rlp::Rlp::new(&node);
+    assert_eq!(node.item_count().unwrap(), 2);
+    let path = node.at(0).unwrap().data().unwrap();
+    assert!(path.starts_with(&[0x00, 0x12, 0x34]));
+}
+
+#[test]
+fn state_test_json() {
+    let pre = r#"{
+        "0x095e7baea6a6c7c4c2dfeb977efac326af552d87" : {
+            "balance" : "0x0de0b6b3a7640000",
+            "code" : "0x600160010160005500",
+            "nonce" : "0x00",
+            "storage" : {
+            }
+        },
+        "0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b" : {
+            "balance" : "0x0de0b6b3a7640000",
+            "code" : "0x",
+            "nonce" : "0x00",
+            "storage" : {
+            }
+        }
+    }"#;
+    let contract: H160 = "0x095e7baea6a6c7c4c2dfeb977efac326af552d87"
+        .parse()
+        .unwrap();
+    let sender: H160 = "0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b"
+        .parse()
+        .unwrap();
+    let balance = U256::from(10).pow(U256::from(18));
+    let code = [0x60, 0x01, 0x60, 0x01, 0x01, 0x60, 0x00, 0x55, 0x00];
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(contract, Account::new(0, balance, keccak256(code)));
+    trie_contents.insert(sender, Account::eoa(0, balance));
+
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.from_state_test_json(pre).unwrap();
+    assert_eq!(
+        txn.state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+    assert_eq!(
+        txn.code_from_hash(keccak256(code)).unwrap().unwrap(),
+        &code[..]
+    );
+
+    txn.set_storage(contract, H256::zero(), U256::from(2))
+        .unwrap();
+    txn.set_account(sender, Some(Account::eoa(1, U256::from(5))));
+    let json = txn.to_state_test_json().unwrap();
+    let expected = format!(
+        "{{\"{:?}\":{{\"balance\":\"0xde0b6b3a7640000\",\"code\":\"0x600160010160005500\",\
+         \"nonce\":\"0x0\",\"storage\":{{\"{:?}\":\"0x{:064x}\"}}}},\
+         \"{:?}\":{{\"balance\":\"0x5\",\"code\":\"0x\",\"nonce\":\"0x1\",\"storage\":{{}}}}}}",
+        contract,
+        H256::zero(),
+        2,
+        sender
+    );
+    assert_eq!(json, expected);
+
+    let copy = Db::memory().unwrap();
+    let mut copy_txn = copy.begin_mut().unwrap();
+    copy_txn.from_state_test_json(&json).unwrap();
+    assert_eq!(copy_txn.state_root().unwrap(), txn.state_root().unwrap());
+    assert_eq!(copy_txn.to_state_test_json().unwrap(), json);
+    assert!(copy_txn.from_state_test_json("{\"0x00\": {}}").is_err());
+}
+
+#[test]
+fn checked_storage() {
+    let db = Db::memory().unwrap();
+    let address = get_address_from_index(0);
+    let absent = get_address_from_index(1);
+    let (zero_slot, slot) = (H256::from_low_u64_be(1), H256::from_low_u64_be(2));
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_storage(address, slot, U256::from(3)).unwrap();
+    for _ in 0..2 {
+        assert_eq!(txn.checked_storage(absent, slot).unwrap(), None);
+        assert_eq!(txn.storage(absent, slot).unwrap(), U256::zero());
+        assert_eq!(
+            txn.checked_storage(address, zero_slot).unwrap(),
+            Some(U256::zero())
+        );
+        assert_eq!(
+            txn.checked_storage(address, slot).unwrap(),
+            Some(U256::from(3))
+        );
+        txn.commit_and_continue().unwrap();
+    }
+    txn.commit().unwrap();
+
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(txn.checked_storage(absent, slot).unwrap(), None);
+    assert_eq!(
+        txn.checked_storage(address, zero_slot).unwrap(),
+        Some(U256::zero())
+    );
+    assert_eq!(
+        txn.checked_storage(address, slot).unwrap(),
+        Some(U256::from(3))
+    );
+}
+
+#[test]
+fn db_from_iter() {
+    let accounts = |range: std::ops::Range<usize>| {
+        range.map(|i| {
+            (
+                get_address_from_index(i),
+                Account::eoa(i as u64, U256::one()),
+            )
+        })
+    };
+    let mut db: Db = accounts(0..10).collect();
+    db.extend(accounts(10..20));
+    let mut trie_contents: HashMap<Address, Account> = accounts(0..20).collect();
+    assert_eq!(
+        db.begin_mut().unwrap().state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+
+    let db = Db::try_from_iter(accounts(0..5)).unwrap();
+    trie_contents.retain(|address, _| (0..5).any(|i| get_address_from_index(i) == *address));
+    assert_eq!(
+        db.begin_mut().unwrap().state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+}
+
+#[test]
+fn trie_stats() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.trie_stats().unwrap(), TrieStats::default());
+
+    let address1 = get_address_with_prefix_nibbles(&[0, 0, 1, 1, 1, 1]);
+    let address2 = get_address_with_prefix_nibbles(&[0, 0, 1, 1, 1, 2]);
+    txn.set_account(address1, ACCOUNT1.clone());
+    let stats = txn.trie_stats().unwrap();
+    assert_eq!(stats.account_trie.leaves, 1);
+    assert_eq!(stats.account_trie.branches, 0);
+    assert_eq!(stats.account_trie.max_depth, 0);
+
+    txn.set_account(address2, ACCOUNT1.clone());
+    txn.set_storage(address2, H256::zero(), U256::one())
+        .unwrap();
+    txn.set_storage(address2, H256::repeat_byte(1), U256::one())
+        .unwrap();
+    let stats = txn.trie_stats().unwrap();
+    assert_eq!(stats.account_trie.leaves, 2);
+    assert_eq!(stats.account_trie.branches, 1);
+    assert_eq!(stats.account_trie.nodes(), 3);
+    assert_eq!(stats.account_trie.max_depth, 6);
+    assert_eq!(stats.storage_tries.leaves, 2);
+    assert!(stats.storage_tries.branches >= 1);
+
+    txn.destroy_storage(address2).unwrap();
+    let stats = txn.trie_stats().unwrap();
+    assert_eq!(stats.storage_tries, NodeCounts::default());
+}
+
+#[test]
+fn max_code_size() {
+    let code = vec![0x60; MAX_CODE_SIZE + 1];
+
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.store_code(&code).unwrap();
+
+    let db = Db::memory_with_options(DbOptions {
+        max_code_size: Some(MAX_CODE_SIZE),
+        ..DbOptions::default()
+    })
+    .unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let code_hash = txn.store_code(&code[..MAX_CODE_SIZE]).unwrap();
+    assert_eq!(
+        txn.code_from_hash(code_hash).unwrap().unwrap(),
+        &code[..MAX_CODE_SIZE]
+    );
+    assert!(txn.store_code(&code).is_err());
+    assert_eq!(txn.code_from_hash(keccak256(&code)).unwrap(), None);
+}
+
+#[test]
+fn state_root_detailed() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let summary = txn.state_root_detailed().unwrap();
+    assert_eq!(summary.root, check_trie::calc_root(&HashMap::new()));
+    assert_eq!(summary.accounts_flushed, 0);
+
+    for i in 0..10 {
+        txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+    }
+    txn.set_storage(get_address_from_index(0), H256::zero(), U256::one())
+        .unwrap();
+    let summary = txn.state_root_detailed().unwrap();
+    assert_eq!(summary.root, txn.state_root().unwrap());
+    assert_eq!(summary.accounts_flushed, 10);
+    assert_eq!(summary.storage_tries_touched, 1);
+    assert!(summary.nodes_written > 10);
+
+    let unchanged = txn.state_root_detailed().unwrap();
+    assert_eq!(unchanged.root, summary.root);
+    assert_eq!((unchanged.nodes_written, unchanged.nodes_deleted), (0, 0));
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.destroy_storage(get_address_from_index(0)).unwrap();
+    for i in 0..10 {
+        txn.set_account(get_address_from_index(i), None);
+    }
+    let summary = txn.state_root_detailed().unwrap();
+    assert_eq!(summary.root, check_trie::calc_root(&HashMap::new()));
+    assert_eq!(summary.accounts_flushed, 10);
+    assert_eq!(summary.storage_tries_touched, 1);
+    assert_eq!(summary.nodes_written, 0);
+    assert!(summary.nodes_deleted > 10);
+}
+
+fn do_destroy_storage_in_batches_test(db: &mut Db) {
+    let address = get_address_from_index(0);
+    let other = get_address_from_index(1);
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(address, ACCOUNT1.clone().unwrap());
+    trie_contents.insert(other, ACCOUNT1.clone().unwrap());
+    let mut storage = HashMap::new();
+    storage.insert(other, HashMap::new());
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_account(other, ACCOUNT1.clone());
+    for i in 0..2000 {
+        txn.set_storage(address, H256::from_low_u64_be(i), U256::one())
+            .unwrap();
+    }
+    txn.set_storage(other, H256::zero(), U256::from(2)).unwrap();
+    storage
+        .get_mut(&other)
+        .unwrap()
+        .insert(H256::zero(), U256::from(2));
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.destroy_storage_in_batches(address, 100).unwrap();
+    for i in (0..2000).step_by(97) {
+        assert_eq!(
+            txn.storage(address, H256::from_low_u64_be(i)).unwrap(),
+            U256::zero()
+        );
+    }
+    assert_eq!(txn.storage(other, H256::zero()).unwrap(), U256::from(2));
+    assert_eq!(
+        txn.commit().unwrap(),
+        check_trie::calc_root_with_storage(&trie_contents, &storage)
+    );
+}
+
+#[test]
+fn modify_account() {
+    let db = Db::memory().unwrap();
+    let address = get_address_from_index(0);
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, Some(Account::eoa(1, U256::from(100))));
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    let modified = txn
+        .modify_account(address, |account| {
+            account.nonce += 1;
+            account.balance -= U256::from(30);
+        })
+        .unwrap();
+    assert!(modified);
+    // A pending account is modified in place
+    assert!(txn
+        .modify_account(address, |account| account.nonce += 1)
+        .unwrap());
+    let missing = get_address_from_index(1);
+    assert!(!txn.modify_account(missing, |_| unreachable!()).unwrap());
+    assert_eq!(txn.try_account(missing).unwrap(), None);
+    txn.set_account(missing, None);
+    assert!(!txn.modify_account(missing, |_| unreachable!()).unwrap());
+    let root = txn.commit().unwrap();
+
+    let expected = Some(Account::eoa(3, U256::from(70)));
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(address, expected.clone().unwrap());
+    assert_eq!(root, check_trie::calc_root(&trie_contents));
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(txn.try_account(address).unwrap(), expected);
+}
+
+#[test]
+fn update_storage() {
+    let db = Db::memory().unwrap();
+    let address = get_address_from_index(0);
+    let (slot, other) = (H256::zero(), H256::repeat_byte(1));
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    assert_eq!(
+        txn.update_storage(address, slot, U256::one()).unwrap(),
+        U256::zero()
+    );
+    assert_eq!(
+        txn.update_storage(address, other, U256::from(5)).unwrap(),
+        U256::zero()
+    );
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(
+        txn.update_storage(address, slot, U256::from(2)).unwrap(),
+        U256::one()
+    );
+    assert_eq!(
+        txn.update_storage(address, slot, U256::from(3)).unwrap(),
+        U256::from(2)
+    );
+    txn.destroy_storage(address).unwrap();
+    assert_eq!(
+        txn.update_storage(address, slot, U256::from(4)).unwrap(),
+        U256::zero()
+    );
+    assert_eq!(
+        txn.update_storage(address, other, U256::from(6)).unwrap(),
+        U256::zero()
+    );
+    assert_eq!(txn.storage(address, slot).unwrap(), U256::from(4));
+    assert!(txn
+        .update_storage(get_address_from_index(1), slot, U256::one())
+        .is_err());
+}
+
+#[test]
+fn destroy_storage_idempotent() {
+    let db = Db::memory().unwrap();
+    let address = get_address_from_index(0);
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+    let root = txn.commit().unwrap();
+
+    // Never an account, nothing is queued
+    let mut txn = db.begin_mut().unwrap();
+    let missing = get_address_from_index(1);
+    txn.destroy_storage(missing).unwrap();
+    txn.destroy_storage(missing).unwrap();
+    assert_eq!(txn.drain_pending().unwrap().count(), 0);
+    let summary = txn.state_root_detailed().unwrap();
+    assert_eq!(summary.root, root);
+    assert_eq!(summary.accounts_flushed, 0);
+
+    // Created then destroyed in the same block
+    txn.set_account(missing, ACCOUNT2.clone());
+    txn.set_storage(missing, H256::zero(), U256::one()).unwrap();
+    txn.destroy_storage(missing).unwrap();
+    txn.destroy_storage(missing).unwrap();
+    txn.set_account(missing, None);
+    assert_eq!(txn.state_root().unwrap(), root);
+
+    drop(txn);
+
+    // Destroying twice is the same as once
+    let mut txn = db.begin_mut().unwrap();
+    txn.destroy_storage(address).unwrap();
+    let once: Vec<_> = txn.drain_pending().unwrap().collect();
+    drop(txn);
+    let mut txn = db.begin_mut().unwrap();
+    txn.destroy_storage(address).unwrap();
+    txn.destroy_storage(address).unwrap();
+    let root = txn.state_root().unwrap();
+    assert_eq!(txn.storage(address, H256::zero()).unwrap(), U256::zero());
+    assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+    drop(txn);
+    let mut txn = db.begin_mut().unwrap();
+    txn.apply_changes(once).unwrap();
+    assert_eq!(txn.state_root().unwrap(), root);
+}
+
+#[test]
+fn destroy_storage_in_batches() {
+    with_memory(do_destroy_storage_in_batches_test);
+    with_temp_db(do_destroy_storage_in_batches_test);
+}
+
+#[test]
+fn destroy_storage_in_batches_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let address = get_address_from_index(0);
+    let other = get_address_from_index(1);
+    let fill = |db: &Db| {
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_account(address, ACCOUNT1.clone());
+        for i in 0..2000 {
+            txn.set_storage(address, H256::from_low_u64_be(i), U256::one())
+                .unwrap();
+        }
+        txn.commit().unwrap();
+    };
+    let mut trie_contents = HashMap::new();
+    trie_contents.insert(address, ACCOUNT1.clone().unwrap());
+    let expected = check_trie::calc_root(&trie_contents);
+
+    let db = Db::file(dir.path()).unwrap();
+    fill(&db);
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(other, ACCOUNT1.clone());
+    assert!(txn.destroy_storage_in_batches(address, 100).is_err());
+    txn.rollback().unwrap();
+
+    // Only the last batch is left to the final commit
+    let mut txn = db.begin_mut().unwrap();
+    txn.destroy_storage_in_batches(address, 100).unwrap();
+    let (root, report) = txn.commit_reported().unwrap();
+    assert_eq!(root, expected);
+    assert!(report.keys_deleted <= 110, "{:?}", report);
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(txn.metadata(b"destroying_storage").unwrap(), None);
+    drop(txn);
+
+    // Storage left half destroyed is finished when the database is opened
+    fill(&db);
+    let mut txn = db.begin_mut().unwrap();
+    txn.destroy_storage_in_batches(address, 100).unwrap();
+    txn.rollback().unwrap();
+    let txn = db.begin_ro().unwrap();
+    assert!(txn.metadata(b"destroying_storage").unwrap().is_some());
+    assert!(txn
+        .storage(address, H256::from_low_u64_be(1999))
+        .unwrap()
+        .is_zero());
+    drop(txn);
+    let mut txn = db.begin_mut().unwrap();
+    assert!(txn.destroy_storage_in_batches(other, 100).is_err());
+    txn.rollback().unwrap();
+    drop(db);
+
+    let db = Db::file(dir.path()).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.state_root().unwrap(), expected);
+    assert_eq!(txn.metadata(b"destroying_storage").unwrap(), None);
+    for i in (0..2000).step_by(97) {
+        assert!(txn
+            .storage(address, H256::from_low_u64_be(i))
+            .unwrap()
+            .is_zero());
+    }
+}
+
+#[test]
+fn with_account() {
+    let db = Db::memory().unwrap();
+    let address = get_address_from_index(0);
+    let mut txn = db.begin_mut().unwrap();
+    assert!(txn
+        .with_account(address, |account| account.is_none())
+        .unwrap());
+    txn.set_account(address, ACCOUNT1.clone());
+    assert_eq!(
+        txn.with_account(address, |account| account.unwrap().nonce)
+            .unwrap(),
+        1
+    );
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(
+        txn.with_account(address, |account| account.cloned())
+            .unwrap(),
+        *ACCOUNT1
+    );
+    txn.set_account(address, None);
+    assert!(txn
+        .with_account(address, |account| account.is_none())
+        .unwrap());
+}
+
+#[test]
+fn compact() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let mut trie_contents = HashMap::new();
+    {
+        let db = Db::file(dir.path()).unwrap();
+        let mut txn = db.begin_mut().unwrap();
+        for i in 0..1000 {
+            txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+        }
+        txn.commit().unwrap();
+
+        let mut txn = db.begin_mut().unwrap();
+        for i in 0..1000 {
+            let address = get_address_from_index(i);
+            if i % 10 == 0 {
+                trie_contents.insert(address, ACCOUNT1.clone().unwrap());
+            } else {
+                txn.set_account(address, None);
+            }
+        }
+        txn.commit().unwrap();
+
+        db.compact(&target.path().join("db")).unwrap();
+        assert!(db.compact(&target.path().join("db")).is_err());
+    }
+
+    let db = Db::open_existing(&target.path().join("db")).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(
+        txn.state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+    assert_eq!(
+        txn.try_account(get_address_from_index(10)).unwrap(),
+        *ACCOUNT1
+    );
+
+    Db::memory()
+        .unwrap()
+        .compact(&target.path().join("memory"))
+        .unwrap();
+}
+
+#[test]
+fn checkpoint() {
+    let dir = tempfile::tempdir().unwrap();
+    let crashed = tempfile::tempdir().unwrap();
+    let db = Db::file(dir.path()).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..100 {
+        txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+    }
+    let state_root = txn.commit().unwrap();
+    db.checkpoint().unwrap();
+
+    // The data file as a crash would leave it, with the database still open
+    std::fs::copy(dir.path().join("mdbx.dat"), crashed.path().join("mdbx.dat")).unwrap();
+    let recovered = Db::file(crashed.path()).unwrap();
+    assert_eq!(
+        recovered.begin_mut().unwrap().state_root().unwrap(),
+        state_root
+    );
+    drop(db);
+}
+
+#[test]
+fn space_report() {
+    let fill = |db: &Db| {
+        let mut txn = db.begin_mut().unwrap();
+        let code_hash = txn.store_code(&[0x60; 100]).unwrap();
+        for i in 0..10 {
+            let address = get_address_from_index(i);
+            txn.set_account(address, Some(Account::new(1, U256::one(), code_hash)));
+            txn.set_storage(address, H256::zero(), U256::from(i + 1))
+                .unwrap();
+        }
+        txn.commit().unwrap();
+    };
+    let db = Db::memory().unwrap();
+    assert_eq!(db.space_report().unwrap(), SpaceReport::default());
+    fill(&db);
+    let report = db.space_report().unwrap();
+    assert_eq!(report.code_bytes, 100);
+    assert_eq!(report.storage_bytes, 10);
+    assert!(report.account_bytes > 0);
+    assert!(report.trie_node_bytes > 0);
+    assert_eq!(report.physical_bytes, 0);
+
+    with_temp_db(|db| {
+        fill(db);
+        let on_disk = db.space_report().unwrap();
+        assert_eq!(
+            SpaceReport {
+                physical_bytes: 0,
+                ..on_disk
+            },
+            report
+        );
+        assert!(on_disk.physical_bytes > 0);
+
+        // A snapshot read, pending writes are neither waited for nor seen
+        let mut txn = db.begin_mut().unwrap();
+        txn.store_code(&[0x61; 50]).unwrap();
+        txn.state_root().unwrap();
+        assert_eq!(db.space_report().unwrap().code_bytes, 100);
+        txn.commit().unwrap();
+        assert_eq!(db.space_report().unwrap().code_bytes, 150);
+    });
+}
+
+#[test]
+fn map_full() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        max_size: 1 << 22,
+        ..DbOptions::default()
+    };
+    let (db, _) = Db::open_with_options(dir.path(), options).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..1000 {
+        txn.store_code(&[i as u8; 8192][..8192 - i]).unwrap();
+    }
+    let err = txn.commit().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DbError>(),
+        Some(DbError::MapFull)
+    ));
+    assert!(err.to_string().contains("max_size"));
+}
+
+#[test]
+fn failed_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        max_size: 1 << 22,
+        ..DbOptions::default()
+    };
+    let address = get_address_from_index(0);
+    let state_root = {
+        let (db, _) = Db::open_with_options(dir.path(), options).unwrap();
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_account(address, ACCOUNT1.clone());
+        let state_root = txn.commit().unwrap();
+
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_account(address, ACCOUNT2.clone());
+        let code_hashes: Vec<H256> = (0..1000)
+            .map(|i| txn.store_code(&[i as u8; 8192][..8192 - i]).unwrap())
+            .collect();
+        assert!(txn.try_commit().is_err());
+
+        // `try_commit` keeps the pending writes, `commit` discards them and
+        // nothing of the failed commit is seen or committed by the next
+        // transaction
+        assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT2);
+        for (i, code_hash) in code_hashes.iter().enumerate().step_by(99) {
+            assert_eq!(
+                txn.code_from_hash(*code_hash).unwrap().unwrap(),
+                &[i as u8; 8192][..8192 - i]
+            );
+        }
+        assert!(txn.commit().is_err());
+
+        let mut txn = db.begin_mut().unwrap();
+        assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+        assert_eq!(txn.code_from_hash(code_hashes[0]).unwrap(), None);
+        txn.set_metadata(b"key", b"value").unwrap();
+        assert_eq!(txn.commit().unwrap(), state_root);
+        state_root
+    };
+
+    let db = Db::open_existing(dir.path()).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+    assert_eq!(txn.state_root().unwrap(), state_root);
+}
+
+#[test]
+fn auto_flush_bytes() {
+    const LIMIT: usize = 1 << 16;
+    let import = |txn: &mut MutableTransaction, max_pending: &mut usize| {
+        for i in 0..2000 {
+            let address = get_address_from_index(i);
+            txn.set_account(address, ACCOUNT1.clone());
+            txn.set_storage(address, H256::zero(), U256::from(i + 1))
+                .unwrap();
+            txn.store_code(&i.to_be_bytes().repeat(32)).unwrap();
+            *max_pending = (*max_pending).max(txn.pending_bytes());
+            if i % 500 == 499 {
+                txn.state_root().unwrap();
+                *max_pending = (*max_pending).max(txn.pending_bytes());
+            }
+        }
+        txn.state_root().unwrap()
+    };
+    let db = Db::memory().unwrap();
+    let expected = import(&mut db.begin_mut().unwrap(), &mut 0);
+
+    let dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        auto_flush_bytes: Some(LIMIT),
+        ..DbOptions::default()
+    };
+    let (db, _) = Db::open_with_options(dir.path(), options).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let mut max_pending = 0;
+    assert_eq!(import(&mut txn, &mut max_pending), expected);
+    // Code is the largest write
+    assert!(max_pending <= LIMIT + 1024);
+    assert_eq!(
+        txn.storage(get_address_from_index(0), H256::zero())
+            .unwrap(),
+        U256::one()
+    );
+    assert_eq!(txn.commit().unwrap(), expected);
+
+    let dir = tempfile::tempdir().unwrap();
+    let db = Db::file(dir.path()).unwrap();
+    let mut max_pending = 0;
+    import(&mut db.begin_mut().unwrap(), &mut max_pending);
+    assert!(max_pending > LIMIT);
+}
+
+#[test]
+fn auto_grow() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        max_size: 1 << 22,
+        auto_grow: true,
+        ..DbOptions::default()
+    };
+    let mut code_hashes = Vec::new();
+    let mut trie_contents = HashMap::new();
+    {
+        let (db, _) = Db::open_with_options(dir.path(), options).unwrap();
+        let mut txn = db.begin_mut().unwrap();
+        for i in 0..200 {
+            let address = get_address_from_index(i);
+            txn.set_account(address, ACCOUNT1.clone());
+            trie_contents.insert(address, ACCOUNT1.clone().unwrap());
+        }
+        txn.commit_and_continue().unwrap();
+        txn.destroy_storage_in_batches(get_address_from_index(0), 10)
+            .unwrap();
+        for i in 0..1000 {
+            code_hashes.push(txn.store_code(&[i as u8; 8192][..8192 - i]).unwrap());
+            if i % 100 == 0 {
+                txn.state_root().unwrap();
+            }
+        }
+        txn.commit().unwrap();
+    }
+
+    let db = Db::open_existing(dir.path()).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    for (i, code_hash) in code_hashes.into_iter().enumerate() {
+        assert_eq!(
+            txn.code_from_hash(code_hash).unwrap().unwrap().len(),
+            8192 - i
+        );
+    }
+    assert_eq!(
+        txn.state_root().unwrap(),
+        check_trie::calc_root(&trie_contents)
+    );
+}
+
+#[test]
+fn preimages() {
+    let address = get_address_from_index(0);
+    let slot = H256::repeat_byte(0x11);
+
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_storage(address, slot, U256::one()).unwrap();
+    txn.state_root().unwrap();
+    assert_eq!(txn.preimage(keccak256(address)).unwrap(), None);
+
+    let db = Db::memory_with_options(DbOptions {
+        record_preimages: true,
+        ..DbOptions::default()
+    })
+    .unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_storage(address, slot, U256::one()).unwrap();
+    txn.commit().unwrap();
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(
+        txn.preimage(keccak256(address)).unwrap().unwrap(),
+        address.as_bytes()
+    );
+    assert_eq!(
+        txn.preimage(keccak256(slot)).unwrap().unwrap(),
+        slot.as_bytes()
+    );
+    assert_eq!(txn.preimage(H256::zero()).unwrap(), None);
+}
+
+#[test]
+fn memory_shared() {
+    let db = Db::memory_shared().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..100 {
+        txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+        txn.set_storage(get_address_from_index(i), H256::zero(), U256::from(i))
+            .unwrap();
+    }
+    txn.commit().unwrap();
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                let txn = db.begin_ro().unwrap();
+                for i in 0..100 {
+                    let address = get_address_from_index(i);
+                    assert_eq!(txn.try_account(address).unwrap(), ACCOUNT1.clone());
+                    assert_eq!(txn.storage(address, H256::zero()).unwrap(), U256::from(i));
+                }
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(get_address_from_index(0), None);
+    txn.commit().unwrap();
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(txn.try_account(get_address_from_index(0)).unwrap(), None);
+}
+
+fn do_raw_test(db: &mut Db) {
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(get_address_from_index(0), ACCOUNT1.clone());
+    let root = txn.state_root().unwrap();
+    txn.raw_put(0, b"key", b"value").unwrap();
+    txn.raw_put(1, b"key", b"other").unwrap();
+    txn.raw_put(0, b"gone", b"value").unwrap();
+    txn.raw_delete(0, b"gone").unwrap();
+    assert!(txn.raw_put(0, &[0; MAX_RAW_KEY_LEN + 1], b"value").is_err());
+    assert_eq!(txn.state_root().unwrap(), root);
+    assert_eq!(txn.metadata(b"key").unwrap(), None);
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.raw_put(0, b"key", b"rolled back").unwrap();
+    txn.rollback().unwrap();
+
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(*txn.raw_get(0, b"key").unwrap().unwrap(), *b"value");
+    assert_eq!(*txn.raw_get(1, b"key").unwrap().unwrap(), *b"other");
+    assert_eq!(txn.raw_get(0, b"gone").unwrap(), None);
+    assert_eq!(txn.raw_get(2, b"key").unwrap(), None);
+}
+
+fn do_clear_raw_namespace_test(db: &mut Db) {
+    let address = get_address_from_index(0);
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+    for namespace in 0..5 {
+        txn.raw_put(namespace, b"", b"empty").unwrap();
+        txn.raw_put(namespace, b"key", b"value").unwrap();
+    }
+    let root = txn.commit().unwrap();
+
+    // The namespaces sharing a value with the table prefixes of the state
+    // are raw keys like any other
+    let mut txn = db.begin_mut().unwrap();
+    txn.raw_put(1, b"pending", b"value").unwrap();
+    for namespace in 1..4 {
+        txn.clear_raw_namespace(namespace).unwrap();
+    }
+    assert_eq!(txn.raw_get(1, b"pending").unwrap(), None);
+    assert_eq!(txn.state_root().unwrap(), root);
+    txn.commit().unwrap();
+
+    let txn = db.begin_ro().unwrap();
+    for namespace in 0..5 {
+        let cleared = (1..4).contains(&namespace);
+        assert_eq!(txn.raw_get(namespace, b"").unwrap().is_none(), cleared);
+        assert_eq!(txn.raw_get(namespace, b"key").unwrap().is_none(), cleared);
+    }
+    assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+    assert_eq!(txn.storage(address, H256::zero()).unwrap(), U256::one());
+}
+
+#[test]
+fn clear_raw_namespace() {
+    with_memory(do_clear_raw_namespace_test);
+    with_temp_db(do_clear_raw_namespace_test);
+}
+
+fn populate_for_clear_all(db: &Db, seed: usize) -> H256 {
+    let mut txn = db.begin_mut().unwrap();
+    for i in seed..seed + 20 {
+        let address = get_address_from_index(i);
+        let code_hash = txn.store_code(&i.to_be_bytes()).unwrap();
+        txn.set_account(address, Some(Account::new(1, U256::from(i), code_hash)));
+        txn.set_storage(address, H256::from_low_u64_be(i as u64), U256::one())
+            .unwrap();
+    }
+    txn.commit().unwrap()
+}
+
+// `fresh` is an empty database with the same options as `db`
+fn do_clear_all_test(db: &mut Db, fresh: &Db) {
+    populate_for_clear_all(db, 0);
+    let mut txn = db.begin_mut().unwrap();
+    txn.raw_put(0, b"key", b"value").unwrap();
+    txn.set_account(get_address_from_index(100), ACCOUNT1.clone());
+    txn.clear_all().unwrap();
+    assert_eq!(txn.try_account(get_address_from_index(0)).unwrap(), None);
+    assert_eq!(txn.state_root().unwrap(), *walk::EMPTY_TRIE_ROOT);
+    assert_eq!(txn.commit().unwrap(), *walk::EMPTY_TRIE_ROOT);
+
+    let report = db.space_report().unwrap();
+    assert_eq!(report.account_bytes + report.storage_bytes, 0);
+    assert_eq!(report.code_bytes + report.trie_node_bytes, 0);
+    assert_eq!(db.version().unwrap(), CURRENT_VERSION);
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(
+        txn.raw_get(0, b"key").unwrap().as_deref(),
+        Some(&b"value"[..])
+    );
+    assert_eq!(
+        txn.preimage(keccak256(get_address_from_index(0))).unwrap(),
+        None
+    );
+    assert_eq!(txn.state_root().unwrap(), *walk::EMPTY_TRIE_ROOT);
+    txn.rollback().unwrap();
+
+    // The cleared database behaves as a fresh one
+    assert_eq!(
+        populate_for_clear_all(db, 10),
+        populate_for_clear_all(fresh, 10)
+    );
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(txn.try_account(get_address_from_index(5)).unwrap(), None);
+    assert_eq!(
+        txn.preimage(keccak256(get_address_from_index(5))).unwrap(),
+        None
+    );
+    let address = get_address_from_index(15);
+    assert!(txn.try_account(address).unwrap().is_some());
+    assert_eq!(
+        txn.preimage(keccak256(address)).unwrap().as_deref(),
+        Some(address.as_bytes())
+    );
+}
+
+// Every entry of a file database but the raw keys
+fn dump_tables(path: &std::path::Path) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let backend = backend::Backend::file(path, &backend::FileOptions::default()).unwrap();
+    let tx = backend.begin_mut().unwrap();
+    let entries = backend::Table::ALL
+        .iter()
+        .filter(|table| **table != backend::Table::Raw)
+        .flat_map(|table| tx.iter_prefix(&[table.prefix()]).collect::<Vec<_>>())
+        .collect::<anyhow::Result<_>>()
+        .unwrap();
+    tx.rollback().unwrap();
+    entries
+}
+
+#[test]
+fn clear_all() {
+    let options = || DbOptions {
+        record_preimages: true,
+        ..DbOptions::default()
+    };
+    // Only the backend writes hash keyed nodes
+    let node = (keys::node_key(H256::repeat_byte(1)), b"node".to_vec());
+    let mut db = Db::memory_from_with_options([node.clone()], options()).unwrap();
+    do_clear_all_test(&mut db, &Db::memory_with_options(options()).unwrap());
+
+    let dir = tempfile::tempdir().unwrap();
+    let fresh_dir = tempfile::tempdir().unwrap();
+    {
+        let backend = backend::Backend::file(dir.path(), &backend::FileOptions::default()).unwrap();
+        let mut tx = backend.begin_mut().unwrap();
+        tx.put(&node.0, &node.1).unwrap();
+        tx.commit().unwrap();
+    }
+    {
+        let (mut db, _) = Db::open_with_options(dir.path(), options()).unwrap();
+        let (fresh, _) = Db::open_with_options(fresh_dir.path(), options()).unwrap();
+        do_clear_all_test(&mut db, &fresh);
+    }
+    assert_eq!(dump_tables(dir.path()), dump_tables(fresh_dir.path()));
+}
+
+#[test]
+fn account_at_path() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.account_at_path(&[]).unwrap(), None);
+    let address = get_address_from_index(0);
+    txn.set_account(address, ACCOUNT1.clone());
+    let hash = keccak256(address);
+    let nibbles: Vec<u8> = hash
+        .as_bytes()
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0F])
+        .collect();
+    let leaf = txn.account_leaf_rlp(address).unwrap().unwrap();
+    // A lone account is the root, found by any prefix of its key
+    for len in [0, 1, 64] {
+        assert_eq!(
+            txn.account_at_path(&nibbles[..len]).unwrap(),
+            Some((hash, leaf.clone()))
+        );
+    }
+    let mut other = nibbles.clone();
+    other[1] ^= 1;
+    assert_eq!(txn.account_at_path(&other[..2]).unwrap(), None);
+
+    for i in 1..100 {
+        txn.set_account(get_address_from_index(i), ACCOUNT2.clone());
+    }
+    // The root is now a branch
+    assert_eq!(txn.account_at_path(&[]).unwrap(), None);
+    assert_eq!(
+        txn.account_at_path(&nibbles).unwrap(),
+        Some((hash, leaf.clone()))
+    );
+    assert!(txn.account_at_path(&[16]).is_err());
+}
+
+#[test]
+fn debug() {
+    #[derive(Debug)]
+    struct Node {
+        db: Db,
+    }
+
+    let node = Node {
+        db: Db::memory().unwrap(),
+    };
+    assert!(format!("{:?}", node).contains("kind: \"memory\", path: None"));
+    let mut txn = node.db.begin_mut().unwrap();
+    txn.set_account(get_address_from_index(0), ACCOUNT1.clone());
+    txn.set_storage(get_address_from_index(0), H256::zero(), U256::one())
+        .unwrap();
+    let printed = format!("{:?}", txn);
+    assert!(printed.starts_with("MutableTransaction { dirty_accounts: 1, storage_tries: 1,"));
+    assert!(!printed.contains("Account"));
+    drop(txn);
+
+    let dir = tempfile::tempdir().unwrap();
+    let db = Db::file(dir.path()).unwrap();
+    let printed = format!("{:?}", db);
+    assert!(printed.contains("kind: \"file\""));
+    assert!(printed.contains(&format!("{:?}", dir.path())));
+}
+
+fn precompute_block(db: &Db, precompute: bool) -> H256 {
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..10 {
+        let address = get_address_from_index(i);
+        txn.set_account(address, ACCOUNT1.clone());
+        for slot in 0..20 {
+            txn.set_storage(address, H256::from_low_u64_be(slot), U256::from(i + 1))
+                .unwrap();
+        }
+        if precompute {
+            txn.storage_root(&address).unwrap();
+        }
+    }
+    // Changed again after its root was precomputed
+    let address = get_address_from_index(3);
+    txn.set_storage(address, H256::zero(), U256::zero())
+        .unwrap();
+    txn.commit().unwrap()
+}
+
+#[test]
+fn precomputed_storage_roots() {
+    let db = Db::memory().unwrap();
+    let root = precompute_block(&db, false);
+    let mut txn = db.begin_mut().unwrap();
+    let address = get_address_from_index(5);
+    txn.storage_root(&address).unwrap();
+    txn.set_account(address, ACCOUNT2.clone());
+    let expected = txn.state_root().unwrap();
+    txn.rollback().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT2.clone());
+    assert_eq!(txn.state_root().unwrap(), expected);
+    drop(txn);
+
+    with_temp_db(|db| {
+        assert_eq!(precompute_block(db, true), root);
+    });
+}
+
+#[test]
+fn trie_path() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let address1 = get_address_with_prefix_nibbles(&[0, 0, 1, 1, 1, 1]);
+    assert_eq!(txn.trie_path(address1).unwrap(), []);
+    txn.set_account(address1, ACCOUNT1.clone());
+    txn.set_account(
+        get_address_with_prefix_nibbles(&[0, 0, 1, 1, 1, 2]),
+        ACCOUNT1.clone(),
+    );
+    txn.set_account(
+        get_address_with_prefix_nibbles(&[1, 0, 0, 0, 0, 0]),
+        ACCOUNT1.clone(),
+    );
+    let nibbles: Vec<u8> = keccak256(address1)
+        .as_bytes()
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0F])
+        .collect();
+    // The root branch, then a branch with an extension shared by the first
+    // two accounts
+    assert_eq!(
+        txn.trie_path(address1).unwrap(),
+        [
+            TrieStep::Branch {
+                extension_nibbles: vec![],
+                index: Some(0),
+            },
+            TrieStep::Branch {
+                extension_nibbles: vec![0, 1, 1, 1],
+                index: Some(1),
+            },
+            TrieStep::Leaf {
+                rest_of_key: nibbles[6..].to_vec(),
+            },
+        ]
+    );
+    let path = txn
+        .trie_path(get_address_with_prefix_nibbles(&[0, 0, 1, 2, 0, 0]))
+        .unwrap();
+    assert_eq!(
+        path[1],
+        TrieStep::Branch {
+            extension_nibbles: vec![0, 1, 1, 1],
+            index: None,
+        }
+    );
+    assert_eq!(path.len(), 2);
+    let path = txn
+        .trie_path(get_address_with_prefix_nibbles(&[2, 0, 0, 0, 0, 0]))
+        .unwrap();
+    assert_eq!(path.len(), 1);
+}
+
+#[test]
+fn delete() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    Db::file(&path).unwrap();
+    std::fs::write(path.join("mdbx.tmp"), b"").unwrap();
+    let err = Db::delete(&path).unwrap_err();
+    assert!(err.to_string().starts_with("Unexpected file in DB"));
+    Db::delete_permissive(&path).unwrap();
+    assert!(!path.exists());
+    Db::delete_permissive(&path).unwrap();
+
+    // Directories that are not databases are left alone
+    std::fs::create_dir(&path).unwrap();
+    std::fs::write(path.join("notes.txt"), b"").unwrap();
+    assert!(Db::delete_permissive(&path).is_err());
+    std::fs::write(path.join("mdbx.dat"), b"").unwrap();
+    std::fs::create_dir(path.join("nested")).unwrap();
+    assert!(Db::delete_permissive(&path).is_err());
+    assert!(path.join("notes.txt").exists());
+
+    Db::file(&dir.path().join("clean")).unwrap();
+    Db::delete(&dir.path().join("clean")).unwrap();
+    assert!(!dir.path().join("clean").exists());
+}
+
+#[test]
+fn has_pending_changes() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert!(!txn.has_pending_changes());
+    let address = get_address_from_index(0);
+    txn.set_account(address, ACCOUNT1.clone());
+    assert!(txn.has_pending_changes());
+    txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+    txn.state_root().unwrap();
+    assert!(!txn.has_pending_changes());
+
+    txn.destroy_storage(address).unwrap();
+    assert!(txn.has_pending_changes());
+    txn.state_root().unwrap();
+    assert!(!txn.has_pending_changes());
+
+    // Re-setting the stored value is pending, though the state is unchanged
+    txn.set_account(address, ACCOUNT1.clone());
+    assert!(txn.has_pending_changes());
+    assert!(!txn.state_changed().unwrap());
+    assert!(!txn.has_pending_changes());
+
+    txn.set_account_by_hash(H256::zero(), ACCOUNT1.clone());
+    assert!(txn.has_pending_changes());
+    txn.commit().unwrap();
+    assert!(!db.begin_mut().unwrap().has_pending_changes());
+}
+
+#[test]
+fn storage_root_reused_within_transaction() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let contract = get_address_from_index(0);
+    txn.set_account(contract, ACCOUNT1.clone());
+    for slot in 0..10 {
+        txn.set_storage(contract, H256::from_low_u64_be(slot), U256::one())
+            .unwrap();
+    }
+    let summary = txn.state_root_detailed().unwrap();
+    assert_eq!(summary.storage_tries_walked, 1);
+
+    // A balance update reuses the storage root from the last flush
+    txn.set_account(contract, ACCOUNT2.clone());
+    let summary = txn.state_root_detailed().unwrap();
+    assert_eq!(summary.accounts_flushed, 1);
+    assert_eq!(summary.storage_tries_walked, 0);
+    let root = txn.commit_and_continue().unwrap();
+    assert_eq!(root, summary.root);
+
+    txn.set_storage(contract, H256::zero(), U256::from(2))
+        .unwrap();
+    assert_eq!(txn.state_root_detailed().unwrap().storage_tries_walked, 1);
+    txn.commit_and_continue().unwrap();
+    txn.destroy_storage_in_batches(contract, 3).unwrap();
+    txn.set_account(contract, ACCOUNT1.clone());
+    let summary = txn.state_root_detailed().unwrap();
+    assert_eq!(summary.storage_tries_walked, 0);
+    let root = txn.commit().unwrap();
+
+    let db2 = Db::memory().unwrap();
+    let mut txn = db2.begin_mut().unwrap();
+    txn.set_account(contract, ACCOUNT1.clone());
+    assert_eq!(txn.commit().unwrap(), root);
+}
+
+#[test]
+fn storage_root_after_storage_by_hash() {
+    let contract = get_address_from_index(0);
+    let addr_hash = keccak256(contract);
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(contract, ACCOUNT1.clone());
+    txn.set_storage(contract, H256::zero(), U256::one())
+        .unwrap();
+    txn.commit_and_continue().unwrap();
+
+    // The storage root is known from here on
+    txn.set_account(contract, ACCOUNT2.clone());
+    txn.state_root().unwrap();
+    txn.set_account_by_hash(addr_hash, ACCOUNT2.clone());
+    txn.set_storage_by_hash(
+        addr_hash,
+        keccak256(H256::from_low_u64_be(1)),
+        U256::from(2),
+    )
+    .unwrap();
+    txn.state_root().unwrap();
+    txn.set_account(contract, ACCOUNT1.clone());
+    let root = txn.commit().unwrap();
+
+    let db2 = Db::memory().unwrap();
+    let mut txn = db2.begin_mut().unwrap();
+    txn.set_account(contract, ACCOUNT1.clone());
+    txn.set_storage(contract, H256::zero(), U256::one())
+        .unwrap();
+    txn.set_storage(contract, H256::from_low_u64_be(1), U256::from(2))
+        .unwrap();
+    assert_eq!(txn.commit().unwrap(), root);
+}
+
+// Sets a slot between two state roots, calling `between` on the contract
+// before the second, and returns the second root
+fn storage_root_after(between: impl FnOnce(&mut MutableTransaction, H160)) -> H256 {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let contract = get_address_from_index(0);
+    txn.set_account(contract, ACCOUNT1.clone());
+    txn.set_storage(contract, H256::zero(), U256::one())
+        .unwrap();
+    txn.state_root().unwrap();
+    txn.set_storage(contract, H256::from_low_u64_be(1), U256::from(2))
+        .unwrap();
+    between(&mut txn, contract);
+    txn.state_root().unwrap()
+}
+
+#[test]
+fn storage_root_updates_reused_root() {
+    let expected = storage_root_after(|_, _| {});
+    let root = storage_root_after(|txn, contract| {
+        txn.storage_root(&contract).unwrap();
+    });
+    assert_eq!(root, expected);
+    let root = storage_root_after(|txn, contract| {
+        txn.account_leaf_rlp(contract).unwrap().unwrap();
+    });
+    assert_eq!(root, expected);
+}
+
+#[test]
+fn noop_state_root() {
+    with_temp_db(|db| {
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_account(get_address_from_index(0), ACCOUNT1.clone());
+        let root = txn.state_root().unwrap();
+        assert_eq!(txn.pending_bytes(), 0);
+
+        // Nothing is flushed, not even the write outside the state
+        txn.raw_put(0, b"key", b"value").unwrap();
+        let pending = txn.pending_bytes();
+        assert!(pending > 0);
+        let summary = txn.state_root_detailed().unwrap();
+        assert_eq!(summary.root, root);
+        assert_eq!((summary.accounts_flushed, summary.nodes_written), (0, 0));
+        assert_eq!(txn.pending_bytes(), pending);
+        assert_eq!(txn.commit().unwrap(), root);
+
+        let mut txn = db.begin_mut().unwrap();
+        assert_eq!(txn.state_root().unwrap(), root);
+        txn.clear_all().unwrap();
+        assert_eq!(txn.state_root().unwrap(), *walk::EMPTY_TRIE_ROOT);
+    });
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let digits: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("0x{}", digits)
+}
+
+#[test]
+fn account_proof_json() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let address = Address::repeat_byte(0x11);
+    let code_hash = txn.store_code(b"code").unwrap();
+    txn.set_account(
+        address,
+        Some(Account::new(1, U256::from(0x1234), code_hash)),
+    );
+    let slot = H256::from_low_u64_be(1);
+    txn.set_storage(address, slot, U256::from(0xff)).unwrap();
+
+    // A lone account and slot are each a single leaf, whose key is all the
+    // nibbles of the hash: the flag byte 0x20 and the hash
+    let leaf = |key: H256, value: &[u8]| {
+        let mut path = vec![0x20];
+        path.extend_from_slice(keccak256(key).as_bytes());
+        let mut s = rlp::RlpStream::new_list(2);
+        s.append(&path);
+        s.append(&value);
+        s.out().to_vec()
+    };
+    let storage_leaf = leaf(slot, &rlp::encode(&U256::from(0xff)));
+    let storage_hash = keccak256(&storage_leaf);
+    let mut account = rlp::RlpStream::new_list(4);
+    account.append(&1u64);
+    account.append(&U256::from(0x1234));
+    account.append(&storage_hash);
+    account.append(&code_hash);
+    let account_leaf = {
+        let mut path = vec![0x20];
+        path.extend_from_slice(keccak256(address).as_bytes());
+        let mut s = rlp::RlpStream::new_list(2);
+        s.append(&path);
+        s.append(&account.out().to_vec());
+        s.out().to_vec()
+    };
+    let missing = H256::from_low_u64_be(2);
+    let expected = format!(
+        concat!(
+            "{{\"address\":\"0x1111111111111111111111111111111111111111\",",
+            "\"accountProof\":[\"{}\"],\"balance\":\"0x1234\",\"codeHash\":\"{:?}\",",
+            "\"nonce\":\"0x1\",\"storageHash\":\"{:?}\",\"storageProof\":[",
+            "{{\"key\":\"{:?}\",\"value\":\"0xff\",\"proof\":[\"{}\"]}},",
+            "{{\"key\":\"{:?}\",\"value\":\"0x0\",\"proof\":[\"{}\"]}}]}}"
+        ),
+        hex(&account_leaf),
+        code_hash,
+        storage_hash,
+        slot,
+        hex(&storage_leaf),
+        missing,
+        hex(&storage_leaf),
+    );
+    assert_eq!(
+        proof::account_proof_json(&mut txn, address, &[slot, missing]).unwrap(),
+        expected
+    );
+    assert_eq!(txn.state_root().unwrap(), keccak256(&account_leaf));
+
+    let json = proof::account_proof_json(&mut txn, Address::zero(), &[slot]).unwrap();
+    assert!(json.contains(&format!(
+        "\"balance\":\"0x0\",\"codeHash\":\"{:?}\",\"nonce\":\"0x0\"",
+        *EMPTY_CODE_HASH
+    )));
+    assert!(json.ends_with("\"value\":\"0x0\",\"proof\":[]}]}"));
+}
+
+// eth_getProof for 0x11..11 in a state of it and 0x00..01, with two slots
+// of it. The nodes and hashes were computed with an implementation of
+// keccak, RLP and the trie separate from this crate's.
+const ETH_GET_PROOF_FIXTURE: &str = r#"{
+  "address": "0x1111111111111111111111111111111111111111",
+  "accountProof": [
+    "0xf85180a03abfb8a483f2c6a67ba51c2b9cd08265dc5138956334cbd204b98b5717df41de808080808080808080808080a0377df9a8f47c6d98b35f4e183b76431e799b67a997b1b0f7f0d6eea72fea966b8080",
+    "0xf86ba032c07404b8c1df4c46226425cac68c28d27a766bbddce62309f36724839b22c0b848f84601821234a0de0202602ab4ccdea722591200f4c1aa59f87f9933541ff27db0883db38b44eca02dc081a8d6d4714c79b5abd2e9b08c3a33b4ef1dcf946ef8b8cf6c495014f47b"
+  ],
+  "balance": "0x1234",
+  "codeHash": "0x2dc081a8d6d4714c79b5abd2e9b08c3a33b4ef1dcf946ef8b8cf6c495014f47b",
+  "nonce": "0x1",
+  "storageHash": "0xde0202602ab4ccdea722591200f4c1aa59f87f9933541ff27db0883db38b44ec",
+  "storageProof": [
+    {
+      "key": "0x0000000000000000000000000000000000000000000000000000000000000001",
+      "value": "0xff",
+      "proof": [
+        "0xe5a120b10e2d527612073b26eecdfd717e6a320cf44b4afac2b0732d9fcbe2b7fa0cf68281ff"
+      ]
+    },
+    {
+      "key": "0x0000000000000000000000000000000000000000000000000000000000000002",
+      "value": "0x0",
+      "proof": [
+        "0xe5a120b10e2d527612073b26eecdfd717e6a320cf44b4afac2b0732d9fcbe2b7fa0cf68281ff"
+      ]
+    }
+  ]
+}"#;
+
+#[test]
+fn account_proof_json_fixture() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let address = Address::repeat_byte(0x11);
+    let code_hash = txn.store_code(b"code").unwrap();
+    txn.set_account(
+        address,
+        Some(Account::new(1, U256::from(0x1234), code_hash)),
+    );
+    txn.set_storage(address, H256::from_low_u64_be(1), U256::from(0xff))
+        .unwrap();
+    txn.set_account(
+        Address::from_low_u64_be(1),
+        Some(Account::eoa(7, U256::exp10(18))),
+    );
+    let keys = [H256::from_low_u64_be(1), H256::from_low_u64_be(2)];
+    let json = proof::account_proof_json(&mut txn, address, &keys).unwrap();
+    let expected: String = ETH_GET_PROOF_FIXTURE.split_whitespace().collect();
+    assert_eq!(json, expected);
+    #[cfg(feature = "json")]
+    assert_eq!(
+        proof::account_proof_value(&mut txn, address, &keys).unwrap(),
+        serde_json::from_str::<serde_json::Value>(ETH_GET_PROOF_FIXTURE).unwrap()
+    );
+    assert_eq!(
+        txn.state_root().unwrap(),
+        "0x01b03c460a2eb75fade8c036dd6d7830db49eda331708a328379a73783caf01b"
+            .parse::<H256>()
+            .unwrap()
+    );
+}
+
+#[test]
+fn page_size() {
+    assert_eq!(Db::memory().unwrap().page_size().unwrap(), None);
+    let dir = tempfile::tempdir().unwrap();
+    let options = |page_size| DbOptions {
+        page_size,
+        ..DbOptions::default()
+    };
+    {
+        let (db, opened) = Db::open_with_options(dir.path(), options(Some(16384))).unwrap();
+        assert!(opened.created);
+        assert_eq!(db.page_size().unwrap(), Some(16384));
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_account(get_address_from_index(0), ACCOUNT1.clone());
+        txn.commit().unwrap();
+    }
+    let err = Db::open_with_options(dir.path(), options(Some(4096))).unwrap_err();
+    assert_eq!(err.to_string(), "Database has page size 16384, not 4096");
+    let (db, _) = Db::open_with_options(dir.path(), options(None)).unwrap();
+    assert_eq!(db.page_size().unwrap(), Some(16384));
+    assert_eq!(
+        db.begin_ro()
+            .unwrap()
+            .try_account(get_address_from_index(0))
+            .unwrap(),
+        *ACCOUNT1
+    );
+    drop(db);
+
+    let dir = tempfile::tempdir().unwrap();
+    let err = Db::open_with_options(dir.path(), options(Some(1000))).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid page size 1000");
+}
+
+#[test]
+fn same_thread_transactions() {
+    let db = Db::memory().unwrap();
+    let read = db.begin_ro().unwrap();
+    let err = db.begin_mut().err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "This thread holds a transaction, a write would deadlock"
+    );
+    let second = db.begin_ro().unwrap();
+    drop((read, second));
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(get_address_from_index(0), ACCOUNT1.clone());
+    let err = db.version().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "This thread holds a write transaction, a read would deadlock"
+    );
+    assert!(db.begin_mut().is_err());
+    txn.commit().unwrap();
+
+    let read = db.begin_ro().unwrap();
+    assert_eq!(
+        read.try_account(get_address_from_index(0)).unwrap(),
+        *ACCOUNT1
+    );
+}
+
+#[test]
+fn version() {
+    assert_eq!(DB_VERSION, CURRENT_VERSION.to_string().as_bytes());
+    assert_eq!(Db::memory().unwrap().version().unwrap(), CURRENT_VERSION);
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let db = Db::file(dir.path()).unwrap();
+        assert_eq!(db.version().unwrap(), CURRENT_VERSION);
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_metadata(b"version", b"2").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.version().unwrap(), 2);
+    }
+    let err = Db::file(dir.path()).err().unwrap();
+    assert_eq!(err.to_string(), "Wrong DB_VERSION expected: 1, got: 2");
+}
+
+// Rewrites the file database at `from` into `to` in the version 0 layout,
+// every key with its table prefix in MDBX's unnamed database
+fn write_version_0_file(from: &std::path::Path, to: &std::path::Path) {
+    use ethereum_pyspec_db::backend::{Backend, FileOptions, Table};
+    use libmdbx::{Environment, WriteFlags, WriteMap};
+
+    let backend = Backend::file(from, &FileOptions::default()).unwrap();
+    let tx = backend.begin_mut().unwrap();
+    let env = Environment::<WriteMap>::new().open(to).unwrap();
+    let txn = env.begin_rw_txn().unwrap();
+    let main_db = txn.open_db(None).unwrap();
+    for table in Table::ALL {
+        for entry in tx.iter_prefix(&[table.prefix()]) {
+            let (key, value) = entry.unwrap();
+            txn.put(&main_db, key, value, WriteFlags::default())
+                .unwrap();
+        }
+    }
+    txn.put(&main_db, b"\0version", b"0", WriteFlags::default())
+        .unwrap();
+    txn.commit().unwrap();
+}
+
+#[test]
+fn open_version_0_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let address = get_address_from_index(0);
+    let state_root = {
+        let db = Db::file(dir.path()).unwrap();
+        let mut txn = db.begin_mut().unwrap();
+        txn.set_account(address, ACCOUNT1.clone());
+        txn.store_code(b"code").unwrap();
+        txn.set_metadata(b"key", b"value").unwrap();
+        txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+        txn.raw_put(7, b"raw", b"value").unwrap();
+        let state_root = txn.state_root().unwrap();
+        txn.commit().unwrap();
+        state_root
+    };
+    let old = tempfile::tempdir().unwrap();
+    write_version_0_file(dir.path(), old.path());
+
+    for _ in 0..2 {
+        let db = Db::file(old.path()).unwrap();
+        assert_eq!(db.version().unwrap(), CURRENT_VERSION);
+        let mut txn = db.begin_mut().unwrap();
+        assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+        assert_eq!(txn.storage(address, H256::zero()).unwrap(), U256::one());
+        assert_eq!(txn.metadata(b"key").unwrap().unwrap(), &b"value"[..]);
+        assert_eq!(txn.raw_get(7, b"raw").unwrap().unwrap(), &b"value"[..]);
+        assert_eq!(
+            txn.code_from_hash(keccak256(b"code")).unwrap().unwrap(),
+            &b"code"[..]
+        );
+        assert_eq!(txn.state_root().unwrap(), state_root);
+        txn.commit().unwrap();
+    }
+}
+
+#[test]
+fn iter_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Db::file(dir.path()).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let entries: Vec<_> = txn.iter_metadata().collect::<anyhow::Result<_>>().unwrap();
+    assert_eq!(entries, [(b"version".to_vec(), DB_VERSION.to_vec())]);
+    txn.set_metadata(b"a", b"pending").unwrap();
+    txn.raw_put(0, b"b", b"raw").unwrap();
+    let keys: Vec<_> = txn.iter_metadata().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(keys, [b"a".to_vec(), b"version".to_vec()]);
+}
+
+#[test]
+fn raw() {
+    with_memory(do_raw_test);
+    with_temp_db(do_raw_test);
+}
+
+fn do_iter_contracts_test(db: &mut Db) {
+    let mut expected = std::collections::BTreeMap::new();
+    let mut txn = db.begin_mut().unwrap();
+    let contract = |txn: &mut MutableTransaction, i: usize| {
+        Account::new(1, U256::zero(), txn.store_code(&i.to_be_bytes()).unwrap())
+    };
+    for i in 0..50 {
+        let address = get_address_from_index(i);
+        let account = if i % 5 == 0 {
+            contract(&mut txn, i)
+        } else {
+            ACCOUNT1.clone().unwrap()
+        };
+        expected.insert(address, account.clone());
+        txn.set_account(address, Some(account));
+        txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    for (address, account) in [
+        (get_address_from_index(0), None),
+        (get_address_from_index(1), Some(contract(&mut txn, 1))),
+        (get_address_from_index(2), ACCOUNT2.clone()),
+        (get_address_from_index(100), Some(contract(&mut txn, 100))),
+    ] {
+        match &account {
+            Some(account) => expected.insert(address, account.clone()),
+            None => expected.remove(&address),
+        };
+        txn.set_account(address, account);
+    }
+    let check = |txn: &MutableTransaction| {
+        let accounts: Vec<_> = txn.iter_accounts().map(Result::unwrap).collect();
+        assert_eq!(accounts, expected.clone().into_iter().collect::<Vec<_>>());
+        let contracts: Vec<_> = txn.iter_contracts().map(Result::unwrap).collect();
+        assert_eq!(
+            contracts,
+            expected
+                .clone()
+                .into_iter()
+                .filter(|(_, account)| account.code_hash != *EMPTY_CODE_HASH)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(contracts.len(), 11);
+    };
+    check(&txn);
+    txn.state_root().unwrap();
+    check(&txn);
+}
+
+#[test]
+fn iter_contracts() {
+    with_memory(do_iter_contracts_test);
+    with_temp_db(do_iter_contracts_test);
+}
+
+#[test]
+fn read_during_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Db::file(dir.path()).unwrap();
+    let address = get_address_from_index(0);
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.commit().unwrap();
+
+    let before = db.begin_ro().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT2.clone());
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let txn = db.begin_ro().unwrap();
+                assert_eq!(txn.try_account(address).unwrap(), ACCOUNT1.clone());
+            })
+            .join()
+            .unwrap();
+    });
+    txn.commit().unwrap();
+    assert_eq!(before.try_account(address).unwrap(), ACCOUNT1.clone());
+    let after = db.begin_ro().unwrap();
+    assert_eq!(after.try_account(address).unwrap(), ACCOUNT2.clone());
+}
+
+#[test]
+fn memory_from() {
+    let address = get_address_from_index(0);
+    let account = ACCOUNT1.clone().unwrap();
+    let db = Db::memory_from([
+        (keys::metadata_key(b"fixture"), b"yes".to_vec()),
+        (keys::account_key(address), account.marshal().to_vec()),
+    ])
+    .unwrap();
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(*txn.metadata(b"fixture").unwrap().unwrap(), *b"yes");
+    assert_eq!(txn.try_account(address).unwrap(), Some(account));
+
+    assert!(Db::memory_from([(vec![], vec![])]).is_err());
+    assert!(Db::memory_from([(vec![200], vec![])]).is_err());
+}
+
+#[test]
+fn key_too_long() {
+    let err = Db::memory_from([(vec![2; 100], vec![])]).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Key of 100 bytes in table trie exceeds the maximum of 96"
+    );
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert!(txn.set_metadata(&[0; backend::MAX_KEY_LEN], b"").is_err());
+}
+
+#[test]
+fn read_at_root() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(
+        txn.try_account_at(
+            check_trie::calc_root(&HashMap::new()),
+            get_address_from_index(0)
+        )
+        .unwrap(),
+        None
+    );
+    for i in 0..100 {
+        txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+        txn.set_storage(get_address_from_index(i), H256::zero(), U256::from(i))
+            .unwrap();
+    }
+    let root = txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..110 {
+        let address = get_address_from_index(i);
+        assert_eq!(
+            txn.try_account_at(root, address).unwrap(),
+            txn.try_account(address).unwrap()
+        );
+        assert_eq!(
+            txn.storage_at(root, address, H256::zero()).unwrap(),
+            txn.storage(address, H256::zero()).unwrap()
+        );
+        assert_eq!(
+            txn.storage_at(root, address, H256::repeat_byte(1)).unwrap(),
+            U256::zero()
+        );
+    }
+
+    txn.set_account(get_address_from_index(0), ACCOUNT2.clone());
+    let new_root = txn.state_root().unwrap();
+    assert_eq!(
+        txn.try_account_at(new_root, get_address_from_index(0))
+            .unwrap(),
+        *ACCOUNT2
+    );
+    let err = txn
+        .try_account_at(root, get_address_from_index(0))
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DbError>(),
+        Some(DbError::RootUnavailable(_))
+    ));
+}
+
+fn do_counts_test(db: &mut Db) {
+    let mut rng = ChaCha8Rng::seed_from_u64(2);
+    let mut accounts = std::collections::HashSet::new();
+    let mut slots = std::collections::HashSet::new();
+    for _ in 0..20 {
+        let mut txn = db.begin_mut().unwrap();
+        for _ in 0..20 {
+            let address = get_address_from_index(rng.gen_range(0..20));
+            if !accounts.contains(&address) {
+                txn.set_account(address, ACCOUNT1.clone());
+                accounts.insert(address);
+            } else if rng.gen_bool(0.1) {
+                txn.destroy_storage(address).unwrap();
+                txn.set_account(address, None);
+                accounts.remove(&address);
+                slots.retain(|(owner, _)| *owner != address);
+            } else if rng.gen_bool(0.1) {
+                txn.destroy_storage(address).unwrap();
+                slots.retain(|(owner, _)| *owner != address);
+            } else {
+                let slot = H256::from_low_u64_be(rng.gen_range(0..5));
+                let value = U256::from(rng.gen_range(0..3));
+                txn.set_storage(address, slot, value).unwrap();
+                if value.is_zero() {
+                    slots.remove(&(address, slot));
+                } else {
+                    slots.insert((address, slot));
+                }
+            }
+        }
+        assert_eq!(txn.account_count().unwrap(), accounts.len() as u64);
+        assert_eq!(txn.storage_slot_count().unwrap(), slots.len() as u64);
+        txn.commit().unwrap();
+    }
+    let txn = db.begin_mut().unwrap();
+    assert_eq!(txn.account_count().unwrap(), accounts.len() as u64);
+    assert_eq!(txn.storage_slot_count().unwrap(), slots.len() as u64);
+}
+
+#[test]
+fn counts() {
+    with_memory(do_counts_test);
+    with_temp_db(do_counts_test);
+}
+
+#[test]
+fn verify_code_on_read() {
+    let code_hash = keccak256(b"code");
+    let entries = || [(keys::code_key(code_hash), b"c0de".to_vec())];
+    let db = Db::memory_from(entries()).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(*txn.code_from_hash(code_hash).unwrap().unwrap(), *b"c0de");
+
+    let options = DbOptions {
+        verify_code_on_read: true,
+        ..DbOptions::default()
+    };
+    let db = Db::memory_from_with_options(entries(), options).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let err = txn.code_from_hash(code_hash).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DbError>(),
+        Some(DbError::CorruptCode { hash }) if *hash == code_hash
+    ));
+    let other_hash = txn.store_code(b"other").unwrap();
+    assert_eq!(*txn.code_from_hash(other_hash).unwrap().unwrap(), *b"other");
+    txn.commit().unwrap();
+    let txn = db.begin_ro().unwrap();
+    assert!(txn.code_from_hash(code_hash).is_err());
+    assert_eq!(txn.code_from_hash(keccak256(b"missing")).unwrap(), None);
+}
+
+#[test]
+fn drain_pending() {
+    let db = Db::memory().unwrap();
+    let address0 = get_address_from_index(0);
+    let address1 = get_address_from_index(1);
+    let (address0, address1) = (address0.min(address1), address0.max(address1));
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address0, ACCOUNT1.clone());
+    txn.set_storage(address0, H256::zero(), U256::one())
+        .unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address1, ACCOUNT2.clone());
+    txn.set_storage(address1, H256::repeat_byte(2), U256::from(2))
+        .unwrap();
+    txn.set_storage(address1, H256::repeat_byte(1), U256::one())
+        .unwrap();
+    txn.destroy_storage(address0).unwrap();
+    txn.set_storage(address0, H256::repeat_byte(3), U256::from(3))
+        .unwrap();
+    let changes: Vec<_> = txn.drain_pending().unwrap().collect();
+    assert_eq!(
+        changes,
+        [
+            PendingChange::Destroyed { address: address0 },
+            PendingChange::Account {
+                address: address0,
+                new: ACCOUNT1.clone()
+            },
+            PendingChange::Storage {
+                address: address0,
+                slot: H256::repeat_byte(3),
+                value: U256::from(3)
+            },
+            PendingChange::Account {
+                address: address1,
+                new: ACCOUNT2.clone()
+            },
+            PendingChange::Storage {
+                address: address1,
+                slot: H256::repeat_byte(1),
+                value: U256::one()
+            },
+            PendingChange::Storage {
+                address: address1,
+                slot: H256::repeat_byte(2),
+                value: U256::from(2)
+            },
+        ]
+    );
+    assert_eq!(txn.drain_pending().unwrap().count(), 0);
+    let root = txn.state_root().unwrap();
+    assert_eq!(txn.commit().unwrap(), root);
+
+    let txn = db.begin_ro().unwrap();
+    assert_eq!(txn.storage(address0, H256::zero()).unwrap(), U256::zero());
+    assert_eq!(
+        txn.storage(address0, H256::repeat_byte(3)).unwrap(),
+        U256::from(3)
+    );
+    assert_eq!(txn.try_account(address1).unwrap(), *ACCOUNT2);
+}
+
+#[test]
+fn state_changed() {
+    let db = Db::memory().unwrap();
+    let (address0, address1) = (get_address_from_index(0), get_address_from_index(1));
+    let mut txn = db.begin_mut().unwrap();
+    assert!(!txn.state_changed().unwrap());
+    txn.set_account(address0, ACCOUNT1.clone());
+    txn.set_account(address1, ACCOUNT2.clone());
+    txn.set_storage(address0, H256::zero(), U256::one())
+        .unwrap();
+    assert!(txn.state_changed().unwrap());
+    let root = txn.commit().unwrap();
+
+    // Every write matches what is stored
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address0, ACCOUNT1.clone());
+    txn.set_account(get_address_from_index(2), None);
+    txn.set_storage(address0, H256::zero(), U256::one())
+        .unwrap();
+    txn.set_storage(address0, H256::repeat_byte(1), U256::zero())
+        .unwrap();
+    txn.set_storage(address1, H256::zero(), U256::one())
+        .unwrap();
+    txn.destroy_storage(address1).unwrap();
+    assert!(!txn.state_changed().unwrap());
+    let summary = txn.state_root_detailed().unwrap();
+    assert_eq!(summary.root, root);
+    assert_eq!((summary.nodes_written, summary.nodes_deleted), (0, 0));
+    assert_eq!(summary.accounts_flushed, 0);
+
+    txn.set_storage(address0, H256::zero(), U256::from(2))
+        .unwrap();
+    assert!(txn.state_changed().unwrap());
+    txn.set_storage(address0, H256::zero(), U256::one())
+        .unwrap();
+    assert!(!txn.state_changed().unwrap());
+    txn.destroy_storage(address0).unwrap();
+    assert!(txn.state_changed().unwrap());
+    assert_ne!(txn.state_root().unwrap(), root);
+}
+
+fn do_redundant_writes_test(db: &mut Db) {
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..100 {
+        let address = get_address_from_index(i);
+        txn.set_account(address, Some(Account::eoa(i as u64, U256::from(i))));
+        for j in 0..5 {
+            txn.set_storage(address, H256::from_low_u64_be(j), U256::from(j + 1))
+                .unwrap();
+        }
+    }
+    let root = txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..100 {
+        let address = get_address_from_index(i);
+        txn.set_account(address, Some(Account::eoa(i as u64, U256::from(i))));
+        for j in 0..10 {
+            let value = if j < 5 {
+                U256::from(j + 1)
+            } else {
+                U256::zero()
+            };
+            txn.set_storage(address, H256::from_low_u64_be(j), value)
+                .unwrap();
+        }
+    }
+    let (committed, report) = txn.commit_reported().unwrap();
+    assert_eq!(committed, root);
+    assert_eq!((report.keys_written, report.keys_deleted), (0, 0));
+}
+
+#[test]
+fn redundant_writes() {
+    with_memory(do_redundant_writes_test);
+    with_temp_db(do_redundant_writes_test);
+}
+
+#[test]
+fn state_root_retaining() {
+    let address = get_address_from_index(0);
+    let mut roots = Vec::new();
+    for retain in [false, true] {
+        let db = Db::memory().unwrap();
+        let mut txn = db.begin_mut().unwrap();
+        assert_eq!(txn.last_changeset(), None);
+        txn.set_account(address, ACCOUNT1.clone());
+        txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+        let root = if retain {
+            txn.state_root_retaining().unwrap()
+        } else {
+            txn.state_root().unwrap()
+        };
+        roots.push((root, txn.commit().unwrap()));
+    }
+    assert_eq!(roots[0], roots[1]);
+
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(address, ACCOUNT1.clone());
+    txn.set_storage(address, H256::zero(), U256::one()).unwrap();
+    txn.state_root_retaining().unwrap();
+    let changeset = txn.last_changeset().unwrap();
+    assert_eq!(changeset.accounts[&address], ACCOUNT1.clone());
+    assert_eq!(changeset.storage[&address][&H256::zero()], U256::one());
+    assert!(changeset.destroyed_storage.is_empty());
+    // Kept until the next `state_root_retaining`
+    txn.set_storage(address, H256::zero(), U256::from(2))
+        .unwrap();
+    txn.state_root().unwrap();
+    assert_eq!(
+        txn.last_changeset().unwrap().storage[&address][&H256::zero()],
+        U256::one()
+    );
+    txn.state_root_retaining().unwrap();
+    assert!(txn.last_changeset().unwrap().storage.is_empty());
+}
+
+#[test]
+fn proofs() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let empty_root = check_trie::calc_root(&HashMap::new());
+    let address = get_address_from_index(0);
+    assert_eq!(
+        txn.account_proof(empty_root, address).unwrap(),
+        Vec::<Vec<u8>>::new()
+    );
+    assert_eq!(
+        proof::verify_proof(empty_root, keccak256(address).as_bytes(), &[]).unwrap(),
+        None
+    );
+    for i in 0..1000 {
+        txn.set_account(
+            get_address_from_index(i),
+            Some(Account::eoa(i as u64, U256::from(i))),
+        );
+    }
+    let root = txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    let mut items: Vec<(Vec<u8>, Vec<Vec<u8>>)> = (0..1100)
+        .map(|i| {
+            let address = get_address_from_index(i);
+            (
+                keccak256(address).as_bytes().to_vec(),
+                txn.account_proof(root, address).unwrap(),
+            )
+        })
+        .collect();
+    let values = proof::verify_many(root, &items).unwrap();
+    for (i, ((key, proof), value)) in items.iter().zip(&values).enumerate() {
+        assert_eq!(*value, proof::verify_proof(root, key, proof).unwrap());
+        let account = value.as_ref().map(|value| {
+            let rlp = rlp::Rlp::new(value);
+            Account::new(
+                rlp.val_at(0).unwrap(),
+                rlp.val_at(1).unwrap(),
+                rlp.val_at(3).unwrap(),
+            )
+        });
+        assert_eq!(account, txn.try_account(get_address_from_index(i)).unwrap());
+    }
+
+    let node = items[5].1.last_mut().unwrap();
+    *node.last_mut().unwrap() ^= 1;
+    let err = proof::verify_many(root, &items).unwrap_err();
+    assert_eq!(err.to_string(), "Proof 5 is invalid");
+    items[5].1.pop();
+    assert!(proof::verify_many(root, &items).is_err());
+    items[5].1 = items[6].1.clone();
+    assert!(proof::verify_many(root, &items).is_err());
+}
+
+#[test]
+fn storage_proofs() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let address = get_address_from_index(0);
+    txn.set_account(address, ACCOUNT1.clone());
+    let values = [U256::one(), U256::MAX, U256::from(0x1234)];
+    for (i, value) in values.iter().enumerate() {
+        txn.set_storage(address, H256::from_low_u64_be(i as u64), *value)
+            .unwrap();
+    }
+    let root = txn.commit().unwrap();
+
+    let txn = db.begin_mut().unwrap();
+    let account_proof = txn.account_proof(root, address).unwrap();
+    let leaf = proof::verify_proof(root, keccak256(address).as_bytes(), &account_proof)
+        .unwrap()
+        .unwrap();
+    let storage_root: H256 = rlp::Rlp::new(&leaf).val_at(2).unwrap();
+    for i in 0..5 {
+        let slot = H256::from_low_u64_be(i);
+        let proof = txn.storage_proof(root, address, slot).unwrap();
+        assert_eq!(
+            proof::verify_storage_proof(storage_root, slot, &proof).unwrap(),
+            txn.storage(address, slot).unwrap()
+        );
+    }
+    let other = get_address_from_index(1);
+    assert!(txn
+        .storage_proof(root, other, H256::zero())
+        .unwrap()
+        .is_empty());
+
+    for value in values {
+        assert_eq!(
+            proof::decode_storage_value(&rlp::encode(&value)).unwrap(),
+            value
+        );
+    }
+    assert!(proof::decode_storage_value(&rlp::encode(&U256::zero())).is_err());
+    assert!(proof::decode_storage_value(&[0x82, 0x00, 0x01]).is_err());
+}
+
+#[test]
+fn try_clone() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..10 {
+        txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+    }
+    let root = txn.commit().unwrap();
+
+    let fork = db.try_clone().unwrap();
+    let mut txn = fork.begin_mut().unwrap();
+    txn.set_account(get_address_from_index(0), ACCOUNT2.clone());
+    txn.set_account(get_address_from_index(10), ACCOUNT2.clone());
+    let fork_root = txn.commit().unwrap();
+    assert_ne!(fork_root, root);
+
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(
+        txn.try_account(get_address_from_index(0)).unwrap(),
+        *ACCOUNT1
+    );
+    assert_eq!(txn.try_account(get_address_from_index(10)).unwrap(), None);
+    assert_eq!(txn.state_root().unwrap(), root);
+    drop(txn);
+    assert_eq!(fork.begin_mut().unwrap().state_root().unwrap(), fork_root);
+
+    let dir = tempfile::tempdir().unwrap();
+    assert!(Db::file(dir.path()).unwrap().try_clone().is_err());
+}
+
+#[test]
+fn iter_accounts_by_trie_order() {
+    let options = DbOptions {
+        record_preimages: true,
+        ..DbOptions::default()
+    };
+    let db = Db::memory_with_options(options).unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..200 {
+        txn.set_account(
+            get_address_from_index(i),
+            Some(Account::eoa(i as u64, U256::zero())),
+        );
+    }
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(get_address_from_index(0), None);
+    txn.set_account(get_address_from_index(200), ACCOUNT1.clone());
+    let mut keys: Vec<H256> = (1..201)
+        .map(|i| keccak256(get_address_from_index(i)))
+        .collect();
+    keys.sort();
+    let start = keys[57];
+    let accounts: Vec<_> = txn
+        .iter_accounts_by_trie_order(H256::zero())
+        .unwrap()
+        .collect::<anyhow::Result<_>>()
+        .unwrap();
+    assert!(accounts.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(
+        accounts.iter().map(|(key, _, _)| *key).collect::<Vec<_>>(),
+        keys
+    );
+    for (key, address, account) in &accounts {
+        let address = address.unwrap();
+        assert_eq!(keccak256(address), *key);
+        assert_eq!(txn.try_account(address).unwrap().as_ref(), Some(account));
+    }
+
+    let from_start: Vec<H256> = txn
+        .iter_accounts_by_trie_order(start)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(from_start, keys[57..]);
+    let mut after_start = start;
+    after_start.0[31] += 1;
+    let first = txn
+        .iter_accounts_by_trie_order(after_start)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(first.0, keys[58]);
+}
+
+#[test]
+fn account_leaf_rlp() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..20 {
+        let address = get_address_from_index(i);
+        txn.set_account(address, Some(Account::eoa(i as u64, U256::from(i))));
+        txn.set_storage(address, H256::from_low_u64_be(i as u64), U256::one())
+            .unwrap();
+    }
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    let pending = get_address_from_index(3);
+    txn.set_storage(pending, H256::from_low_u64_be(100), U256::from(5))
+        .unwrap();
+    let pending_leaf = txn.account_leaf_rlp(pending).unwrap();
+    assert_eq!(
+        txn.account_leaf_rlp(get_address_from_index(20)).unwrap(),
+        None
+    );
+    let root = txn.commit().unwrap();
+
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..20 {
+        let address = get_address_from_index(i);
+        let proof = txn.account_proof(root, address).unwrap();
+        let value = proof::verify_proof(root, keccak256(address).as_bytes(), &proof).unwrap();
+        assert_eq!(txn.account_leaf_rlp(address).unwrap(), value);
+        if address == pending {
+            assert_eq!(pending_leaf, value);
+        }
+    }
+    // Reading the leaves of committed accounts leaves nothing to flush
+    assert!(!txn.has_pending_changes());
+    assert_eq!(txn.state_root_detailed().unwrap().storage_tries_walked, 0);
+}
+
+#[test]
+fn account_range_proof() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    let mut addresses = HashMap::new();
+    for i in 0..300 {
+        let address = get_address_from_index(i);
+        txn.set_account(address, Some(Account::eoa(i as u64, U256::from(i))));
+        if i % 10 == 0 {
+            txn.set_storage(address, H256::from_low_u64_be(1), U256::from(i + 1))
+                .unwrap();
+        }
+        addresses.insert(keccak256(address), address);
+    }
+    let root = txn.commit().unwrap();
+    let mut keys: Vec<H256> = addresses.keys().copied().collect();
+    keys.sort();
+
+    let mut txn = db.begin_mut().unwrap();
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let mut starts = vec![H256::zero(), keys[0], keys[150], H256::repeat_byte(0xFF)];
+    starts.extend((0..10).map(|_| H256(rng.gen())));
+    for start in starts {
+        for limit in [1, 2, 17, 300] {
+            let (accounts, proof) = txn.account_range_proof(start, limit).unwrap();
+            let expected: Vec<H256> = keys
+                .iter()
+                .filter(|key| **key >= start)
+                .take(limit)
+                .copied()
+                .collect();
+            let leaves: Vec<(H256, Vec<u8>)> = accounts
+                .iter()
+                .map(|(key, account)| {
+                    let address = addresses[key];
+                    assert_eq!(Some(account), txn.try_account(address).unwrap().as_ref());
+                    let mut stream = rlp::RlpStream::new_list(4);
+                    stream.append(&account.nonce);
+                    stream.append(&account.balance);
+                    stream.append(&txn.storage_root(&address).unwrap());
+                    stream.append(&account.code_hash);
+                    (*key, stream.out().to_vec())
+                })
+                .collect();
+            assert_eq!(
+                leaves.iter().map(|(key, _)| *key).collect::<Vec<_>>(),
+                expected
+            );
+            let has_more = proof::verify_range(root, start, &leaves, &proof).unwrap();
+            assert_eq!(
+                has_more,
+                expected
+                    .last()
+                    .is_some_and(|last| last < keys.last().unwrap())
+            );
+
+            if leaves.len() > 2 {
+                let mut missing = leaves.clone();
+                missing.remove(1);
+                assert!(proof::verify_range(root, start, &missing, &proof).is_err());
+                let mut changed = leaves.clone();
+                *changed[1].1.last_mut().unwrap() ^= 1;
+                assert!(proof::verify_range(root, start, &changed, &proof).is_err());
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "node-epochs")]
+fn prune_older_than() {
+    let db = Db::memory().unwrap();
+    let reference = Db::memory().unwrap();
+    let mut roots = Vec::new();
+    for (epoch, range) in [(1, 0..100), (2, 0..5)] {
+        let mut txn = db.begin_mut().unwrap();
+        let mut reference_txn = reference.begin_mut().unwrap();
+        txn.set_epoch(epoch);
+        for i in range {
+            let account = Some(Account::eoa(epoch as u64, U256::from(i)));
+            txn.set_account(get_address_from_index(i), account.clone());
+            reference_txn.set_account(get_address_from_index(i), account);
+        }
+        let root = txn.commit().unwrap();
+        assert_eq!(root, reference_txn.commit().unwrap());
+        roots.push(root);
+    }
+
+    assert!(db.prune_older_than(2).unwrap() > 0);
+    assert_eq!(db.prune_older_than(2).unwrap(), 0);
+    let mut txn = db.begin_mut().unwrap();
+    assert_eq!(txn.state_root().unwrap(), roots[1]);
+    for i in 0..5 {
+        let address = get_address_from_index(i);
+        let account = txn.try_account_at(roots[1], address).unwrap();
+        assert_eq!(account, Some(Account::eoa(2, U256::from(i))));
+        let proof = txn.account_proof(roots[1], address).unwrap();
+        assert!(
+            proof::verify_proof(roots[1], keccak256(address).as_bytes(), &proof)
+                .unwrap()
+                .is_some()
+        );
+    }
+    let err = (5..100)
+        .map(|i| txn.try_account_at(roots[1], get_address_from_index(i)))
+        .find_map(Result::err)
+        .unwrap();
+    assert!(matches!(
+        err.downcast_ref::<DbError>(),
+        Some(DbError::RootUnavailable(_))
+    ));
+    assert_eq!(
+        txn.try_account(get_address_from_index(50)).unwrap(),
+        Some(Account::eoa(1, U256::from(50)))
+    );
+}
+
+fn do_commit_reported_test(db: &mut Db) -> Vec<CommitReport> {
+    let mut reports = Vec::new();
+    let mut txn = db.begin_mut().unwrap();
+    for i in 0..10 {
+        txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+        txn.set_storage(get_address_from_index(i), H256::zero(), U256::one())
+            .unwrap();
+    }
+    let (root, report) = txn.commit_reported().unwrap();
+    assert_eq!(report.keys_deleted, 0);
+    // The accounts, slots and a storage trie leaf each, and the account trie
+    assert!(report.keys_written > 30);
+    reports.push(report);
+
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(get_address_from_index(0), None);
+    txn.destroy_storage(get_address_from_index(0)).unwrap();
+    // Deleting what does not exist deletes nothing
+    txn.set_account(get_address_from_index(20), None);
+    let (new_root, report) = txn.commit_reported().unwrap();
+    assert_ne!(new_root, root);
+    assert!(report.keys_deleted >= 4);
+    reports.push(report);
+    reports
+}
+
+#[test]
+fn commit_reported() {
+    let memory = with_memory(do_commit_reported_test);
+    let file = with_temp_db(do_commit_reported_test);
+    for (memory, file) in memory.iter().zip(&file) {
+        assert_eq!(
+            (memory.keys_written, memory.keys_deleted),
+            (file.keys_written, file.keys_deleted)
+        );
+    }
+}
+
+#[test]
+fn commit_staged() {
+    let blocks: Vec<Vec<PendingChange>> = (0..3)
+        .map(|block| {
+            (0..10)
+                .flat_map(|i| {
+                    let address = get_address_from_index(block * 5 + i);
+                    [
+                        PendingChange::Account {
+                            address,
+                            new: Some(Account::eoa(block as u64, U256::from(i))),
+                        },
+                        PendingChange::Storage {
+                            address,
+                            slot: H256::from_low_u64_be(block as u64),
+                            value: U256::from(i + 1),
+                        },
+                    ]
+                })
+                .collect()
+        })
+        .collect();
+    let db = Db::memory().unwrap();
+    let roots: Vec<H256> = blocks
+        .iter()
+        .map(|changes| {
+            let mut txn = db.begin_mut().unwrap();
+            txn.apply_changes(changes.clone()).unwrap();
+            txn.commit().unwrap()
+        })
+        .collect();
+
+    with_temp_db(|db| {
+        let mut staging = StagingArea::new();
+        for changes in &blocks {
+            staging.push(changes.clone(), None);
+        }
+        assert_eq!(staging.len(), 3);
+        assert_eq!(db.commit_staged(staging).unwrap(), roots);
+        assert_eq!(db.begin_mut().unwrap().state_root().unwrap(), roots[2]);
+    });
+
+    with_temp_db(|db| {
+        let mut staging = StagingArea::new();
+        staging.push(blocks[0].clone(), Some(roots[0]));
+        staging.push(blocks[1].clone(), Some(roots[0]));
+        let err = db.commit_staged(staging).unwrap_err();
+        assert!(err.downcast_ref::<StateRootMismatch>().is_some());
+        assert_eq!(db.begin_mut().unwrap().account_count().unwrap(), 0);
+    });
+}
+
+#[test]
+fn apply_changes() {
+    let dbs = [Db::memory().unwrap(), Db::memory().unwrap()];
+    for db in &dbs {
+        let mut txn = db.begin_mut().unwrap();
+        for i in 0..20 {
+            txn.set_account(get_address_from_index(i), ACCOUNT1.clone());
+            txn.set_storage(get_address_from_index(i), H256::zero(), U256::from(i))
+                .unwrap();
+        }
+        txn.commit().unwrap();
+    }
+
+    let mut rng = ChaCha8Rng::seed_from_u64(3);
+    for _ in 0..10 {
+        let mut txn = dbs[0].begin_mut().unwrap();
+        for _ in 0..20 {
+            let address = get_address_from_index(rng.gen_range(0..30));
+            match rng.gen_range(0..4) {
+                0 => txn.set_account(address, None),
+                1 => txn.destroy_storage(address).unwrap(),
+                2 => txn.set_account(address, Some(Account::eoa(rng.gen(), U256::zero()))),
+                _ => {
+                    if txn.try_account(address).unwrap().is_some() {
+                        let slot = H256::from_low_u64_be(rng.gen_range(0..4));
+                        txn.set_storage(address, slot, U256::from(rng.gen_range(0..3)))
+                            .unwrap();
+                    }
+                }
+            }
+        }
+        let changes: Vec<_> = txn.drain_pending().unwrap().collect();
+        let root = txn.commit().unwrap();
+
+        let mut txn = dbs[1].begin_mut().unwrap();
+        txn.apply_changes(changes).unwrap();
+        assert_eq!(txn.commit().unwrap(), root);
+    }
+}
+
+#[test]
+#[should_panic(expected = "Account code hash is zero")]
+fn zero_code_hash() {
+    let db = Db::memory().unwrap();
+    let mut txn = db.begin_mut().unwrap();
+    txn.set_account(
+        get_address_from_index(0),
+        Some(Account::new(0, U256::zero(), H256::zero())),
+    );
+}
+
+#[test]
+fn disk_spill() {
+    let db = Db::open_in_memory_with_disk_spill(100).unwrap();
+    let reference = Db::memory().unwrap();
+    for db in [&db, &reference] {
+        let mut txn = db.begin_mut().unwrap();
+        for i in 0..2000 {
+            let address = get_address_from_index(i);
+            txn.set_account(address, ACCOUNT1.clone());
+            txn.set_storage(address, H256::from_low_u64_be(i as u64), U256::from(i + 1))
+                .unwrap();
+        }
+        txn.commit().unwrap();
+    }
+
+    let mut txn = db.begin_mut().unwrap();
+    let mut reference_txn = reference.begin_mut().unwrap();
+    assert_eq!(
+        txn.state_root().unwrap(),
+        reference_txn.state_root().unwrap()
+    );
+    for i in 0..2000 {
+        let address = get_address_from_index(i);
+        assert_eq!(txn.try_account(address).unwrap(), *ACCOUNT1);
+        assert_eq!(
+            txn.storage(address, H256::from_low_u64_be(i as u64))
+                .unwrap(),
+            U256::from(i + 1)
+        );
+        txn.set_account(address, None);
+    }
+    // The deletes are spilled to disk, and still rolled back
+    txn.state_root().unwrap();
+    txn.rollback().unwrap();
+    let txn = db.begin_ro().unwrap();
+    for i in 0..2000 {
+        assert_eq!(
+            txn.try_account(get_address_from_index(i)).unwrap(),
+            *ACCOUNT1
+        );
+    }
+}